@@ -0,0 +1,62 @@
+//! Instructions per second of the interpreter loop, reported by criterion
+//! as elements per second:
+//!
+//! ```text
+//! cargo bench --bench cpu
+//! ```
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use nes_emulator::asm;
+use nes_emulator::cpu::CPU;
+
+/// Instructions per measured iteration.
+const INSTRUCTIONS: usize = 100_000;
+
+/// Programs that loop forever over the instructions the CPU implements.
+const PROGRAMS: &[(&str, &str)] = &[
+    (
+        "load_store_add",
+        "
+        loop:
+            LDA $10
+            ADC #1
+            STA $10
+            LDA $0200,X
+            TAX
+            INX
+            STA ($20),Y
+            JSR loop
+        ",
+    ),
+    (
+        "subroutine",
+        "
+        loop:
+            JSR sub
+            JSR loop
+        sub:
+            RTS
+        ",
+    ),
+];
+
+fn bench_programs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("instructions");
+    group.throughput(Throughput::Elements(INSTRUCTIONS as u64));
+    for (name, source) in PROGRAMS {
+        let mut cpu = CPU::default();
+        cpu.load(asm::assemble(source).unwrap());
+        cpu.reset();
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                std::hint::black_box(&mut cpu)
+                    .run_for_instructions(INSTRUCTIONS)
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_programs);
+criterion_main!(benches);
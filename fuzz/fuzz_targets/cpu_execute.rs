@@ -0,0 +1,107 @@
+//! Random programs through the CPU, one instruction at a time.
+//!
+//! The first bytes of the input set A, X, Y, P and SP; the rest is loaded
+//! at $8000 and run from the reset vector for at most [`MAX_STEPS`]
+//! instructions. Besides not panicking, every instruction must:
+//!
+//! - leave the program counter just past its operand, unless it changes
+//!   the flow of control;
+//! - move the stack pointer by what it pushes and pulls, writing the
+//!   stack only in page 1;
+//! - spend one cycle per bus access, no fewer than the opcode table says.
+//!
+//! A fault must leave the program counter at the opcode.
+//!
+//! ```text
+//! cargo +nightly fuzz run cpu_execute
+//! ```
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nes_emulator::cpu::{AddressingMode, BusAccess, CPU};
+use nes_emulator::opcodes::{OpCode, OPCODES_MAP};
+
+const MAX_STEPS: usize = 1_000;
+/// Room left for the program below the vectors at $FFFA.
+const MAX_PROGRAM: usize = 0x7ffa;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((registers, program)) = data.split_first_chunk::<5>() else {
+        return;
+    };
+    let mut cpu = CPU::default();
+    cpu.load(program[..program.len().min(MAX_PROGRAM)].to_vec());
+    cpu.reset();
+    let [a, x, y, p, s] = *registers;
+    cpu.register_a = a;
+    cpu.register_x = x;
+    cpu.register_y = y;
+    cpu.status = p;
+    cpu.stack_pointer = s;
+    cpu.set_bus_logging(true);
+
+    for _ in 0..MAX_STEPS {
+        let pc = cpu.program_counter;
+        let sp = cpu.stack_pointer;
+        let cycles = cpu.cycles;
+        let result = cpu.step();
+        let bus = cpu.take_bus_log();
+        if let Err(err) = result {
+            assert_eq!(err.pc, pc);
+            assert_eq!(cpu.program_counter, pc, "{}", err);
+            return;
+        }
+        let opcode = OPCODES_MAP[&bus[0].value];
+
+        if !changes_flow(opcode) {
+            assert_eq!(
+                cpu.program_counter,
+                pc.wrapping_add(opcode.len as u16),
+                "{} at ${:04X}",
+                opcode.mnemonic,
+                pc
+            );
+        }
+
+        let pushed = stack_delta(opcode.mnemonic);
+        assert_eq!(
+            cpu.stack_pointer,
+            sp.wrapping_add_signed(-pushed),
+            "{} at ${:04X}",
+            opcode.mnemonic,
+            pc
+        );
+        if pushed > 0 {
+            for cycle in bus.iter().filter(|c| c.access == BusAccess::Write) {
+                assert_eq!(cycle.address & 0xff00, 0x0100, "{:?}", cycle);
+            }
+        }
+
+        assert_eq!(cpu.cycles - cycles, bus.len());
+        assert!(
+            bus.len() >= opcode.cycles as usize,
+            "{} at ${:04X}",
+            opcode.mnemonic,
+            pc
+        );
+    }
+});
+
+fn changes_flow(opcode: &OpCode) -> bool {
+    opcode.mode == AddressingMode::Relative
+        || matches!(opcode.mnemonic, "JMP" | "JSR" | "RTS" | "RTI" | "BRK")
+}
+
+/// Bytes the instruction leaves pushed on the stack; negative if it pulls.
+fn stack_delta(mnemonic: &str) -> i8 {
+    match mnemonic {
+        "BRK" => 3,
+        "JSR" => 2,
+        "PHA" | "PHP" => 1,
+        "PLA" | "PLP" => -1,
+        "RTS" => -2,
+        "RTI" => -3,
+        _ => 0,
+    }
+}
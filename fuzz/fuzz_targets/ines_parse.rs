@@ -0,0 +1,30 @@
+//! Random bytes through the iNES parser. A file it accepts must have PRG
+//! and CHR ROM of the sizes its header declares, and must load into the
+//! CPU.
+//!
+//! ```text
+//! cargo +nightly fuzz run ines_parse
+//! ```
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nes_emulator::cartridge::{Rom, CHR_ROM_PAGE_SIZE, PRG_ROM_PAGE_SIZE};
+use nes_emulator::cpu::{Mem, CPU};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(rom) = Rom::new(data) else {
+        return;
+    };
+    assert_eq!(rom.prg_rom.len(), data[4] as usize * PRG_ROM_PAGE_SIZE);
+    assert_eq!(rom.chr_rom.len(), data[5] as usize * CHR_ROM_PAGE_SIZE);
+    assert_eq!(rom.mapper, (data[7] & 0xf0) | (data[6] >> 4));
+    rom.md5();
+    rom.sha1();
+
+    let mut cpu = CPU::default();
+    cpu.load_rom(&rom);
+    if let Some(&first) = rom.prg_rom.first() {
+        assert_eq!(cpu.mem_read(0x8000), first);
+    }
+});
@@ -0,0 +1,691 @@
+//! Two-pass 6502 assembler for tests, patches and quick experiments.
+//!
+//! ```text
+//! counter = $10
+//!         .org $8000
+//! start:  LDX #0
+//! loop:   INC counter,X
+//!         INX
+//!         BNE loop
+//!         JMP (vector)
+//! vector: .word start
+//!         .byte <start, >start, 'A'
+//! ```
+//!
+//! Mnemonics are case-insensitive, `;` starts a comment, and expressions
+//! are numbers (`$ff`, `%1010`, `255`, `'c'`), labels and `*` for the
+//! current address combined with `+`/`-` and the `<`/`>` low/high byte
+//! operators.
+
+use crate::cpu::AddressingMode;
+use crate::opcodes::{self, OpCode};
+use crate::symbols::SymbolTable;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Where code is placed when the source has no `.org`; matches [`crate::cpu::CPU::load`].
+pub const DEFAULT_ORIGIN: u16 = 0x8000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based source line.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembled code along with the address it starts at and its labels.
+#[derive(Debug)]
+pub struct Program {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub symbols: SymbolTable,
+}
+
+/// Assembles `source` into bytes starting at the first `.org` (or
+/// [`DEFAULT_ORIGIN`]), ready for [`crate::cpu::CPU::load`].
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    Ok(assemble_program(source)?.bytes)
+}
+
+pub fn assemble_program(source: &str) -> Result<Program, AsmError> {
+    let mut statements = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        parse_line(line, text, &mut statements).map_err(|message| AsmError { line, message })?;
+    }
+
+    let mut assembler = Assembler::default();
+    assembler.layout(&mut statements)?;
+    assembler.emit(&statements)
+}
+
+/// Assembles string literals, one per source line, panicking on errors:
+///
+/// ```
+/// let program = nes_emulator::assemble!("LDA #$c0", "TAX", "INX", "BRK");
+/// assert_eq!(program, vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+/// ```
+#[macro_export]
+macro_rules! assemble {
+    ($($line:expr),* $(,)?) => {
+        $crate::asm::assemble(&[$($line),*].join("\n"))
+            .unwrap_or_else(|err| panic!("{}", err))
+    };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i32),
+    Label(String),
+    Here,
+    Low(Box<Expr>),
+    High(Box<Expr>),
+    Neg(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Operand {
+    Implied,
+    Accumulator,
+    Immediate(Expr),
+    /// Plain, `,X` or `,Y` address; `index` is `None`, `Some('X')` or `Some('Y')`.
+    Address(Expr, Option<char>),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+enum Kind {
+    Label(String),
+    Constant(String, Expr),
+    Org(Expr),
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+    Instruction {
+        mnemonic: String,
+        operand: Operand,
+        /// Opcode picked by the first pass, so both passes agree on sizes.
+        opcode: Option<&'static OpCode>,
+    },
+}
+
+struct Statement {
+    line: usize,
+    kind: Kind,
+}
+
+#[derive(Default)]
+struct Assembler {
+    labels: HashMap<String, i32>,
+    /// Label names in definition order, for the symbol table.
+    order: Vec<String>,
+}
+
+impl Assembler {
+    /// First pass: picks opcodes and assigns every label an address.
+    fn layout(&mut self, statements: &mut [Statement]) -> Result<(), AsmError> {
+        let mut pc = DEFAULT_ORIGIN as i32;
+        for statement in statements.iter_mut() {
+            let line = statement.line;
+            let err = |message: String| AsmError { line, message };
+            match &mut statement.kind {
+                Kind::Label(name) => self.define(name, pc).map_err(err)?,
+                Kind::Constant(name, expr) => {
+                    let value = self.eval(expr, pc, true).map_err(err)?.ok_or_else(|| {
+                        err(format!("constant {} uses a label defined later", name))
+                    })?;
+                    self.define(name, value).map_err(err)?;
+                }
+                Kind::Org(expr) => {
+                    pc = self
+                        .eval(expr, pc, true)
+                        .map_err(err)?
+                        .ok_or_else(|| err(".org uses a label defined later".to_string()))?;
+                }
+                Kind::Bytes(exprs) => pc += exprs.len() as i32,
+                Kind::Words(exprs) => pc += 2 * exprs.len() as i32,
+                Kind::Instruction {
+                    mnemonic,
+                    operand,
+                    opcode,
+                } => {
+                    let found = self.select_opcode(mnemonic, operand, pc).map_err(err)?;
+                    *opcode = Some(found);
+                    pc += found.len as i32;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Second pass: evaluates operands and writes the bytes.
+    fn emit(&self, statements: &[Statement]) -> Result<Program, AsmError> {
+        let mut origin = None;
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut pc = DEFAULT_ORIGIN as i32;
+        for statement in statements {
+            let line = statement.line;
+            let err = |message: String| AsmError { line, message };
+            let eval = |expr: &Expr, pc: i32| -> Result<i32, AsmError> {
+                Ok(self.eval(expr, pc, false).map_err(err)?.unwrap())
+            };
+            match &statement.kind {
+                Kind::Label(_) | Kind::Constant(..) => {}
+                Kind::Org(expr) => {
+                    let target = eval(expr, pc)?;
+                    match origin {
+                        None if bytes.is_empty() => {}
+                        _ if target < pc => {
+                            return Err(err(format!(".org ${:04X} moves backwards", target)))
+                        }
+                        _ => bytes.resize(bytes.len() + (target - pc) as usize, 0),
+                    }
+                    pc = target;
+                }
+                Kind::Bytes(exprs) => {
+                    for expr in exprs {
+                        bytes.push(byte(eval(expr, pc)?).map_err(err)?);
+                    }
+                    pc += exprs.len() as i32;
+                }
+                Kind::Words(exprs) => {
+                    for expr in exprs {
+                        bytes.extend(word(eval(expr, pc)?).map_err(err)?.to_le_bytes());
+                    }
+                    pc += 2 * exprs.len() as i32;
+                }
+                Kind::Instruction {
+                    operand, opcode, ..
+                } => {
+                    let opcode = opcode.expect("opcode picked in the first pass");
+                    bytes.push(opcode.code);
+                    match operand {
+                        Operand::Implied | Operand::Accumulator => {}
+                        Operand::Immediate(expr) => bytes.push(byte(eval(expr, pc)?).map_err(err)?),
+                        Operand::Address(expr, _)
+                        | Operand::Indirect(expr)
+                        | Operand::IndirectX(expr)
+                        | Operand::IndirectY(expr) => {
+                            let value = eval(expr, pc)?;
+                            match opcode.mode {
+                                AddressingMode::Relative => {
+                                    let offset = value - (pc + 2);
+                                    if !(-128..=127).contains(&offset) {
+                                        return Err(err(format!(
+                                            "branch target ${:04X} is out of range",
+                                            value
+                                        )));
+                                    }
+                                    bytes.push(offset as u8);
+                                }
+                                _ if opcode.len == 2 => {
+                                    if !(0..=0xff).contains(&value) {
+                                        return Err(err(format!(
+                                            "${:X} is not a zero page address",
+                                            value
+                                        )));
+                                    }
+                                    bytes.push(value as u8);
+                                }
+                                _ => bytes.extend(word(value).map_err(err)?.to_le_bytes()),
+                            }
+                        }
+                    }
+                    pc += opcode.len as i32;
+                }
+            }
+            if origin.is_none() && !bytes.is_empty() {
+                origin = Some((pc - bytes.len() as i32) as u16);
+            }
+        }
+
+        let mut symbols = SymbolTable::new();
+        for name in &self.order {
+            let value = self.labels[name];
+            if (0..=0xffff).contains(&value) {
+                symbols.insert(value as u16, name);
+            }
+        }
+        Ok(Program {
+            origin: origin.unwrap_or(DEFAULT_ORIGIN),
+            bytes,
+            symbols,
+        })
+    }
+
+    fn define(&mut self, name: &str, value: i32) -> Result<(), String> {
+        if self.labels.insert(name.to_string(), value).is_some() {
+            return Err(format!("{} is defined twice", name));
+        }
+        self.order.push(name.to_string());
+        Ok(())
+    }
+
+    /// Evaluates `expr`. Labels that are not defined yet give `Ok(None)`
+    /// when `allow_forward` is set and an error otherwise.
+    fn eval(&self, expr: &Expr, pc: i32, allow_forward: bool) -> Result<Option<i32>, String> {
+        let value = match expr {
+            Expr::Number(value) => *value,
+            Expr::Here => pc,
+            Expr::Label(name) => match self.labels.get(name) {
+                Some(value) => *value,
+                None if allow_forward => return Ok(None),
+                None => return Err(format!("undefined label {}", name)),
+            },
+            Expr::Low(inner) => match self.eval(inner, pc, allow_forward)? {
+                Some(value) => value & 0xff,
+                None => return Ok(None),
+            },
+            Expr::High(inner) => match self.eval(inner, pc, allow_forward)? {
+                Some(value) => (value >> 8) & 0xff,
+                None => return Ok(None),
+            },
+            Expr::Neg(inner) => match self.eval(inner, pc, allow_forward)? {
+                Some(value) => -value,
+                None => return Ok(None),
+            },
+            Expr::Add(lhs, rhs) | Expr::Sub(lhs, rhs) => {
+                let lhs = self.eval(lhs, pc, allow_forward)?;
+                let rhs = self.eval(rhs, pc, allow_forward)?;
+                match (lhs, rhs) {
+                    (Some(lhs), Some(rhs)) if matches!(expr, Expr::Add(..)) => lhs + rhs,
+                    (Some(lhs), Some(rhs)) => lhs - rhs,
+                    _ => return Ok(None),
+                }
+            }
+        };
+        Ok(Some(value))
+    }
+
+    /// Finds the opcode for `mnemonic` with the given operand syntax,
+    /// preferring zero page forms when the address is already known to fit.
+    fn select_opcode(
+        &self,
+        mnemonic: &str,
+        operand: &Operand,
+        pc: i32,
+    ) -> Result<&'static OpCode, String> {
+        let find = |mode: AddressingMode| {
+            opcodes::CPU_OPS_CODES
+                .iter()
+                .find(|op| op.mnemonic == mnemonic && op.mode == mode)
+        };
+        if !opcodes::CPU_OPS_CODES
+            .iter()
+            .any(|op| op.mnemonic == mnemonic)
+        {
+            return Err(format!("unknown instruction {}", mnemonic));
+        }
+
+        let found = match operand {
+            Operand::Implied => {
+                find(AddressingMode::NoneAddressing).or_else(|| find(AddressingMode::Accumulator))
+            }
+            Operand::Accumulator => find(AddressingMode::Accumulator),
+            Operand::Immediate(_) => find(AddressingMode::Immediate),
+            Operand::Indirect(_) => find(AddressingMode::Indirect),
+            Operand::IndirectX(_) => find(AddressingMode::Indirect_X),
+            Operand::IndirectY(_) => find(AddressingMode::Indirect_Y),
+            Operand::Address(expr, index) => {
+                let zero_page = matches!(self.eval(expr, pc, true)?, Some(0..=0xff));
+                let (zp_mode, abs_mode) = match index {
+                    None => (AddressingMode::ZeroPage, AddressingMode::Absolute),
+                    Some('X') => (AddressingMode::ZeroPage_X, AddressingMode::Absolute_X),
+                    _ => (AddressingMode::ZeroPage_Y, AddressingMode::Absolute_Y),
+                };
+                let relative = if index.is_none() {
+                    find(AddressingMode::Relative)
+                } else {
+                    None
+                };
+                // `STX zp,Y` and `STY zp,X` have no absolute form, so forward
+                // references fall back to zero page and are range-checked in
+                // the second pass.
+                relative
+                    .or_else(|| if zero_page { find(zp_mode) } else { None })
+                    .or_else(|| find(abs_mode))
+                    .or_else(|| find(zp_mode))
+            }
+        };
+        found.ok_or_else(|| format!("{} does not support this addressing mode", mnemonic))
+    }
+}
+
+fn byte(value: i32) -> Result<u8, String> {
+    if !(-128..=0xff).contains(&value) {
+        return Err(format!("${:X} does not fit in a byte", value));
+    }
+    Ok(value as u8)
+}
+
+fn word(value: i32) -> Result<u16, String> {
+    if !(-0x8000..=0xffff).contains(&value) {
+        return Err(format!("${:X} does not fit in a word", value));
+    }
+    Ok(value as u16)
+}
+
+fn parse_line(line: usize, text: &str, statements: &mut Vec<Statement>) -> Result<(), String> {
+    let mut rest = strip_comment(text).trim();
+
+    // `name = value` defines a constant.
+    if let Some((name, value)) = rest.split_once('=') {
+        let name = name.trim();
+        if is_identifier(name) {
+            statements.push(Statement {
+                line,
+                kind: Kind::Constant(name.to_string(), parse_expr(value)?),
+            });
+            return Ok(());
+        }
+    }
+
+    if let Some((name, after)) = rest
+        .split_once(':')
+        .filter(|(name, _)| is_identifier(name.trim()))
+    {
+        let name = name.trim();
+        statements.push(Statement {
+            line,
+            kind: Kind::Label(name.to_string()),
+        });
+        rest = after.trim();
+    }
+    if rest.is_empty() {
+        return Ok(());
+    }
+
+    let (word, args) = match rest.split_once(char::is_whitespace) {
+        Some((word, args)) => (word, args.trim()),
+        None => (rest, ""),
+    };
+    let kind = match word.to_ascii_lowercase().as_str() {
+        ".org" => Kind::Org(parse_expr(args)?),
+        ".byte" | ".db" => Kind::Bytes(parse_list(args)?),
+        ".word" | ".dw" => Kind::Words(parse_list(args)?),
+        directive if directive.starts_with('.') => {
+            return Err(format!("unknown directive {}", word))
+        }
+        _ => Kind::Instruction {
+            mnemonic: word.to_ascii_uppercase(),
+            operand: parse_operand(args)?,
+            opcode: None,
+        },
+    };
+    statements.push(Statement { line, kind });
+    Ok(())
+}
+
+fn strip_comment(text: &str) -> &str {
+    // A ';' inside a character literal is not a comment.
+    let mut in_char = false;
+    for (index, c) in text.char_indices() {
+        match c {
+            '\'' => in_char = !in_char,
+            ';' if !in_char => return &text[..index],
+            _ => {}
+        }
+    }
+    text
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '@')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_list(args: &str) -> Result<Vec<Expr>, String> {
+    if args.is_empty() {
+        return Err("expected a value".to_string());
+    }
+    args.split(',').map(parse_expr).collect()
+}
+
+fn parse_operand(args: &str) -> Result<Operand, String> {
+    let args: String = args.chars().filter(|c| !c.is_whitespace()).collect();
+    let upper = args.to_ascii_uppercase();
+    if args.is_empty() {
+        return Ok(Operand::Implied);
+    }
+    if upper == "A" {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(value) = args.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_expr(value)?));
+    }
+    if args.starts_with('(') {
+        if let Some(inner) = upper.strip_suffix(",X)") {
+            return Ok(Operand::IndirectX(parse_expr(&args[1..inner.len()])?));
+        }
+        if let Some(inner) = upper.strip_suffix("),Y") {
+            return Ok(Operand::IndirectY(parse_expr(&args[1..inner.len()])?));
+        }
+        if let Some(inner) = args.strip_suffix(')') {
+            return Ok(Operand::Indirect(parse_expr(&inner[1..])?));
+        }
+        return Err(format!("malformed operand {}", args));
+    }
+    if let Some(inner) = upper.strip_suffix(",X") {
+        return Ok(Operand::Address(
+            parse_expr(&args[..inner.len()])?,
+            Some('X'),
+        ));
+    }
+    if let Some(inner) = upper.strip_suffix(",Y") {
+        return Ok(Operand::Address(
+            parse_expr(&args[..inner.len()])?,
+            Some('Y'),
+        ));
+    }
+    Ok(Operand::Address(parse_expr(&args)?, None))
+}
+
+fn parse_expr(text: &str) -> Result<Expr, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("expected an expression".to_string());
+    }
+    // Binary operators are left associative, so split at the last one that
+    // is not a unary sign or inside a character literal.
+    let bytes = text.as_bytes();
+    let mut in_char = false;
+    let mut split = None;
+    for (index, &c) in bytes.iter().enumerate() {
+        match c {
+            b'\'' => in_char = !in_char,
+            b'+' | b'-' if !in_char && index > 0 => {
+                let before = text[..index].trim_end();
+                if !before.is_empty() && !before.ends_with(['+', '-', '<', '>']) {
+                    split = Some(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(index) = split {
+        let lhs = Box::new(parse_expr(&text[..index])?);
+        let rhs = Box::new(parse_expr(&text[index + 1..])?);
+        return Ok(if bytes[index] == b'+' {
+            Expr::Add(lhs, rhs)
+        } else {
+            Expr::Sub(lhs, rhs)
+        });
+    }
+
+    if let Some(inner) = text.strip_prefix('<') {
+        return Ok(Expr::Low(Box::new(parse_expr(inner)?)));
+    }
+    if let Some(inner) = text.strip_prefix('>') {
+        return Ok(Expr::High(Box::new(parse_expr(inner)?)));
+    }
+    if let Some(inner) = text.strip_prefix('-') {
+        return Ok(Expr::Neg(Box::new(parse_expr(inner)?)));
+    }
+    if text == "*" {
+        return Ok(Expr::Here);
+    }
+
+    let number = |digits: &str, radix: u32| {
+        i32::from_str_radix(digits, radix).map_err(|_| format!("invalid number {}", text))
+    };
+    if let Some(digits) = text.strip_prefix('$') {
+        return Ok(Expr::Number(number(digits, 16)?));
+    }
+    if let Some(digits) = text.strip_prefix('%') {
+        return Ok(Expr::Number(number(digits, 2)?));
+    }
+    if text.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok(Expr::Number(number(text, 10)?));
+    }
+    if let Some(c) = text
+        .strip_prefix('\'')
+        .and_then(|rest| rest.strip_suffix('\''))
+    {
+        let mut chars = c.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii() => Ok(Expr::Number(c as i32)),
+            _ => Err(format!("invalid character literal {}", text)),
+        };
+    }
+    if is_identifier(text) {
+        return Ok(Expr::Label(text.to_string()));
+    }
+    Err(format!("invalid expression {}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Mem, CPU};
+    use crate::disasm;
+
+    #[test]
+    fn test_addressing_modes() {
+        let program = assemble(
+            "
+            LDA #$c0
+            LDA $10
+            LDA $10,X
+            LDX $10,Y
+            LDA $1234
+            LDA $1234,X
+            lda $1234,y
+            LDA ($20,X)
+            LDA ($20),Y
+            ASL A
+            ASL
+            JMP ($0200)
+            BRK
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            program,
+            vec![
+                0xa9, 0xc0, 0xa5, 0x10, 0xb5, 0x10, 0xb6, 0x10, 0xad, 0x34, 0x12, 0xbd, 0x34, 0x12,
+                0xb9, 0x34, 0x12, 0xa1, 0x20, 0xb1, 0x20, 0x0a, 0x0a, 0x6c, 0x00, 0x02, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_branches() {
+        let program = assemble_program(
+            "
+            counter = $10
+            start:  LDX #3
+            loop:   INC counter ; forward and backward references
+                    DEX
+                    BNE loop
+                    JMP end
+                    NOP
+            end:    BRK
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            program.bytes,
+            vec![0xa2, 0x03, 0xe6, 0x10, 0xca, 0xd0, 0xfb, 0x4c, 0x0b, 0x80, 0xea, 0x00]
+        );
+        assert_eq!(program.origin, 0x8000);
+        assert_eq!(program.symbols.lookup("end"), Some(0x800b));
+        assert_eq!(program.symbols.get(0x0010), Some("counter"));
+    }
+
+    #[test]
+    fn test_directives_and_expressions() {
+        let program = assemble_program(
+            "
+                    .org $c000
+            table:  .word table, $1234
+                    .byte <table, >table+1, 'A', %101, -1
+                    .org $c010
+                    LDA #>data
+                    LDA data-2,X
+                    JMP *
+            data:
+            ",
+        )
+        .unwrap();
+        assert_eq!(program.origin, 0xc000);
+        assert_eq!(
+            program.bytes[..9],
+            [0x00, 0xc0, 0x34, 0x12, 0x00, 0xc1, 0x41, 0x05, 0xff]
+        );
+        assert!(program.bytes[9..0x10].iter().all(|&byte| byte == 0));
+        assert_eq!(
+            program.bytes[0x10..],
+            [0xa9, 0xc0, 0xbd, 0x16, 0xc0, 0x4c, 0x15, 0xc0]
+        );
+    }
+
+    #[test]
+    fn test_errors_report_line_numbers() {
+        let err = assemble("NOP\n  FOO #1\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.to_string(), "line 2: unknown instruction FOO");
+
+        assert_eq!(assemble("JMP nowhere").unwrap_err().line, 1);
+        assert_eq!(assemble("NOP\nINX #1").unwrap_err().line, 2);
+        assert_eq!(assemble("LDA #$100").unwrap_err().line, 1);
+        assert_eq!(assemble("a: NOP\na: NOP").unwrap_err().line, 2);
+
+        let far = "loop: NOP\n.org $8100\nBNE loop";
+        assert_eq!(
+            assemble(far).unwrap_err().message,
+            "branch target $8000 is out of range"
+        );
+    }
+
+    #[test]
+    fn test_round_trips_through_disassembler() {
+        let program = assemble!("LDA ($12),Y", "STA $4455,X", "BPL *", "BRK");
+        let listing: Vec<String> = disasm::disassemble(&program, 0x8000)
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect();
+        assert_eq!(
+            listing,
+            vec!["LDA ($12),Y", "STA $4455,X", "BPL $8005", "BRK"]
+        );
+    }
+
+    #[test]
+    fn test_macro_output_runs() {
+        let mut cpu = CPU::default();
+        cpu.load_and_run(assemble!("LDA #$05", "ADC #$03", "STA $20", "BRK"))
+            .unwrap();
+        assert_eq!(cpu.mem_read(0x20), 0x08);
+    }
+}
@@ -0,0 +1,237 @@
+//! Pass/fail detection for test ROMs that report through cartridge RAM the
+//! way blargg's do (instr_test-v5, cpu_timing_test, ppu_vbl_nmi,
+//! apu_test, ...).
+//!
+//! Once $6001-$6003 hold the signature `DE B0 61`, $6000 is the status:
+//! $80 while the test runs, $81 when it wants the reset button pressed,
+//! and the result code when it is done, 0 meaning passed. The text it
+//! printed is at $6004, ended by a zero byte.
+
+use crate::cpu::{Mem, CPU};
+use crate::runner::{frame_of, ExitCondition, Runner, StopReason};
+use std::fmt;
+
+pub const STATUS: u16 = 0x6000;
+pub const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+pub const MESSAGE: u16 = 0x6004;
+/// End of cartridge RAM, where an unterminated message stops.
+const MESSAGE_END: u16 = 0x8000;
+
+const RUNNING: u8 = 0x80;
+const RESET_REQUEST: u8 = 0x81;
+/// The ROMs ask for reset to be pressed no sooner than 100 ms after they
+/// request it.
+const RESET_DELAY_FRAMES: u64 = 6;
+/// A minute of frames; the longest of the suites take about this long.
+pub const DEFAULT_TIMEOUT_FRAMES: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The signature is not there yet.
+    Unknown,
+    Running,
+    ResetRequested,
+    Done(u8),
+}
+
+/// Reads the status the test ROM reports.
+pub fn status(cpu: &CPU) -> Status {
+    if (0..3).any(|i| cpu.mem_read(STATUS + 1 + i) != SIGNATURE[i as usize]) {
+        return Status::Unknown;
+    }
+    match cpu.mem_read(STATUS) {
+        RESET_REQUEST => Status::ResetRequested,
+        code if code >= RUNNING => Status::Running,
+        code => Status::Done(code),
+    }
+}
+
+/// The text the test ROM printed so far.
+pub fn message(cpu: &CPU) -> String {
+    (MESSAGE..MESSAGE_END)
+        .map(|address| cpu.mem_read(address))
+        .take_while(|&byte| byte != 0)
+        .map(|byte| byte as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Passed,
+    /// The result code the ROM reported.
+    Failed(u8),
+    TimedOut,
+    /// The CPU faulted before the ROM reported a result.
+    Stopped(StopReason),
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Passed => write!(f, "passed"),
+            Verdict::Failed(code) => write!(f, "failed with code {}", code),
+            Verdict::TimedOut => write!(f, "timed out"),
+            Verdict::Stopped(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub verdict: Verdict,
+    pub message: String,
+    pub frames: u64,
+    /// Soft resets performed at the ROM's request.
+    pub resets: usize,
+}
+
+/// Runs a test ROM frame by frame until it reports a result, pressing
+/// reset when it asks, or until `timeout` frames have run. The runner's
+/// input and cheats apply; its exit conditions are left out.
+pub fn run(runner: &mut Runner, cpu: &mut CPU, timeout: u64) -> TestResult {
+    let conditions = std::mem::take(&mut runner.conditions);
+    let mut reset_at = None;
+    let mut resets = 0;
+    let verdict = loop {
+        let frame = frame_of(cpu.cycles);
+        if frame >= timeout {
+            break Verdict::TimedOut;
+        }
+        runner.conditions = vec![ExitCondition::Frames(frame + 1)];
+        let summary = runner.run(cpu);
+        match status(cpu) {
+            Status::Done(0) => break Verdict::Passed,
+            Status::Done(code) => break Verdict::Failed(code),
+            Status::ResetRequested => match reset_at {
+                None => reset_at = Some(summary.frames + RESET_DELAY_FRAMES),
+                Some(at) if summary.frames >= at => {
+                    cpu.soft_reset();
+                    reset_at = None;
+                    resets += 1;
+                }
+                Some(_) => {}
+            },
+            Status::Unknown | Status::Running => reset_at = None,
+        }
+        if !matches!(summary.reason, StopReason::Condition(_)) {
+            break Verdict::Stopped(summary.reason);
+        }
+    };
+    runner.conditions = conditions;
+    TestResult {
+        verdict,
+        message: message(cpu),
+        frames: frame_of(cpu.cycles),
+        resets,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn load(source: &str) -> CPU {
+        let mut cpu = CPU::default();
+        cpu.load(asm::assemble(source).unwrap());
+        cpu.reset();
+        cpu
+    }
+
+    /// Writes the signature and `OK\n` and sets the status to `A`.
+    const REPORT: &str = "
+        STA $6000
+        LDA #$DE
+        STA $6001
+        LDA #$B0
+        STA $6002
+        LDA #$61
+        STA $6003
+        LDA #$4F
+        STA $6004
+        LDA #$4B
+        STA $6005
+        LDA #$0A
+        STA $6006
+    ";
+
+    #[test]
+    fn test_status_and_message() {
+        let mut cpu = CPU::default();
+        assert_eq!(status(&cpu), Status::Unknown);
+        cpu.mem_write(STATUS, 0x80);
+        for (i, byte) in SIGNATURE.iter().enumerate() {
+            cpu.mem_write(STATUS + 1 + i as u16, *byte);
+        }
+        assert_eq!(status(&cpu), Status::Running);
+        cpu.mem_write(STATUS, 0x81);
+        assert_eq!(status(&cpu), Status::ResetRequested);
+        cpu.mem_write(STATUS, 0x03);
+        assert_eq!(status(&cpu), Status::Done(3));
+
+        for (i, byte) in b"2-branch timing\n\nFailed\n".iter().enumerate() {
+            cpu.mem_write(MESSAGE + i as u16, *byte);
+        }
+        assert_eq!(message(&cpu), "2-branch timing\n\nFailed");
+    }
+
+    #[test]
+    fn test_pass_and_fail() {
+        let mut cpu = load(&format!("LDA #0\n{}\nloop: JSR loop", REPORT));
+        let result = run(&mut Runner::new(), &mut cpu, 10);
+        assert_eq!(result.verdict, Verdict::Passed);
+        assert_eq!(result.message, "OK");
+        assert_eq!(result.frames, 1);
+
+        let mut cpu = load(&format!("LDA #2\n{}\nBRK", REPORT));
+        let result = run(&mut Runner::new(), &mut cpu, 10);
+        assert_eq!(result.verdict, Verdict::Failed(2));
+        assert_eq!(result.verdict.to_string(), "failed with code 2");
+
+        let mut cpu = load(&format!("LDA #$80\n{}\nloop: JSR loop", REPORT));
+        let result = run(&mut Runner::new(), &mut cpu, 10);
+        assert_eq!(result.verdict, Verdict::TimedOut);
+        assert_eq!(result.frames, 10);
+
+        // A JAM opcode locks the CPU up.
+        let mut cpu = load("LDA #$80\nSTA $6000\n.byte $02");
+        let result = run(&mut Runner::new(), &mut cpu, 10);
+        assert_eq!(
+            result.verdict.to_string(),
+            "CPU fault: JAM opcode $02 at $8005"
+        );
+    }
+
+    #[test]
+    fn test_reset_request() {
+        // Memory is flat, so the program can point the reset vector at the
+        // code that should run after the reset.
+        let mut cpu = load(&format!(
+            "
+            LDA #<after_reset
+            STA $FFFC
+            LDA #>after_reset
+            STA $FFFD
+            LDA #$81
+            {}
+            wait: JSR wait
+            after_reset:
+            LDA #0
+            STA $6000
+            BRK
+            ",
+            REPORT
+        ));
+        let mut runner = Runner::new();
+        runner.conditions.push(ExitCondition::Pc(0x8000));
+        let result = run(&mut runner, &mut cpu, 30);
+        assert_eq!(result.verdict, Verdict::Passed);
+        assert_eq!(result.resets, 1);
+        // The request is seen after frame 1 and honoured 6 frames later;
+        // the result is read at the end of that frame.
+        assert_eq!(result.frames, 8);
+        assert_eq!(runner.conditions, [ExitCondition::Pc(0x8000)]);
+    }
+}
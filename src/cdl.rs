@@ -0,0 +1,282 @@
+//! Code/Data Logger in the FCEUX `.cdl` format.
+//!
+//! A `.cdl` file holds one flag byte per PRG ROM byte followed by one per
+//! CHR ROM byte, so flags follow the ROM data wherever a mapper banks it in.
+//!
+//! # PRG flags
+//!
+//!  7 6 5 4 3 2 1 0
+//!  _ P d c A A D C
+//!    | | | | | | +--- Executed as code
+//!    | | | | | +----- Read as data
+//!    | | | +-+------- 8 KiB CPU window it was last accessed through
+//!    | | |            ($8000, $A000, $C000 or $E000)
+//!    | | +---------- Reached as code through a pointer (JMP indirect)
+//!    | +------------ Read as data through a pointer (($nn),Y and ($nn,X))
+//!    +-------------- Played as DPCM sample data
+//!
+//! # CHR flags
+//!
+//!  bit 0: rendered, bit 1: read through $2007
+
+use crate::cartridge::Rom;
+use crate::cpu::{AddressingMode, Mem, CPU};
+use crate::debugger;
+use crate::opcodes;
+use std::fmt;
+
+pub const CDL_CODE: u8 = 0b0000_0001;
+pub const CDL_DATA: u8 = 0b0000_0010;
+pub const CDL_BANK_MASK: u8 = 0b0000_1100;
+pub const CDL_INDIRECT_CODE: u8 = 0b0001_0000;
+pub const CDL_INDIRECT_DATA: u8 = 0b0010_0000;
+pub const CDL_PCM: u8 = 0b0100_0000;
+
+pub const CDL_CHR_RENDERED: u8 = 0b0000_0001;
+pub const CDL_CHR_READ: u8 = 0b0000_0010;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CdlError {
+    /// The log was made for a ROM with different PRG/CHR sizes.
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for CdlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CdlError::SizeMismatch { expected, actual } => write!(
+                f,
+                "code/data log is {} bytes, expected {} for this ROM",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CdlError {}
+
+/// Offset into PRG ROM of a CPU address on an NROM board, where a single
+/// 16 KiB bank is mirrored into $C000.
+pub fn nrom_prg_offset(prg_len: usize, addr: u16) -> Option<usize> {
+    if addr < 0x8000 || prg_len == 0 {
+        return None;
+    }
+    Some((addr as usize - 0x8000) % prg_len)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeDataLog {
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    /// An empty log sized for `rom`.
+    pub fn new(rom: &Rom) -> Self {
+        CodeDataLog {
+            prg: vec![0; rom.prg_rom.len()],
+            chr: vec![0; rom.chr_rom.len()],
+        }
+    }
+
+    /// Reads a `.cdl` file made for `rom`.
+    pub fn from_bytes(rom: &Rom, data: &[u8]) -> Result<Self, CdlError> {
+        let mut log = CodeDataLog::new(rom);
+        let expected = log.prg.len() + log.chr.len();
+        if data.len() != expected {
+            return Err(CdlError::SizeMismatch {
+                expected,
+                actual: data.len(),
+            });
+        }
+        let (prg, chr) = data.split_at(log.prg.len());
+        log.prg.copy_from_slice(prg);
+        log.chr.copy_from_slice(chr);
+        Ok(log)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.prg[..], &self.chr[..]].concat()
+    }
+
+    /// Adds the flags from another session of the same ROM.
+    pub fn merge(&mut self, other: &CodeDataLog) -> Result<(), CdlError> {
+        if other.prg.len() != self.prg.len() || other.chr.len() != self.chr.len() {
+            return Err(CdlError::SizeMismatch {
+                expected: self.prg.len() + self.chr.len(),
+                actual: other.prg.len() + other.chr.len(),
+            });
+        }
+        for (flags, other) in self.prg.iter_mut().zip(&other.prg) {
+            // Keep our bank bits where we have any; they record the last access.
+            let bank = if *flags & (CDL_CODE | CDL_DATA) != 0 {
+                *flags & CDL_BANK_MASK
+            } else {
+                other & CDL_BANK_MASK
+            };
+            *flags = (*flags | other) & !CDL_BANK_MASK | bank;
+        }
+        for (flags, other) in self.chr.iter_mut().zip(&other.chr) {
+            *flags |= other;
+        }
+        Ok(())
+    }
+
+    /// Flags the PRG byte at `offset`, accessed through CPU address `addr`.
+    pub fn log_prg(&mut self, offset: usize, addr: u16, flags: u8) {
+        if let Some(entry) = self.prg.get_mut(offset) {
+            let bank = (((addr >> 13) & 0b11) as u8) << 2;
+            *entry = (*entry | flags) & !CDL_BANK_MASK | bank;
+        }
+    }
+
+    pub fn log_chr(&mut self, offset: usize, flags: u8) {
+        if let Some(entry) = self.chr.get_mut(offset) {
+            *entry |= flags;
+        }
+    }
+
+    /// Logs the instruction at the program counter, before it executes.
+    /// `prg_offset` maps CPU addresses to PRG ROM offsets for the current
+    /// banking, or `None` for addresses outside PRG ROM.
+    ///
+    /// Meant to be called before every instruction, from
+    /// [`CPU::run_with_callback`] or the predicate of [`CPU::run_until`].
+    pub fn log_instruction<F>(&mut self, cpu: &CPU, prg_offset: F)
+    where
+        F: Fn(u16) -> Option<usize>,
+    {
+        let pc = cpu.program_counter;
+        let opcode = match opcodes::OPCODES_MAP.get(&cpu.mem_read(pc)) {
+            Some(opcode) => opcode,
+            None => return,
+        };
+        let mut log = |addr: u16, flags: u8| {
+            if let Some(offset) = prg_offset(addr) {
+                self.log_prg(offset, addr, flags);
+            }
+        };
+
+        for i in 0..opcode.len as u16 {
+            log(pc.wrapping_add(i), CDL_CODE);
+        }
+        match opcode.mode {
+            AddressingMode::Indirect => {
+                let pointer = cpu.mem_read_u16(pc.wrapping_add(1));
+                // JMP ($xxFF) reads its high byte from $xx00.
+                let pointer_hi = (pointer & 0xff00) | (pointer.wrapping_add(1) & 0x00ff);
+                let target = (cpu.mem_read(pointer_hi) as u16) << 8 | cpu.mem_read(pointer) as u16;
+                log(pointer, CDL_DATA);
+                log(pointer_hi, CDL_DATA);
+                log(target, CDL_INDIRECT_CODE);
+            }
+            AddressingMode::Indirect_X | AddressingMode::Indirect_Y => {
+                if let Some((addr, _)) = debugger::memory_access(cpu) {
+                    log(addr, CDL_DATA | CDL_INDIRECT_DATA);
+                }
+            }
+            _ => {
+                if let Some((addr, _)) = debugger::memory_access(cpu) {
+                    log(addr, CDL_DATA);
+                }
+            }
+        }
+    }
+
+    /// Number of PRG bytes flagged as code and as data.
+    pub fn prg_coverage(&self) -> (usize, usize) {
+        let count = |flag: u8| self.prg.iter().filter(|flags| *flags & flag != 0).count();
+        (count(CDL_CODE), count(CDL_DATA))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::cartridge::test::test_rom;
+
+    fn run_logged(source: &str) -> (Rom, CodeDataLog) {
+        let rom = Rom::new(&test_rom(&asm::assemble(source).unwrap())).unwrap();
+        let mut log = CodeDataLog::new(&rom);
+        let mut cpu = CPU::default();
+        cpu.load_rom(&rom);
+        cpu.reset();
+        cpu.mem_write_u16(0x10, 0xc000);
+        let prg_len = rom.prg_rom.len();
+        cpu.run_until(|cpu| {
+            log.log_instruction(cpu, |addr| nrom_prg_offset(prg_len, addr));
+            cpu.at_brk()
+        })
+        .unwrap();
+        (rom, log)
+    }
+
+    #[test]
+    fn test_logs_code_data_and_indirect_access() {
+        let (_, log) = run_logged(
+            "
+            LDA table
+            LDA ($10),Y
+            BRK
+            table: .byte 1, 2
+            ",
+        );
+        assert_eq!(log.prg[0..3], [CDL_CODE; 3]);
+        assert_eq!(log.prg[5], CDL_CODE);
+        assert_eq!(log.prg[6], CDL_DATA);
+        assert_eq!(log.prg[7], 0);
+        // ($10),Y points at $C000, the start of the upper bank.
+        assert_eq!(log.prg[0x4000], CDL_DATA | CDL_INDIRECT_DATA | 0b1000);
+        assert_eq!(log.prg_coverage(), (6, 2));
+    }
+
+    #[test]
+    fn test_file_round_trip_and_merge() {
+        let (rom, first) = run_logged("LDA $8004\nBRK");
+        let bytes = first.to_bytes();
+        assert_eq!(bytes.len(), rom.prg_rom.len() + rom.chr_rom.len());
+        assert_eq!(CodeDataLog::from_bytes(&rom, &bytes).unwrap(), first);
+        assert_eq!(
+            CodeDataLog::from_bytes(&rom, &bytes[1..]),
+            Err(CdlError::SizeMismatch {
+                expected: bytes.len(),
+                actual: bytes.len() - 1
+            })
+        );
+
+        let mut merged = CodeDataLog::new(&rom);
+        merged.log_prg(4, 0x8004, CDL_CODE);
+        merged.log_chr(0, CDL_CHR_RENDERED);
+        merged.merge(&first).unwrap();
+        assert_eq!(merged.prg[0], CDL_CODE);
+        assert_eq!(merged.prg[4], CDL_CODE | CDL_DATA);
+        assert_eq!(merged.chr[0], CDL_CHR_RENDERED);
+    }
+
+    #[test]
+    fn test_indirect_jump_operand_wraps_past_ffff() {
+        let rom = Rom::new(&test_rom(&[])).unwrap();
+        let mut log = CodeDataLog::new(&rom);
+        let mut cpu = CPU::default();
+        cpu.load_rom(&rom);
+        // JMP ($C100) at $FFFE, its pointer's high byte wrapped to $0000.
+        cpu.program_counter = 0xfffe;
+        cpu.mem_write(0xfffe, 0x6c);
+        cpu.mem_write(0xffff, 0x00);
+        cpu.mem_write(0x0000, 0xc1);
+        cpu.mem_write_u16(0xc100, 0x8000);
+        let prg_len = rom.prg_rom.len();
+        log.log_instruction(&cpu, |addr| nrom_prg_offset(prg_len, addr));
+        assert_eq!(log.prg[0x7ffe..], [CDL_CODE | 0b1100; 2]);
+        assert_eq!(log.prg[0x4100..0x4102], [CDL_DATA | 0b1000; 2]);
+        assert_eq!(log.prg[0], CDL_INDIRECT_CODE);
+    }
+
+    #[test]
+    fn test_nrom_mirroring() {
+        assert_eq!(nrom_prg_offset(0x4000, 0xc123), Some(0x0123));
+        assert_eq!(nrom_prg_offset(0x8000, 0xc123), Some(0x4123));
+        assert_eq!(nrom_prg_offset(0x8000, 0x6000), None);
+    }
+}
@@ -0,0 +1,112 @@
+//! Game Genie codes: 6 letters for an address and value, 8 letters when a
+//! compare byte is added.
+//!
+//! Each letter is a nibble, and the bits of address, value and compare are
+//! scattered across them. The third letter's high bit marks an 8 letter
+//! code.
+
+use super::CheatError;
+
+const LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+/// Decodes a Game Genie code into the address, value and optional compare
+/// byte it patches.
+pub fn decode(code: &str) -> Result<(u16, u8, Option<u8>), CheatError> {
+    let bad_code = || CheatError::BadCode(code.to_string());
+    let n: Vec<u16> = code
+        .bytes()
+        .map(|letter| {
+            LETTERS
+                .iter()
+                .position(|&l| l == letter.to_ascii_uppercase())
+                .map(|nibble| nibble as u16)
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(bad_code)?;
+    if n.len() != 6 && n.len() != 8 {
+        return Err(bad_code());
+    }
+
+    let address = 0x8000
+        | ((n[3] & 7) << 12)
+        | ((n[5] & 7) << 8)
+        | ((n[4] & 8) << 8)
+        | ((n[2] & 7) << 4)
+        | ((n[1] & 8) << 4)
+        | (n[4] & 7)
+        | (n[3] & 8);
+    let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+    if n.len() == 6 {
+        return Ok((address, (value | (n[5] & 8)) as u8, None));
+    }
+    let compare = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+    Ok((address, (value | (n[7] & 8)) as u8, Some(compare as u8)))
+}
+
+/// Encodes a patch as a Game Genie code. Only the low 15 bits of
+/// `address` are stored; the code always applies to $8000-$FFFF.
+pub fn encode(address: u16, value: u8, compare: Option<u8>) -> String {
+    let address = address as usize;
+    let value = value as usize;
+    let mut n = [
+        (value & 7) | ((value >> 4) & 8),
+        ((value >> 4) & 7) | ((address >> 4) & 8),
+        (address >> 4) & 7,
+        ((address >> 12) & 7) | (address & 8),
+        (address & 7) | ((address >> 8) & 8),
+        ((address >> 8) & 7) | (value & 8),
+        0,
+        0,
+    ];
+    let len = match compare {
+        Some(compare) => {
+            let compare = compare as usize;
+            n[2] |= 8;
+            n[5] = ((address >> 8) & 7) | (compare & 8);
+            n[6] = (compare & 7) | ((compare >> 4) & 8);
+            n[7] = ((compare >> 4) & 7) | (value & 8);
+            8
+        }
+        None => 6,
+    };
+    n[..len]
+        .iter()
+        .map(|&nibble| LETTERS[nibble] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_codes() {
+        // Super Mario Bros.: infinite lives.
+        assert_eq!(decode("SXIOPO"), Ok((0x91d9, 0xad, None)));
+        assert_eq!(decode("sxiopo"), Ok((0x91d9, 0xad, None)));
+        assert_eq!(
+            decode("SXIOP"),
+            Err(CheatError::BadCode("SXIOP".to_string()))
+        );
+        assert_eq!(
+            decode("SXIOPB"),
+            Err(CheatError::BadCode("SXIOPB".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for &(address, value, compare) in &[
+            (0x8000, 0x00, None),
+            (0xffff, 0xff, None),
+            (0x91d9, 0xad, None),
+            (0xc44c, 0xad, Some(0xde)),
+            (0xa5a5, 0x5a, Some(0xa5)),
+        ] {
+            let code = encode(address, value, compare);
+            assert_eq!(code.len(), if compare.is_some() { 8 } else { 6 });
+            assert_eq!(decode(&code), Ok((address, value, compare)), "{}", code);
+        }
+        assert_eq!(encode(0x91d9, 0xad, None), "SXIOPO");
+    }
+}
@@ -0,0 +1,309 @@
+//! Cheats: RAM bytes held at fixed values and Game Genie patches of
+//! cartridge reads.
+//!
+//! Codes are written the way players know them: `SXIOPO` or `SXSUXEVK` for
+//! the Game Genie, `0075:09` for a Pro Action Replay RAM freeze.
+//! [`CheatList::apply`] writes every enabled freeze and installs the
+//! enabled patches on the CPU. Call it once per frame, or before every
+//! instruction from [`CPU::run_with_callback`] for a byte the game rewrites
+//! constantly.
+//!
+//! # Cheat files
+//!
+//! One code per line, optionally followed by a description. A leading `-`
+//! disables the cheat and `#` starts a comment line:
+//!
+//! ```text
+//! # Super Mario Bros.
+//! SXIOPO Infinite lives
+//! -0756:02 Fire Mario
+//! ```
+
+pub mod game_genie;
+
+use crate::cpu::{Mem, ReadPatch, CPU};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CheatError {
+    /// Neither a Game Genie code nor `AAAA:VV`.
+    BadCode(String),
+    /// A cheat file line that does not hold a valid code.
+    BadLine { line: usize, code: String },
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheatError::BadCode(code) => write!(f, "bad cheat code {}", code),
+            CheatError::BadLine { line, code } => {
+                write!(f, "line {}: bad cheat code {}", line, code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatKind {
+    /// Written to RAM on every apply, like a Pro Action Replay code.
+    Freeze,
+    /// Replaces what the CPU reads from cartridge space, like a Game Genie.
+    ReadPatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub kind: CheatKind,
+    pub address: u16,
+    pub value: u8,
+    /// For read patches, only replace the ROM byte if it equals this.
+    pub compare: Option<u8>,
+    pub enabled: bool,
+    pub description: String,
+}
+
+impl Cheat {
+    /// Holds `address` at `value`.
+    pub fn freeze(address: u16, value: u8) -> Self {
+        Cheat {
+            kind: CheatKind::Freeze,
+            address,
+            value,
+            compare: None,
+            enabled: true,
+            description: String::new(),
+        }
+    }
+
+    /// Makes reads of `address` in $8000-$FFFF return `value`.
+    pub fn read_patch(address: u16, value: u8, compare: Option<u8>) -> Self {
+        Cheat {
+            kind: CheatKind::ReadPatch,
+            address: address | 0x8000,
+            value,
+            compare,
+            enabled: true,
+            description: String::new(),
+        }
+    }
+
+    /// Parses a Game Genie code or a Pro Action Replay `AAAA:VV` code.
+    pub fn parse(code: &str) -> Result<Self, CheatError> {
+        match code.split_once(':') {
+            Some((address, value)) => {
+                let bad_code = || CheatError::BadCode(code.to_string());
+                if address.len() != 4 || value.len() != 2 {
+                    return Err(bad_code());
+                }
+                let address = u16::from_str_radix(address, 16).map_err(|_| bad_code())?;
+                let value = u8::from_str_radix(value, 16).map_err(|_| bad_code())?;
+                Ok(Cheat::freeze(address, value))
+            }
+            None => {
+                let (address, value, compare) = game_genie::decode(code)?;
+                Ok(Cheat::read_patch(address, value, compare))
+            }
+        }
+    }
+
+    /// The code for this cheat, as accepted by [`Cheat::parse`].
+    pub fn code(&self) -> String {
+        match self.kind {
+            CheatKind::Freeze => format!("{:04X}:{:02X}", self.address, self.value),
+            CheatKind::ReadPatch => game_genie::encode(self.address, self.value, self.compare),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CheatList {
+    cheats: Vec<Cheat>,
+}
+
+impl CheatList {
+    pub fn new() -> Self {
+        CheatList::default()
+    }
+
+    /// Reads a cheat file.
+    pub fn parse(text: &str) -> Result<Self, CheatError> {
+        let mut list = CheatList::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (enabled, line) = match line.strip_prefix('-') {
+                Some(rest) => (false, rest.trim_start()),
+                None => (true, line),
+            };
+            let (code, description) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let mut cheat = Cheat::parse(code).map_err(|_| CheatError::BadLine {
+                line: index + 1,
+                code: code.to_string(),
+            })?;
+            cheat.enabled = enabled;
+            cheat.description = description.trim().to_string();
+            list.cheats.push(cheat);
+        }
+        Ok(list)
+    }
+
+    /// Writes the list in the format [`CheatList::parse`] reads.
+    pub fn to_text(&self) -> String {
+        self.cheats
+            .iter()
+            .map(|cheat| {
+                let line = format!(
+                    "{}{} {}",
+                    if cheat.enabled { "" } else { "-" },
+                    cheat.code(),
+                    cheat.description
+                );
+                line.trim_end().to_string() + "\n"
+            })
+            .collect()
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    /// Adds `cheat`, replacing an earlier one of the same kind for the same
+    /// address, and returns its index.
+    pub fn add(&mut self, cheat: Cheat) -> usize {
+        match self
+            .cheats
+            .iter()
+            .position(|other| other.kind == cheat.kind && other.address == cheat.address)
+        {
+            Some(index) => {
+                self.cheats[index] = cheat;
+                index
+            }
+            None => {
+                self.cheats.push(cheat);
+                self.cheats.len() - 1
+            }
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Cheat> {
+        (index < self.cheats.len()).then(|| self.cheats.remove(index))
+    }
+
+    /// Turns the cheat at `index` on or off; false if there is none.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.cheats.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cheats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    /// Writes the value of every enabled freeze and makes the CPU's read
+    /// patches match the enabled read patches.
+    pub fn apply(&self, cpu: &mut CPU) {
+        let enabled = || self.cheats.iter().filter(|cheat| cheat.enabled);
+        for cheat in enabled().filter(|cheat| cheat.kind == CheatKind::Freeze) {
+            cpu.mem_write(cheat.address, cheat.value);
+        }
+
+        let patches = || {
+            enabled()
+                .filter(|cheat| cheat.kind == CheatKind::ReadPatch)
+                .map(|cheat| ReadPatch {
+                    address: cheat.address,
+                    value: cheat.value,
+                    compare: cheat.compare,
+                })
+        };
+        if !cpu.read_patches().iter().copied().eq(patches()) {
+            cpu.set_read_patches(patches().collect());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_freeze_and_replace() {
+        let mut cheats = CheatList::new();
+        assert_eq!(cheats.add(Cheat::freeze(0x0075, 9)), 0);
+        assert_eq!(cheats.add(Cheat::freeze(0x6000, 1)), 1);
+        assert_eq!(cheats.add(Cheat::freeze(0x0075, 5)), 0);
+        assert_eq!(cheats.len(), 2);
+
+        let mut cpu = CPU::default();
+        cheats.apply(&mut cpu);
+        assert_eq!(cpu.mem_read(0x0075), 5);
+        assert_eq!(cpu.mem_read(0x6000), 1);
+
+        assert_eq!(cheats.remove(1), Some(Cheat::freeze(0x6000, 1)));
+        assert_eq!(cheats.remove(1), None);
+    }
+
+    #[test]
+    fn test_parse_codes() {
+        assert_eq!(Cheat::parse("0075:09"), Ok(Cheat::freeze(0x0075, 0x09)));
+        assert_eq!(
+            Cheat::parse("SXIOPO"),
+            Ok(Cheat::read_patch(0x91d9, 0xad, None))
+        );
+        assert_eq!(Cheat::parse("sxiopo").unwrap().code(), "SXIOPO");
+        assert_eq!(Cheat::freeze(0x0756, 2).code(), "0756:02");
+        assert_eq!(
+            Cheat::parse("75:9"),
+            Err(CheatError::BadCode("75:9".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_cheat_file_round_trip() {
+        let text = "# Super Mario Bros.\nSXIOPO  Infinite lives\n-0756:02 Fire Mario\n\n";
+        let list = CheatList::parse(text).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.cheats()[0].description, "Infinite lives");
+        assert!(!list.cheats()[1].enabled);
+        assert_eq!(
+            list.to_text(),
+            "SXIOPO Infinite lives\n-0756:02 Fire Mario\n"
+        );
+
+        assert_eq!(
+            CheatList::parse("SXIOPO\nbogus code").unwrap_err(),
+            CheatError::BadLine {
+                line: 2,
+                code: "bogus".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_toggle_read_patch() {
+        let mut cpu = CPU::default();
+        cpu.mem_write(0x91d9, 0xce);
+        let mut list = CheatList::parse("SXIOPO").unwrap();
+        list.apply(&mut cpu);
+        assert_eq!(cpu.mem_read(0x91d9), 0xad);
+
+        list.set_enabled(0, false);
+        list.apply(&mut cpu);
+        assert_eq!(cpu.mem_read(0x91d9), 0xce);
+        assert!(!list.set_enabled(1, true));
+    }
+}
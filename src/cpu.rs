@@ -1,5 +1,7 @@
-use crate::opcodes;
-use std::collections::HashMap;
+use crate::cartridge::Rom;
+use crate::input::InputPorts;
+use crate::opcodes::{CPU_OPS_CODES, OPCODES_MAP};
+use std::fmt;
 
 /// # Status Register (P) http://wiki.nesdev.com/w/index.php/Status_flags
 ///
@@ -13,16 +15,168 @@ use std::collections::HashMap;
 ///  | +--------------- OFLAG_ZEROverflow Flag
 ///  +----------------- Negative Flag
 ///
-const STATUS_CARRY: u8 = 0b0000_0001;
-const STATUS_ZERO: u8 = 0b0000_0010;
-const STATUS_INTERRUPT_DISABLE: u8 = 0b0000_0100;
-const STATUS_DECIMAL_MODE: u8 = 0b0000_1000;
-const STATUS_BREAK: u8 = 0b0001_0000;
-const STATUS_BREAK2: u8 = 0b0010_0000;
-const STATUS_OVERFLOW: u8 = 0b0100_0000;
-const STATUS_NEGATIVE: u8 = 0b1000_0000;
-
-#[derive(Debug)]
+pub const STATUS_CARRY: u8 = 0b0000_0001;
+pub const STATUS_ZERO: u8 = 0b0000_0010;
+pub const STATUS_INTERRUPT_DISABLE: u8 = 0b0000_0100;
+pub const STATUS_DECIMAL_MODE: u8 = 0b0000_1000;
+pub const STATUS_BREAK: u8 = 0b0001_0000;
+pub const STATUS_BREAK2: u8 = 0b0010_0000;
+pub const STATUS_OVERFLOW: u8 = 0b0100_0000;
+pub const STATUS_NEGATIVE: u8 = 0b1000_0000;
+
+const STACK: u16 = 0x0100;
+pub const STACK_RESET: u8 = 0xfd;
+/// Cycles spent by the reset sequence before the first instruction.
+pub const RESET_CYCLES: usize = 7;
+const IRQ_VECTOR: u16 = 0xfffe;
+const OPCODE_BRK: u8 = 0x00;
+/// Upper bits of a controller port read, left over from the address byte
+/// on the data bus.
+const OPEN_BUS: u8 = 0x40;
+/// Opcodes that lock up the 6502 until it is reset.
+const JAM_OPCODES: [u8; 12] = [
+    0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xb2, 0xd2, 0xf2,
+];
+
+/// Replaces the byte the CPU reads at `address` in cartridge space with
+/// `value`, only while the ROM holds `compare` there if given. This is how
+/// a Game Genie patches a game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadPatch {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusAccess {
+    Read,
+    Write,
+}
+
+/// One CPU cycle on the bus. Every cycle reads or writes, including the
+/// dummy reads instructions make while they work out an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusCycle {
+    pub address: u16,
+    pub value: u8,
+    pub access: BusAccess,
+}
+
+/// Why [`CPU::step`] and the bounded runs returned without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The hook given to [`CPU::run_with_hook`] returned [`Hook::Break`].
+    Hook,
+    /// [`CPU::step`] ran its instruction.
+    Step,
+    /// [`CPU::run_for_cycles`] used up its cycles.
+    Cycles,
+    /// [`CPU::run_for_instructions`] ran all its instructions.
+    Instructions,
+    /// The predicate given to [`CPU::run_until`] held.
+    Condition,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// A byte that is not a 6502 opcode.
+    IllegalOpcode,
+    /// One of the opcodes that lock up the 6502.
+    Jam,
+    /// An opcode this CPU does not execute yet.
+    Unimplemented,
+    /// An instruction used an addressing mode its implementation does not
+    /// handle.
+    UnsupportedMode(AddressingMode),
+}
+
+/// An instruction the CPU could not execute. The program counter is left
+/// at its opcode, so the caller can reset, skip the instruction or stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuError {
+    /// Address of the opcode.
+    pub pc: u16,
+    pub opcode: u8,
+    pub kind: FaultKind,
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mnemonic = OPCODES_MAP
+            .get(&self.opcode)
+            .map_or("???", |opcode| opcode.mnemonic);
+        match self.kind {
+            FaultKind::IllegalOpcode => write!(f, "illegal opcode ${:02X}", self.opcode)?,
+            FaultKind::Jam => write!(f, "JAM opcode ${:02X}", self.opcode)?,
+            FaultKind::Unimplemented => write!(
+                f,
+                "{} (opcode ${:02X}) is not implemented",
+                mnemonic, self.opcode
+            )?,
+            FaultKind::UnsupportedMode(mode) => write!(
+                f,
+                "{} (opcode ${:02X}) does not support {:?} addressing",
+                mnemonic, self.opcode, mode
+            )?,
+        }
+        write!(f, " at ${:04X}", self.pc)
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+pub(crate) type Handler = fn(&mut CPU, &AddressingMode) -> Result<(), FaultKind>;
+
+/// A slot of the dispatch table: how the opcode addresses memory, its
+/// length and base cycle count, and the function that executes it.
+#[derive(Clone, Copy)]
+struct OpEntry {
+    mode: AddressingMode,
+    len: u8,
+    cycles: u8,
+    handler: Handler,
+}
+
+/// One entry per opcode byte, built from [`CPU_OPS_CODES`] at compile time.
+/// Bytes missing from it get a handler that rejects them.
+static DISPATCH: [OpEntry; 256] = dispatch_table();
+
+const fn dispatch_table() -> [OpEntry; 256] {
+    let mut table = [OpEntry {
+        mode: AddressingMode::NoneAddressing,
+        len: 1,
+        cycles: 0,
+        handler: CPU::illegal,
+    }; 256];
+    let mut i = 0;
+    while i < JAM_OPCODES.len() {
+        table[JAM_OPCODES[i] as usize].handler = CPU::jam;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < CPU_OPS_CODES.len() {
+        let opcode = &CPU_OPS_CODES[i];
+        table[opcode.code as usize] = OpEntry {
+            mode: opcode.mode,
+            len: opcode.len,
+            cycles: opcode.cycles,
+            handler: opcode.handler,
+        };
+        i += 1;
+    }
+    table
+}
+
+/// Returned by a [`CPU::run_with_hook`] callback to keep running or to stop
+/// before the instruction at the program counter executes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    Continue,
+    Break,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -36,16 +190,19 @@ pub enum AddressingMode {
     Indirect_Y,
     NoneAddressing,
     Relative,
+    Indirect,
+    Accumulator,
 }
 
-trait Mem {
+pub trait Mem {
     fn mem_read(&self, addr: u16) -> u8;
 
     fn mem_write(&mut self, addr: u16, data: u8);
 
+    /// Reads a little-endian word; the high byte at $FFFF comes from $0000.
     fn mem_read_u16(&self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
         (hi << 8) | lo
     }
 
@@ -53,13 +210,22 @@ trait Mem {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
         self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+        self.mem_write(pos.wrapping_add(1), hi);
     }
 }
 
 impl Mem for CPU {
     fn mem_read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+        let data = self.memory[addr as usize];
+        if addr < 0x8000 || self.read_patches.is_empty() {
+            return data;
+        }
+        self.read_patches
+            .iter()
+            .find(|patch| {
+                patch.address == addr && patch.compare.is_none_or(|compare| compare == data)
+            })
+            .map_or(data, |patch| patch.value)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
@@ -73,7 +239,15 @@ pub struct CPU {
     pub register_y: u8,
     pub status: u8,
     pub program_counter: u16,
-    memory: [u8; 0x10000],
+    pub stack_pointer: u8,
+    /// CPU cycles elapsed since power-on.
+    pub cycles: usize,
+    pub(crate) memory: [u8; 0x10000],
+    pub(crate) read_patches: Vec<ReadPatch>,
+    pub(crate) bus_log: Option<Vec<BusCycle>>,
+    /// Controllers answering bus reads of $4016/$4017 and strobed by
+    /// writes to $4016. Without them both addresses are plain memory.
+    pub ports: Option<InputPorts>,
 }
 
 impl Default for CPU {
@@ -84,124 +258,242 @@ impl Default for CPU {
             register_y: 0,
             status: 0,
             program_counter: 0,
+            stack_pointer: STACK_RESET,
+            cycles: 0,
             memory: [0; 0x10000],
+            read_patches: Vec::new(),
+            bus_log: None,
+            ports: None,
         }
     }
 }
 
 impl CPU {
-    fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
-        match mode {
-            AddressingMode::Immediate => self.program_counter,
+    /// Reads `addr` as a bus cycle of its own, logging it if enabled.
+    fn bus_read(&mut self, addr: u16) -> u8 {
+        let value = match (addr, &mut self.ports) {
+            (0x4016 | 0x4017, Some(ports)) => OPEN_BUS | ports.read(addr),
+            _ => self.mem_read(addr),
+        };
+        self.cycles += 1;
+        if let Some(log) = &mut self.bus_log {
+            log.push(BusCycle {
+                address: addr,
+                value,
+                access: BusAccess::Read,
+            });
+        }
+        value
+    }
+
+    /// Writes `addr` as a bus cycle of its own, logging it if enabled.
+    fn bus_write(&mut self, addr: u16, data: u8) {
+        match (addr, &mut self.ports) {
+            (0x4016, Some(ports)) => ports.write(data),
+            _ => self.mem_write(addr, data),
+        }
+        self.cycles += 1;
+        if let Some(log) = &mut self.bus_log {
+            log.push(BusCycle {
+                address: addr,
+                value: data,
+                access: BusAccess::Write,
+            });
+        }
+    }
 
-            AddressingMode::ZeroPage => self.mem_read(self.program_counter) as u16,
+    /// Reads the byte at the program counter and steps past it.
+    fn fetch(&mut self) -> u8 {
+        let value = self.bus_read(self.program_counter);
+        self.program_counter = self.program_counter.wrapping_add(1);
+        value
+    }
 
-            AddressingMode::Absolute => self.mem_read_u16(self.program_counter),
+    /// The cycle implied instructions spend reading the next byte and
+    /// throwing it away.
+    fn dummy_fetch(&mut self) {
+        self.bus_read(self.program_counter);
+    }
 
-            AddressingMode::ZeroPage_X => {
-                let pos = self.mem_read(self.program_counter);
-                pos.wrapping_add(self.register_x) as u16
+    /// Fetches the operand of an instruction and works out the address it
+    /// accesses, with the dummy reads the 6502 makes on the way. Indexed
+    /// modes first read from the address before the carry into the high
+    /// byte is fixed up; loads skip that cycle unless the index crosses a
+    /// page, stores (`always_fix`) never do.
+    fn operand_address(
+        &mut self,
+        mode: &AddressingMode,
+        always_fix: bool,
+    ) -> Result<u16, FaultKind> {
+        let addr = match mode {
+            AddressingMode::Immediate => {
+                let addr = self.program_counter;
+                self.program_counter = self.program_counter.wrapping_add(1);
+                addr
             }
-            AddressingMode::ZeroPage_Y => {
-                let pos = self.mem_read(self.program_counter);
-                pos.wrapping_add(self.register_y) as u16
+
+            AddressingMode::ZeroPage => self.fetch() as u16,
+
+            AddressingMode::Absolute => {
+                let lo = self.fetch() as u16;
+                let hi = self.fetch() as u16;
+                hi << 8 | lo
             }
 
-            AddressingMode::Absolute_X => {
-                let base: u16 = self.mem_read_u16(self.program_counter);
-                base.wrapping_add(self.register_x as u16)
+            AddressingMode::ZeroPage_X | AddressingMode::ZeroPage_Y => {
+                let pos = self.fetch();
+                self.bus_read(pos as u16);
+                let index = match mode {
+                    AddressingMode::ZeroPage_X => self.register_x,
+                    _ => self.register_y,
+                };
+                pos.wrapping_add(index) as u16
             }
-            AddressingMode::Absolute_Y => {
-                let base = self.mem_read_u16(self.program_counter);
-                base.wrapping_add(self.register_y as u16)
+
+            AddressingMode::Absolute_X | AddressingMode::Absolute_Y => {
+                let lo = self.fetch() as u16;
+                let hi = self.fetch() as u16;
+                let index = match mode {
+                    AddressingMode::Absolute_X => self.register_x,
+                    _ => self.register_y,
+                };
+                self.index_address(hi << 8 | lo, index, always_fix)
             }
 
             AddressingMode::Indirect_X => {
-                let base: u8 = self.mem_read(self.program_counter);
-
-                let ptr: u8 = base.wrapping_add(self.register_x);
-                let lo = self.mem_read(ptr as u16);
-                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
-                (hi as u16) << 8 | (lo as u16)
+                let base = self.fetch();
+                self.bus_read(base as u16);
+                let ptr = base.wrapping_add(self.register_x);
+                let lo = self.bus_read(ptr as u16) as u16;
+                let hi = self.bus_read(ptr.wrapping_add(1) as u16) as u16;
+                hi << 8 | lo
             }
+
             AddressingMode::Indirect_Y => {
-                let base = self.mem_read(self.program_counter);
+                let ptr = self.fetch();
+                let lo = self.bus_read(ptr as u16) as u16;
+                let hi = self.bus_read(ptr.wrapping_add(1) as u16) as u16;
+                self.index_address(hi << 8 | lo, self.register_y, always_fix)
+            }
 
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read(base.wrapping_add(1) as u16);
-                let deref_base = (hi as u16) << 8 | (lo as u16);
+            AddressingMode::NoneAddressing
+            | AddressingMode::Relative
+            | AddressingMode::Indirect
+            | AddressingMode::Accumulator => return Err(FaultKind::UnsupportedMode(*mode)),
+        };
+        Ok(addr)
+    }
 
-                deref_base.wrapping_add(self.register_y as u16)
-            }
+    /// Adds `index` to `base`, reading the address with the unfixed high
+    /// byte when the 6502 would.
+    fn index_address(&mut self, base: u16, index: u8, always_fix: bool) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        if always_fix || addr & 0xff00 != base & 0xff00 {
+            self.bus_read(base & 0xff00 | addr & 0x00ff);
+        }
+        addr
+    }
 
-            AddressingMode::NoneAddressing => {
-                panic!("mode {:?} is not supported", mode);
-            }
+    pub fn load(&mut self, program: Vec<u8>) {
+        self.memory[0x8000..(0x8000 + program.len())].copy_from_slice(&program[..]);
+        self.mem_write_u16(0xFFFC, 0x8000);
+    }
 
-            AddressingMode::Relative => {
-                panic!("mode {:?} is not supported", mode);
-            }
+    /// Maps the PRG ROM of an NROM cartridge at $8000, mirroring a single
+    /// 16 KiB bank into $C000, and leaves its reset vector in place.
+    pub fn load_rom(&mut self, rom: &Rom) {
+        if rom.prg_rom.is_empty() {
+            return;
+        }
+        for bank in self.memory[0x8000..].chunks_mut(rom.prg_rom.len()) {
+            bank.copy_from_slice(&rom.prg_rom[..bank.len()]);
         }
     }
 
-    fn mem_read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+    pub fn read_patches(&self) -> &[ReadPatch] {
+        &self.read_patches
     }
 
-    fn mem_write(&mut self, addr: u16, data: u8) {
-        self.memory[addr as usize] = data;
+    /// Replaces the patches applied to reads from $8000-$FFFF.
+    pub fn set_read_patches(&mut self, patches: Vec<ReadPatch>) {
+        self.read_patches = patches;
     }
 
-    fn mem_read_u16(&self, pos: u16) -> u16 {
-        let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
-        (hi << 8) | lo
+    /// Starts or stops recording every bus cycle. Starting clears the log.
+    pub fn set_bus_logging(&mut self, enabled: bool) {
+        self.bus_log = enabled.then(Vec::new);
     }
 
-    fn mem_write_u16(&mut self, pos: u16, data: u16) {
-        let hi = (data >> 8) as u8;
-        let lo = (data & 0xff) as u8;
-        self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+    /// Bus cycles recorded since logging started or the log was taken.
+    pub fn bus_log(&self) -> &[BusCycle] {
+        self.bus_log.as_deref().unwrap_or(&[])
     }
 
-    pub fn load(&mut self, program: Vec<u8>) {
-        self.memory[0x8000..(0x8000 + program.len())].copy_from_slice(&program[..]);
-        self.mem_write_u16(0xFFFC, 0x8000);
+    /// Returns the recorded bus cycles and empties the log.
+    pub fn take_bus_log(&mut self) -> Vec<BusCycle> {
+        self.bus_log
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
+    /// Loads `program`, resets and runs it up to the BRK that ends it,
+    /// which does not run.
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<StopReason, CpuError> {
         self.load(program);
         self.reset();
-        self.run()
+        self.run_until(CPU::at_brk)
     }
 
     pub fn reset(&mut self) {
-        println!("Resetting CPU");
         self.register_a = 0;
         self.register_x = 0;
         self.status = 0;
+        self.stack_pointer = STACK_RESET;
+        self.cycles = RESET_CYCLES;
 
         self.program_counter = self.mem_read_u16(0xFFFC);
     }
 
-    fn lda(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+    /// Turning the console off and on: internal RAM is cleared and the CPU
+    /// starts over as after [`CPU::reset`], while the cycle counter keeps
+    /// running.
+    pub fn power_cycle(&mut self) {
+        let cycles = self.cycles;
+        self.memory[..0x0800].fill(0);
+        self.reset();
+        self.cycles = cycles + RESET_CYCLES;
+    }
+
+    /// Pressing the reset button: registers and RAM keep their contents,
+    /// the stack pointer drops by three as if an interrupt pushed, and
+    /// interrupts are disabled.
+    pub fn soft_reset(&mut self) {
+        self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+        self.status |= STATUS_INTERRUPT_DISABLE;
+        self.cycles += RESET_CYCLES;
+        self.program_counter = self.mem_read_u16(0xFFFC);
+    }
+
+    pub(crate) fn lda(&mut self, mode: &AddressingMode) -> Result<(), FaultKind> {
+        let addr = self.operand_address(mode, false)?;
+        let value = self.bus_read(addr);
 
         self.register_a = value;
         self.update_zero_and_negative_flags(self.register_a);
+        Ok(())
     }
 
-    fn sta(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+    pub(crate) fn sta(&mut self, mode: &AddressingMode) -> Result<(), FaultKind> {
+        let addr = self.operand_address(mode, true)?;
         let value = self.register_a;
-        self.mem_write(addr, value);
+        self.bus_write(addr, value);
+        Ok(())
     }
 
-    fn adc(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
-        let value = self.mem_read(addr);
+    pub(crate) fn adc(&mut self, mode: &AddressingMode) -> Result<(), FaultKind> {
+        let addr = self.operand_address(mode, false)?;
+        let value = self.bus_read(addr);
         let carry_flag = self.status & STATUS_CARRY;
 
         let (rhs, overflow) = value.overflowing_add(carry_flag);
@@ -221,17 +513,87 @@ impl CPU {
 
         self.register_a = result;
         self.update_zero_and_negative_flags(self.register_a);
+        Ok(())
     }
 
-    fn tax(&mut self) {
+    pub(crate) fn tax(&mut self, _mode: &AddressingMode) -> Result<(), FaultKind> {
+        self.dummy_fetch();
         self.register_x = self.register_a;
         self.update_zero_and_negative_flags(self.register_x);
+        Ok(())
     }
 
-    fn inx(&mut self) {
-        println!("inx");
+    pub(crate) fn inx(&mut self, _mode: &AddressingMode) -> Result<(), FaultKind> {
+        self.dummy_fetch();
         self.register_x = self.register_x.wrapping_add(1);
         self.update_zero_and_negative_flags(self.register_x);
+        Ok(())
+    }
+
+    pub(crate) fn jsr(&mut self, _mode: &AddressingMode) -> Result<(), FaultKind> {
+        let lo = self.fetch() as u16;
+        // The stack pointer is put on the bus while the 6502 waits.
+        self.bus_read(STACK + self.stack_pointer as u16);
+        // The return address pushed is that of the last byte of the JSR,
+        // where the program counter is now.
+        self.stack_push_u16(self.program_counter);
+        let hi = self.bus_read(self.program_counter) as u16;
+        self.program_counter = hi << 8 | lo;
+        Ok(())
+    }
+
+    pub(crate) fn rts(&mut self, _mode: &AddressingMode) -> Result<(), FaultKind> {
+        self.dummy_fetch();
+        self.bus_read(STACK + self.stack_pointer as u16);
+        self.program_counter = self.stack_pop_u16();
+        self.fetch();
+        Ok(())
+    }
+
+    pub(crate) fn brk(&mut self, _mode: &AddressingMode) -> Result<(), FaultKind> {
+        // The byte after BRK is read and skipped, so the return address is
+        // two past the opcode.
+        self.fetch();
+        self.stack_push_u16(self.program_counter);
+        self.stack_push(self.status | STATUS_BREAK | STATUS_BREAK2);
+        self.status |= STATUS_INTERRUPT_DISABLE;
+        let lo = self.bus_read(IRQ_VECTOR) as u16;
+        let hi = self.bus_read(IRQ_VECTOR + 1) as u16;
+        self.program_counter = hi << 8 | lo;
+        Ok(())
+    }
+
+    pub(crate) fn unimplemented(&mut self, _mode: &AddressingMode) -> Result<(), FaultKind> {
+        Err(FaultKind::Unimplemented)
+    }
+
+    fn illegal(&mut self, _mode: &AddressingMode) -> Result<(), FaultKind> {
+        Err(FaultKind::IllegalOpcode)
+    }
+
+    fn jam(&mut self, _mode: &AddressingMode) -> Result<(), FaultKind> {
+        Err(FaultKind::Jam)
+    }
+
+    fn stack_push(&mut self, data: u8) {
+        self.bus_write(STACK + self.stack_pointer as u16, data);
+        self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+    }
+
+    fn stack_pop(&mut self) -> u8 {
+        self.stack_pointer = self.stack_pointer.wrapping_add(1);
+        self.bus_read(STACK + self.stack_pointer as u16)
+    }
+
+    fn stack_push_u16(&mut self, data: u16) {
+        self.stack_push((data >> 8) as u8);
+        self.stack_push((data & 0xff) as u8);
+    }
+
+    fn stack_pop_u16(&mut self) -> u16 {
+        let lo = self.stack_pop() as u16;
+        let hi = self.stack_pop() as u16;
+        hi << 8 | lo
     }
 
     fn update_zero_and_negative_flags(&mut self, result: u8) {
@@ -248,56 +610,127 @@ impl CPU {
         }
     }
 
-    pub fn run(&mut self) {
-        let opcodes: &HashMap<u8, &'static opcodes::OpCode> = &opcodes::OPCODES_MAP;
+    /// Runs until an instruction faults. BRK is an ordinary instruction,
+    /// so use a bounded run or [`CPU::run_until`] to stop sooner.
+    ///
+    /// Nothing but a fault ends the run, so this only ever returns an
+    /// error; the `Result` is the same as the other runs return.
+    pub fn run(&mut self) -> Result<StopReason, CpuError> {
+        self.run_with_callback(|_| {})
+    }
 
-        loop {
-            let code = self.mem_read(self.program_counter);
-            println!(
-                "run opscode: {:x}, program_counter: {:x}",
-                code, self.program_counter
-            );
-            self.program_counter += 1;
-
-            let program_counter_state = self.program_counter;
-            let opcode = opcodes
-                .get(&code)
-                .unwrap_or_else(|| panic!("OpCode {:x} is not recognized", code));
-
-            match code {
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    self.lda(&opcode.mode);
-                }
-                /* STA */
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
-                /* ADC */
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
-                0xAA => self.tax(),
-                0xE8 => self.inx(),
-                // 0x10 => self.bpl(&opcode.mode),
-                0x00 => return,
-                _ => todo!(),
+    /// Runs like [`CPU::run`], calling `callback` before every instruction.
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<StopReason, CpuError>
+    where
+        F: FnMut(&mut CPU),
+    {
+        self.run_loop(|cpu| {
+            callback(cpu);
+            None
+        })
+    }
+
+    /// Runs, calling `hook` before every instruction, until it returns
+    /// [`Hook::Break`] or an instruction faults.
+    pub fn run_with_hook<F>(&mut self, mut hook: F) -> Result<StopReason, CpuError>
+    where
+        F: FnMut(&mut CPU) -> Hook,
+    {
+        self.run_loop(|cpu| (hook(cpu) == Hook::Break).then_some(StopReason::Hook))
+    }
+
+    /// Runs until at least `cycles` more cycles have elapsed. Instructions
+    /// are not split, so the last one may end a few cycles past the budget.
+    pub fn run_for_cycles(&mut self, cycles: usize) -> Result<StopReason, CpuError> {
+        let end = self.cycles.saturating_add(cycles);
+        self.run_loop(|cpu| (cpu.cycles >= end).then_some(StopReason::Cycles))
+    }
+
+    /// Runs at most `count` instructions.
+    pub fn run_for_instructions(&mut self, count: usize) -> Result<StopReason, CpuError> {
+        let mut left = count;
+        self.run_loop(|_| {
+            if left == 0 {
+                return Some(StopReason::Instructions);
             }
+            left -= 1;
+            None
+        })
+    }
+
+    /// Runs until `predicate` holds before an instruction. With
+    /// [`CPU::at_brk`] this runs a test program up to its closing BRK.
+    pub fn run_until<F>(&mut self, mut predicate: F) -> Result<StopReason, CpuError>
+    where
+        F: FnMut(&CPU) -> bool,
+    {
+        self.run_loop(|cpu| predicate(cpu).then_some(StopReason::Condition))
+    }
+
+    /// Runs the instruction at the program counter.
+    pub fn step(&mut self) -> Result<StopReason, CpuError> {
+        self.execute()?;
+        Ok(StopReason::Step)
+    }
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+    /// Whether the instruction at the program counter is BRK.
+    pub fn at_brk(&self) -> bool {
+        self.mem_read(self.program_counter) == OPCODE_BRK
+    }
+
+    /// Whether the instruction at the program counter would lock up the CPU.
+    pub fn at_jam(&self) -> bool {
+        JAM_OPCODES.contains(&self.mem_read(self.program_counter))
+    }
+
+    /// Executes instructions until `stop` gives a reason before one or an
+    /// instruction faults.
+    fn run_loop<F>(&mut self, mut stop: F) -> Result<StopReason, CpuError>
+    where
+        F: FnMut(&mut CPU) -> Option<StopReason>,
+    {
+        loop {
+            if let Some(reason) = stop(self) {
+                return Ok(reason);
             }
+            self.execute()?;
         }
     }
+
+    /// Moves the program counter past the instruction there without
+    /// running it, e.g. to go on after a fault.
+    pub fn skip_instruction(&mut self) {
+        let entry = &DISPATCH[self.mem_read(self.program_counter) as usize];
+        self.program_counter = self.program_counter.wrapping_add(entry.len as u16);
+    }
+
+    fn execute(&mut self) -> Result<(), CpuError> {
+        let pc = self.program_counter;
+        let cycles = self.cycles;
+        let opcode = self.fetch();
+        let entry = &DISPATCH[opcode as usize];
+        (entry.handler)(self, &entry.mode).map_err(|kind| {
+            self.program_counter = pc;
+            CpuError { pc, opcode, kind }
+        })?;
+        debug_assert!(
+            self.cycles - cycles >= entry.cycles as usize,
+            "${:02X} took fewer cycles than the opcode table says",
+            opcode
+        );
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assemble;
 
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
         let mut cpu = CPU::default();
-        cpu.load_and_run(vec![0xa9, 0x05, 0x00]);
+        cpu.load_and_run(assemble!("LDA #$05", "BRK")).unwrap();
         assert_eq!(cpu.register_a, 0x05);
         assert!(cpu.status & STATUS_ZERO == 0b00);
         assert!(cpu.status & STATUS_NEGATIVE == 0b0000_0000);
@@ -306,7 +739,7 @@ mod tests {
     #[test]
     fn test_0xa9_lda_zero_flag() {
         let mut cpu = CPU::default();
-        cpu.load_and_run(vec![0xa9, 0x00, 0x00]);
+        cpu.load_and_run(assemble!("LDA #$00", "BRK")).unwrap();
         assert!(cpu.status & STATUS_ZERO == 0b10);
         assert!(cpu.status & STATUS_NEGATIVE == 0b0000_0000);
     }
@@ -314,7 +747,7 @@ mod tests {
     #[test]
     fn test_0xa9_lda_negative_flag() {
         let mut cpu = CPU::default();
-        cpu.load_and_run(vec![0xa9, 0x80, 0x00]);
+        cpu.load_and_run(assemble!("LDA #$80", "BRK")).unwrap();
         assert!(cpu.status & STATUS_NEGATIVE == STATUS_NEGATIVE);
     }
 
@@ -322,10 +755,10 @@ mod tests {
     fn test_0xaa_tax_move_a_to_x() {
         let mut cpu = CPU::default();
 
-        cpu.load(vec![0xaa, 0x00]);
+        cpu.load(assemble!("TAX", "BRK"));
         cpu.reset();
         cpu.register_a = 10;
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_x, 10);
         assert!(cpu.status & STATUS_ZERO == 0b00);
@@ -336,10 +769,10 @@ mod tests {
     fn test_0xaa_tax_move_a_to_x_negative() {
         let mut cpu = CPU::default();
 
-        cpu.load(vec![0xaa, 0x00]);
+        cpu.load(assemble!("TAX", "BRK"));
         cpu.reset();
         cpu.register_a = 0x80;
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_x, 0x80);
         assert!(cpu.status & STATUS_ZERO == 0b00);
@@ -349,10 +782,10 @@ mod tests {
     #[test]
     fn test_5_ops_working_together() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+        cpu.load(assemble!("LDA #$c0", "TAX", "INX", "BRK"));
         cpu.reset();
         cpu.register_x = 0xff;
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_x, 0xc1)
     }
@@ -360,10 +793,10 @@ mod tests {
     #[test]
     fn test_inx_overflow() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0xe8, 0xe8, 0x00]);
+        cpu.load(assemble!("INX", "INX", "BRK"));
         cpu.reset();
         cpu.register_x = 0xff;
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_x, 1)
     }
@@ -371,10 +804,10 @@ mod tests {
     #[test]
     fn test_lda_from_memory_zero_page() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0xa5, 0x10, 0x00]);
+        cpu.load(assemble!("LDA $10", "BRK"));
         cpu.reset();
         cpu.mem_write(0x10, 0x55);
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_a, 0x55);
     }
@@ -382,11 +815,11 @@ mod tests {
     #[test]
     fn test_lda_from_memory_zero_page_x() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0xb5, 0x10, 0x00]);
+        cpu.load(assemble!("LDA $10,X", "BRK"));
         cpu.reset();
         cpu.register_x = 0x01;
         cpu.mem_write(0x11, 0x56);
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_a, 0x56);
     }
@@ -394,10 +827,10 @@ mod tests {
     #[test]
     fn test_lda_from_memory_absolute() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0xad, 0x10, 0x20, 0x00]);
+        cpu.load(assemble!("LDA $2010", "BRK"));
         cpu.reset();
         cpu.mem_write(0x2010, 0x57);
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_a, 0x57);
     }
@@ -405,11 +838,11 @@ mod tests {
     #[test]
     fn test_lda_from_memory_absolute_x() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0xbd, 0x11, 0x21, 0x00]);
+        cpu.load(assemble!("LDA $2111,X", "BRK"));
         cpu.reset();
         cpu.register_x = 0x01;
         cpu.mem_write(0x2112, 0x58);
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_a, 0x58);
     }
@@ -417,11 +850,11 @@ mod tests {
     #[test]
     fn test_lda_from_memory_absolute_y() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0xb9, 0x12, 0x22, 0x00]);
+        cpu.load(assemble!("LDA $2212,Y", "BRK"));
         cpu.reset();
         cpu.register_y = 0x02;
         cpu.mem_write(0x2214, 0x59);
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_a, 0x59);
     }
@@ -429,12 +862,12 @@ mod tests {
     #[test]
     fn test_lda_from_memory_indirect_x() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0xa1, 0x11, 0x00]);
+        cpu.load(assemble!("LDA ($11,X)", "BRK"));
         cpu.reset();
         cpu.register_x = 0x01;
         cpu.mem_write_u16(0x12, 0x3344);
         cpu.mem_write(0x3344, 0x60);
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_a, 0x60);
     }
@@ -442,12 +875,12 @@ mod tests {
     #[test]
     fn test_lda_from_memory_indirect_y() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0xb1, 0x12, 0x00]);
+        cpu.load(assemble!("LDA ($12),Y", "BRK"));
         cpu.reset();
         cpu.mem_write_u16(0x12, 0x3345);
         cpu.register_y = 0x02;
         cpu.mem_write(0x3347, 0x61);
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.register_a, 0x61);
     }
@@ -455,10 +888,10 @@ mod tests {
     #[test]
     fn test_sta_from_memory_zero_page() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x85, 0x10, 0x00]);
+        cpu.load(assemble!("STA $10", "BRK"));
         cpu.reset();
         cpu.register_a = 0x50;
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.mem_read(0x10), 0x50);
     }
@@ -466,11 +899,11 @@ mod tests {
     #[test]
     fn test_sta_from_memory_zero_page_x() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x95, 0x10, 0x00]);
+        cpu.load(assemble!("STA $10,X", "BRK"));
         cpu.reset();
         cpu.register_x = 0x01;
         cpu.register_a = 0x51;
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.mem_read(0x11), 0x51);
     }
@@ -478,10 +911,10 @@ mod tests {
     #[test]
     fn test_sta_from_memory_absolute() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x8d, 0x20, 0x30, 0x00]);
+        cpu.load(assemble!("STA $3020", "BRK"));
         cpu.reset();
         cpu.register_a = 0x52;
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.mem_read(0x3020), 0x52);
     }
@@ -489,11 +922,11 @@ mod tests {
     #[test]
     fn test_sta_from_memory_absolute_x() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x9d, 0x21, 0x31, 0x00]);
+        cpu.load(assemble!("STA $3121,X", "BRK"));
         cpu.reset();
         cpu.register_a = 0x53;
         cpu.register_x = 0x01;
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.mem_read(0x3122), 0x53);
     }
@@ -501,11 +934,11 @@ mod tests {
     #[test]
     fn test_sta_from_memory_absolute_y() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x99, 0x22, 0x32, 0x00]);
+        cpu.load(assemble!("STA $3222,Y", "BRK"));
         cpu.reset();
         cpu.register_a = 0x54;
         cpu.register_y = 0x02;
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.mem_read(0x3224), 0x54);
     }
@@ -513,12 +946,12 @@ mod tests {
     #[test]
     fn test_sta_from_memory_indirect_x() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x81, 0x23, 0x00]);
+        cpu.load(assemble!("STA ($23,X)", "BRK"));
         cpu.reset();
         cpu.register_x = 0x03;
         cpu.register_a = 0x55;
         cpu.mem_write_u16(0x26, 0x4455);
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.mem_read(0x4455), 0x55);
     }
@@ -526,12 +959,12 @@ mod tests {
     #[test]
     fn test_sta_from_memory_indirect_y() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x91, 0x24, 0x00]);
+        cpu.load(assemble!("STA ($24),Y", "BRK"));
         cpu.reset();
         cpu.mem_write_u16(0x24, 0x5566);
         cpu.register_y = 0x04;
         cpu.register_a = 0x56;
-        cpu.run();
+        cpu.run_until(CPU::at_brk).unwrap();
 
         assert_eq!(cpu.mem_read(0x556a), 0x56);
     }
@@ -540,10 +973,10 @@ mod tests {
     #[test]
     fn test_adc_no_carry() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x69, 0x10, 0x00]);
+        cpu.load(assemble!("ADC #$10", "BRK"));
         cpu.reset();
         cpu.register_a = 0x20;
-        cpu.run();
+        cpu.run_for_instructions(1).unwrap();
         assert_eq!(cpu.register_a, 0x30);
         assert_eq!(cpu.status, 0)
     }
@@ -551,11 +984,11 @@ mod tests {
     #[test]
     fn test_adc_has_carry() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x69, 0x10, 0x00]);
+        cpu.load(assemble!("ADC #$10", "BRK"));
         cpu.reset();
         cpu.register_a = 0x20;
         cpu.status = STATUS_CARRY;
-        cpu.run();
+        cpu.run_for_instructions(1).unwrap();
         assert_eq!(cpu.register_a, 0x31);
         assert_eq!(cpu.status, 0);
     }
@@ -563,10 +996,10 @@ mod tests {
     #[test]
     fn test_adc_occur_carry() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x69, 0x01, 0x00]);
+        cpu.load(assemble!("ADC #$01", "BRK"));
         cpu.reset();
         cpu.register_a = 0xFF;
-        cpu.run();
+        cpu.run_for_instructions(1).unwrap();
         assert_eq!(cpu.register_a, 0x00);
         assert_eq!(cpu.status, STATUS_CARRY | STATUS_ZERO);
     }
@@ -574,10 +1007,10 @@ mod tests {
     #[test]
     fn test_adc_occur_overflow_plus() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x69, 0x10, 0x00]);
+        cpu.load(assemble!("ADC #$10", "BRK"));
         cpu.reset();
         cpu.register_a = 0x7F;
-        cpu.run();
+        cpu.run_for_instructions(1).unwrap();
         assert_eq!(cpu.register_a, 0x8F);
         assert_eq!(cpu.status, STATUS_NEGATIVE | STATUS_OVERFLOW);
     }
@@ -585,11 +1018,11 @@ mod tests {
     #[test]
     fn test_adc_occur_overflow_plus_with_carry() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x69, 0x6F, 0x00]);
+        cpu.load(assemble!("ADC #$6f", "BRK"));
         cpu.reset();
         cpu.register_a = 0x10;
         cpu.status = STATUS_CARRY;
-        cpu.run();
+        cpu.run_for_instructions(1).unwrap();
         assert_eq!(cpu.register_a, 0x80);
         assert_eq!(cpu.status, STATUS_NEGATIVE | STATUS_OVERFLOW);
     }
@@ -597,10 +1030,10 @@ mod tests {
     #[test]
     fn test_adc_occur_overflow_minus() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x69, 0x81, 0x00]);
+        cpu.load(assemble!("ADC #$81", "BRK"));
         cpu.reset();
         cpu.register_a = 0x81;
-        cpu.run();
+        cpu.run_for_instructions(1).unwrap();
         assert_eq!(cpu.register_a, 0x02);
         assert_eq!(cpu.status, STATUS_OVERFLOW | STATUS_CARRY);
     }
@@ -608,11 +1041,11 @@ mod tests {
     #[test]
     fn test_adc_occur_overflow_minus_with_carry() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x69, 0x80, 0x00]);
+        cpu.load(assemble!("ADC #$80", "BRK"));
         cpu.reset();
         cpu.register_a = 0x80;
         cpu.status = STATUS_CARRY;
-        cpu.run();
+        cpu.run_for_instructions(1).unwrap();
         assert_eq!(cpu.register_a, 0x01);
         assert_eq!(cpu.status, STATUS_OVERFLOW | STATUS_CARRY);
     }
@@ -620,11 +1053,303 @@ mod tests {
     #[test]
     fn test_adc_no_overflow() {
         let mut cpu = CPU::default();
-        cpu.load(vec![0x69, 0x7F, 0x00]);
+        cpu.load(assemble!("ADC #$7f", "BRK"));
         cpu.reset();
         cpu.register_a = 0x82;
-        cpu.run();
+        cpu.run_for_instructions(1).unwrap();
         assert_eq!(cpu.register_a, 0x01);
         assert_eq!(cpu.status, STATUS_CARRY);
     }
+
+    #[test]
+    fn test_jsr_rts() {
+        let mut cpu = CPU::default();
+        cpu.load(assemble!(
+            "  JSR sub",
+            "  INX",
+            "  BRK",
+            "sub:",
+            "  LDA #$05",
+            "  TAX",
+            "  RTS",
+        ));
+        cpu.reset();
+        let stopped = cpu.run_until(|cpu| cpu.program_counter == 0x8004);
+        assert_eq!(stopped, Ok(StopReason::Condition));
+        assert_eq!(cpu.register_x, 0x06);
+        assert_eq!(cpu.stack_pointer, STACK_RESET);
+        assert_eq!(cpu.mem_read_u16(0x01fc), 0x8002);
+    }
+
+    #[test]
+    fn test_run_with_hook_stops_before_instruction() {
+        let mut cpu = CPU::default();
+        cpu.load(assemble!("INX", "INX", "INX", "BRK"));
+        cpu.reset();
+        let stopped = cpu.run_with_hook(|cpu| {
+            if cpu.program_counter == 0x8002 {
+                Hook::Break
+            } else {
+                Hook::Continue
+            }
+        });
+        assert_eq!(stopped, Ok(StopReason::Hook));
+        assert_eq!(cpu.register_x, 2);
+        assert_eq!(cpu.program_counter, 0x8002);
+    }
+
+    #[test]
+    fn test_load_rom_mirrors_16k_bank() {
+        let mut raw = crate::cartridge::test::test_rom(&[0xe8]);
+        // Shrink to a single 16 KiB PRG bank whose reset vector points at $C000.
+        raw[4] = 1;
+        raw.drain(16 + 0x4000..16 + 0x8000);
+        raw[16 + 0x3ffc] = 0x00;
+        raw[16 + 0x3ffd] = 0xc0;
+        let rom = Rom::new(&raw).unwrap();
+
+        let mut cpu = CPU::default();
+        cpu.load_rom(&rom);
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0xc000);
+        assert_eq!(cpu.mem_read(0x8000), 0xe8);
+        assert_eq!(cpu.mem_read(0xc000), 0xe8);
+    }
+
+    #[test]
+    fn test_read_patches() {
+        let mut cpu = CPU::default();
+        cpu.load(assemble!("LDA $9000", "BRK"));
+        cpu.mem_write(0x9000, 0x03);
+        cpu.mem_write(0x9001, 0x04);
+        cpu.set_read_patches(vec![
+            ReadPatch {
+                address: 0x9000,
+                value: 0x09,
+                compare: None,
+            },
+            ReadPatch {
+                address: 0x9001,
+                value: 0x09,
+                compare: Some(0x05),
+            },
+        ]);
+        cpu.reset();
+        cpu.run_until(CPU::at_brk).unwrap();
+        assert_eq!(cpu.register_a, 0x09);
+        // The compare byte does not match, so the ROM shows through.
+        assert_eq!(cpu.mem_read(0x9001), 0x04);
+    }
+
+    #[test]
+    fn test_bus_cycles_of_indexed_modes() {
+        use BusAccess::{Read, Write};
+        let cycle = |address, value, access| BusCycle {
+            address,
+            value,
+            access,
+        };
+        let mut cpu = CPU::default();
+        cpu.load(assemble!(
+            "LDA $12F0,X",
+            "STA $12F0,X",
+            "LDA $1200,X",
+            "BRK"
+        ));
+        cpu.reset();
+        cpu.register_x = 0x20;
+        cpu.mem_write(0x1310, 0x55);
+        cpu.set_bus_logging(true);
+        cpu.run_for_instructions(4).unwrap();
+        assert_eq!(
+            cpu.take_bus_log(),
+            vec![
+                cycle(0x8000, 0xbd, Read),
+                cycle(0x8001, 0xf0, Read),
+                cycle(0x8002, 0x12, Read),
+                // The page crossing costs a read before the high byte is
+                // fixed.
+                cycle(0x1210, 0x00, Read),
+                cycle(0x1310, 0x55, Read),
+                cycle(0x8003, 0x9d, Read),
+                cycle(0x8004, 0xf0, Read),
+                cycle(0x8005, 0x12, Read),
+                cycle(0x1210, 0x00, Read),
+                cycle(0x1310, 0x55, Write),
+                cycle(0x8006, 0xbd, Read),
+                cycle(0x8007, 0x00, Read),
+                cycle(0x8008, 0x12, Read),
+                // Loads within the page go straight to the address.
+                cycle(0x1220, 0x00, Read),
+                // BRK skips a byte, pushes the return address and status,
+                // and jumps through the IRQ vector.
+                cycle(0x8009, 0x00, Read),
+                cycle(0x800a, 0x00, Read),
+                cycle(0x01fd, 0x80, Write),
+                cycle(0x01fc, 0x0b, Write),
+                cycle(0x01fb, 0x32, Write),
+                cycle(0xfffe, 0x00, Read),
+                cycle(0xffff, 0x00, Read),
+            ]
+        );
+        assert!(cpu.bus_log().is_empty());
+        assert_eq!(cpu.cycles, RESET_CYCLES + 5 + 5 + 4 + 7);
+    }
+
+    #[test]
+    fn test_bus_cycles_of_jsr_rts() {
+        let mut cpu = CPU::default();
+        cpu.load(assemble!("JSR sub", "BRK", "sub: RTS"));
+        cpu.reset();
+        cpu.set_bus_logging(true);
+        cpu.run_for_instructions(3).unwrap();
+        let log: Vec<(u16, u8, bool)> = cpu
+            .bus_log()
+            .iter()
+            .map(|cycle| (cycle.address, cycle.value, cycle.access == BusAccess::Write))
+            .collect();
+        assert_eq!(
+            log,
+            [
+                (0x8000, 0x20, false),
+                (0x8001, 0x04, false),
+                (0x01fd, 0x00, false),
+                (0x01fd, 0x80, true),
+                (0x01fc, 0x02, true),
+                (0x8002, 0x80, false),
+                (0x8004, 0x60, false),
+                (0x8005, 0x00, false),
+                (0x01fb, 0x00, false),
+                (0x01fc, 0x02, false),
+                (0x01fd, 0x80, false),
+                (0x8002, 0x80, false),
+                (0x8003, 0x00, false),
+                (0x8004, 0x60, false),
+                (0x01fd, 0x80, true),
+                (0x01fc, 0x05, true),
+                (0x01fb, 0x30, true),
+                (0xfffe, 0x00, false),
+                (0xffff, 0x00, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_brk_interrupts() {
+        let mut cpu = CPU::default();
+        cpu.load(assemble!("LDA #$80", "BRK", "NOP", "handler: INX", "BRK"));
+        cpu.mem_write_u16(IRQ_VECTOR, 0x8004);
+        cpu.reset();
+        assert_eq!(cpu.run_for_instructions(2), Ok(StopReason::Instructions));
+        assert_eq!(cpu.program_counter, 0x8004);
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 3);
+        // The return address skips the byte after BRK, and the pushed
+        // status has the B flag set.
+        assert_eq!(cpu.mem_read_u16(0x01fc), 0x8004);
+        assert_eq!(
+            cpu.mem_read(0x01fb),
+            STATUS_NEGATIVE | STATUS_BREAK | STATUS_BREAK2
+        );
+        assert_eq!(cpu.status, STATUS_NEGATIVE | STATUS_INTERRUPT_DISABLE);
+        assert_eq!(cpu.cycles, RESET_CYCLES + 2 + 7);
+
+        // Running on executes the handler.
+        assert_eq!(cpu.run_until(CPU::at_brk), Ok(StopReason::Condition));
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.program_counter, 0x8005);
+    }
+
+    #[test]
+    fn test_bounded_runs() {
+        // An endless loop without BRK.
+        let mut cpu = CPU::default();
+        cpu.load(assemble!("loop: INX", "JSR loop"));
+        cpu.reset();
+        assert_eq!(cpu.run_for_instructions(3), Ok(StopReason::Instructions));
+        assert_eq!(cpu.register_x, 2);
+        assert_eq!(cpu.cycles, RESET_CYCLES + 2 + 6 + 2);
+        assert_eq!(cpu.run_for_instructions(0), Ok(StopReason::Instructions));
+        assert_eq!(cpu.register_x, 2);
+
+        // INX and JSR take 8 cycles between them; the budget is met at the
+        // end of an instruction, not in the middle of one.
+        assert_eq!(cpu.run_for_cycles(7), Ok(StopReason::Cycles));
+        assert_eq!(cpu.cycles, RESET_CYCLES + 10 + 8);
+
+        assert_eq!(
+            cpu.run_until(|cpu| cpu.register_x == 0x10),
+            Ok(StopReason::Condition)
+        );
+        assert_eq!(cpu.program_counter, 0x8001);
+
+        // BRK does not end a run; the handler it jumps to executes.
+        let mut cpu = CPU::default();
+        cpu.load(assemble!("INX", "BRK", "NOP", "handler: INX", "INX"));
+        cpu.mem_write_u16(IRQ_VECTOR, 0x8003);
+        cpu.reset();
+        assert_eq!(cpu.run_for_instructions(4), Ok(StopReason::Instructions));
+        assert_eq!(cpu.register_x, 3);
+        assert_eq!(cpu.program_counter, 0x8005);
+    }
+
+    #[test]
+    fn test_dispatch_table_follows_opcode_table() {
+        for opcode in CPU_OPS_CODES {
+            let entry = &DISPATCH[opcode.code as usize];
+            assert_eq!(entry.mode, opcode.mode);
+            assert_eq!(entry.len, opcode.len);
+            assert_eq!(entry.cycles, opcode.cycles);
+        }
+        assert_eq!(DISPATCH[0x03].len, 1);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut cpu = CPU::default();
+        let fault = |pc, opcode, kind| Err(CpuError { pc, opcode, kind });
+        // LDX is not implemented.
+        cpu.load(assemble!("LDA #1", "LDX #2"));
+        cpu.reset();
+        assert_eq!(cpu.step(), Ok(StopReason::Step));
+        let err = cpu.run().unwrap_err();
+        assert_eq!(Err(err), fault(0x8002, 0xa2, FaultKind::Unimplemented));
+        assert_eq!(
+            err.to_string(),
+            "LDX (opcode $A2) is not implemented at $8002"
+        );
+        // The program counter stays on the instruction, so it can be
+        // skipped.
+        assert_eq!(cpu.program_counter, 0x8002);
+        assert_eq!(cpu.register_a, 1);
+        cpu.skip_instruction();
+        assert_eq!(cpu.program_counter, 0x8004);
+
+        cpu.load(assemble!(".byte $02"));
+        cpu.reset();
+        assert_eq!(cpu.step(), fault(0x8000, 0x02, FaultKind::Jam));
+        cpu.load(assemble!(".byte $03"));
+        cpu.reset();
+        let err = cpu.run().unwrap_err();
+        assert_eq!(err.kind, FaultKind::IllegalOpcode);
+        assert_eq!(err.to_string(), "illegal opcode $03 at $8000");
+    }
+
+    #[test]
+    fn test_soft_reset_keeps_state() {
+        let mut cpu = CPU::default();
+        cpu.load_and_run(assemble!("LDA #$05", "STA $10", "BRK"))
+            .unwrap();
+        let cycles = cpu.cycles;
+        let stack_pointer = cpu.stack_pointer;
+        cpu.soft_reset();
+        assert_eq!(cpu.program_counter, 0x8000);
+        assert_eq!(cpu.register_a, 0x05);
+        assert_eq!(cpu.mem_read(0x10), 0x05);
+        assert_eq!(cpu.stack_pointer, stack_pointer - 3);
+        assert_eq!(
+            cpu.status & STATUS_INTERRUPT_DISABLE,
+            STATUS_INTERRUPT_DISABLE
+        );
+        assert_eq!(cpu.cycles, cycles + RESET_CYCLES);
+    }
 }
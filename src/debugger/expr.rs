@@ -0,0 +1,363 @@
+use crate::cpu::{Mem, CPU};
+use crate::symbols::SymbolTable;
+
+/// Expression over CPU state, used for addresses, values and breakpoint
+/// conditions such as `A == $40 && X > 3`.
+///
+/// Operands are numbers (`$40`, `%0100_0000`, `64`), registers (`A`, `X`,
+/// `Y`, `P`, `SP`, `PC`), labels and memory reads `[addr]`. Comparisons and
+/// `!` give 1 or 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    Label(String),
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    P,
+    SP,
+    PC,
+}
+
+impl Register {
+    pub fn from_name(name: &str) -> Option<Register> {
+        match name.to_ascii_uppercase().as_str() {
+            "A" => Some(Register::A),
+            "X" => Some(Register::X),
+            "Y" => Some(Register::Y),
+            "P" => Some(Register::P),
+            "SP" | "S" => Some(Register::SP),
+            "PC" => Some(Register::PC),
+            _ => None,
+        }
+    }
+
+    pub fn get(self, cpu: &CPU) -> u16 {
+        match self {
+            Register::A => cpu.register_a as u16,
+            Register::X => cpu.register_x as u16,
+            Register::Y => cpu.register_y as u16,
+            Register::P => cpu.status as u16,
+            Register::SP => cpu.stack_pointer as u16,
+            Register::PC => cpu.program_counter,
+        }
+    }
+
+    /// Stores `value`, truncated to the width of the register.
+    pub fn set(self, cpu: &mut CPU, value: u16) {
+        match self {
+            Register::A => cpu.register_a = value as u8,
+            Register::X => cpu.register_x = value as u8,
+            Register::Y => cpu.register_y = value as u8,
+            Register::P => cpu.status = value as u8,
+            Register::SP => cpu.stack_pointer = value as u8,
+            Register::PC => cpu.program_counter = value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {}", token)),
+        }
+    }
+
+    pub fn eval(&self, cpu: &CPU, symbols: &SymbolTable) -> Result<i64, String> {
+        Ok(match self {
+            Expr::Number(value) => *value,
+            Expr::Register(register) => register.get(cpu) as i64,
+            Expr::Label(name) => match symbols.lookup(name) {
+                Some(addr) => addr as i64,
+                None => return Err(format!("unknown symbol {}", name)),
+            },
+            Expr::Memory(addr) => cpu.mem_read(addr.eval(cpu, symbols)? as u16) as i64,
+            Expr::Not(inner) => (inner.eval(cpu, symbols)? == 0) as i64,
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(cpu, symbols)?;
+                // Short-circuit so `X < 4 && [$200+X] == 0` style guards work.
+                match op {
+                    Op::And if lhs == 0 => return Ok(0),
+                    Op::Or if lhs != 0 => return Ok(1),
+                    _ => {}
+                }
+                let rhs = rhs.eval(cpu, symbols)?;
+                match op {
+                    Op::Or | Op::And => (rhs != 0) as i64,
+                    Op::Eq => (lhs == rhs) as i64,
+                    Op::Ne => (lhs != rhs) as i64,
+                    Op::Lt => (lhs < rhs) as i64,
+                    Op::Le => (lhs <= rhs) as i64,
+                    Op::Gt => (lhs > rhs) as i64,
+                    Op::Ge => (lhs >= rhs) as i64,
+                    Op::Add => lhs
+                        .checked_add(rhs)
+                        .ok_or_else(|| format!("{} + {} overflows", lhs, rhs))?,
+                    Op::Sub => lhs
+                        .checked_sub(rhs)
+                        .ok_or_else(|| format!("{} - {} overflows", lhs, rhs))?,
+                }
+            }
+        })
+    }
+
+    /// Evaluates to a CPU address.
+    pub fn address(&self, cpu: &CPU, symbols: &SymbolTable) -> Result<u16, String> {
+        let value = self.eval(cpu, symbols)?;
+        u16::try_from(value).map_err(|_| format!("${:X} is not an address", value))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 15] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "!", "+", "-", "(", ")", "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || "$%_@".contains(c)))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                let c = rest.chars().next().unwrap();
+                return Err(format!("unexpected character {}", c));
+            }
+            tokens.push(word_token(&rest[..end])?);
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn word_token(word: &str) -> Result<Token, String> {
+    let number = |digits: &str, radix: u32| {
+        i64::from_str_radix(&digits.replace('_', ""), radix)
+            .map(Token::Number)
+            .map_err(|_| format!("invalid number {}", word))
+    };
+    if let Some(digits) = word.strip_prefix('$') {
+        number(digits, 16)
+    } else if let Some(digits) = word.strip_prefix('%') {
+        number(digits, 2)
+    } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+        number(word, 10)
+    } else {
+        Ok(Token::Ident(word.to_string()))
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.tokens.get(self.pos), Some(Token::Symbol(s)) if *s == symbol) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.and()?;
+        while self.eat("||") {
+            lhs = Expr::Binary(Op::Or, Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.comparison()?;
+        while self.eat("&&") {
+            lhs = Expr::Binary(Op::And, Box::new(lhs), Box::new(self.comparison()?));
+        }
+        Ok(lhs)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let lhs = self.sum()?;
+        let ops = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+        for (symbol, op) in ops {
+            if self.eat(symbol) {
+                return Ok(Expr::Binary(op, Box::new(lhs), Box::new(self.sum()?)));
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat("+") {
+                Op::Add
+            } else if self.eat("-") {
+                Op::Sub
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            let inner = self.unary()?;
+            return Ok(Expr::Binary(
+                Op::Sub,
+                Box::new(Expr::Number(0)),
+                Box::new(inner),
+            ));
+        }
+        if self.eat("(") {
+            let inner = self.or()?;
+            return self.close(")", inner);
+        }
+        if self.eat("[") {
+            let inner = self.or()?;
+            return self.close("]", Expr::Memory(Box::new(inner)));
+        }
+        match self.tokens.get(self.pos).cloned() {
+            Some(Token::Number(value)) => {
+                self.pos += 1;
+                Ok(Expr::Number(value))
+            }
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                Ok(match Register::from_name(&name) {
+                    Some(register) => Expr::Register(register),
+                    None => Expr::Label(name),
+                })
+            }
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn close(&mut self, symbol: &str, expr: Expr) -> Result<Expr, String> {
+        if self.eat(symbol) {
+            Ok(expr)
+        } else {
+            Err(format!("expected {}", symbol))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str, cpu: &CPU) -> i64 {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x0300, "buffer");
+        Expr::parse(text).unwrap().eval(cpu, &symbols).unwrap()
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut cpu = CPU {
+            register_a: 0x40,
+            register_x: 4,
+            ..Default::default()
+        };
+        cpu.mem_write(0x0302, 0x99);
+
+        assert_eq!(eval("A == $40 && X > 3", &cpu), 1);
+        assert_eq!(eval("A == $40 && X > 4", &cpu), 0);
+        assert_eq!(eval("a != 64 || !(x <= 3)", &cpu), 1);
+        assert_eq!(eval("[buffer + 2] == $99", &cpu), 1);
+        assert_eq!(eval("pc - 1", &cpu), -1);
+        assert_eq!(eval("%0100_0000", &cpu), 64);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Expr::parse("A ==").is_err());
+        assert!(Expr::parse("[A").is_err());
+        assert!(Expr::parse("A # 1").is_err());
+        assert!(Expr::parse("1 2").is_err());
+        assert_eq!(
+            Expr::parse("A == é"),
+            Err("unexpected character é".to_string())
+        );
+        let cpu = CPU::default();
+        assert!(Expr::parse("missing")
+            .unwrap()
+            .eval(&cpu, &SymbolTable::new())
+            .is_err());
+    }
+
+    #[test]
+    fn test_overflow_is_an_error() {
+        let cpu = CPU::default();
+        let symbols = SymbolTable::new();
+        for text in [
+            "$7FFFFFFFFFFFFFFF + 1",
+            "0 - $7FFFFFFFFFFFFFFF - 2",
+            "-(-$7FFFFFFFFFFFFFFF - 1)",
+        ] {
+            assert!(Expr::parse(text).unwrap().eval(&cpu, &symbols).is_err());
+        }
+        assert_eq!(eval("-$7FFFFFFFFFFFFFFF - 1", &cpu), i64::MIN);
+    }
+}
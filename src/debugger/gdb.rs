@@ -0,0 +1,538 @@
+//! GDB remote serial protocol stub.
+//!
+//! Serves a single client over any byte stream, normally a TCP connection
+//! on localhost:
+//!
+//! ```text
+//! (gdb) target remote localhost:2345
+//! ```
+//!
+//! GDB has no 6502 target, so the register layout is described to it with
+//! a `target.xml`: A, X, Y, P and SP as bytes, then PC as a little-endian
+//! word. Continuing blocks until the program stops; Ctrl-C is not
+//! delivered while it runs.
+
+use super::{Access, Debugger, Event, Register};
+use crate::cpu::{Mem, CPU};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+
+const REGISTERS: [Register; 6] = [
+    Register::A,
+    Register::X,
+    Register::Y,
+    Register::P,
+    Register::SP,
+    Register::PC,
+];
+
+/// Largest packet accepted or sent, as advertised in `qSupported`.
+const PACKET_SIZE: usize = 0x4000;
+
+/// Most bytes a memory read or write may cover: each takes two hex digits.
+const MAX_MEMORY_LENGTH: u32 = (PACKET_SIZE / 2) as u32;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nes_emulator.mos6502">
+    <reg name="a" bitsize="8" type="uint8"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Stop reply for SIGTRAP.
+const SIGTRAP: &str = "S05";
+/// Stop reply for SIGILL, when the CPU cannot execute an instruction.
+const SIGILL: &str = "S04";
+
+pub struct GdbStub<'a> {
+    cpu: &'a mut CPU,
+    debugger: Debugger,
+    detached: bool,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(cpu: &'a mut CPU, debugger: Debugger) -> Self {
+        GdbStub {
+            cpu,
+            debugger,
+            detached: false,
+        }
+    }
+
+    /// Waits for one client on `addr` and serves it until it detaches.
+    pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve(stream)
+    }
+
+    /// Serves packets from `stream` until the client detaches, kills the
+    /// session or disconnects.
+    pub fn serve<S: Read + Write>(&mut self, mut stream: S) -> io::Result<()> {
+        self.detached = false;
+        while !self.detached {
+            let packet = match read_packet(&mut stream)? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+            if let Some(reply) = self.handle(&packet) {
+                write_packet(&mut stream, &reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers one packet, without the framing. `None` means no reply is
+    /// sent; unsupported packets get the empty reply.
+    pub fn handle(&mut self, packet: &str) -> Option<String> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => SIGTRAP.to_string(),
+            Some(b'g') => REGISTERS
+                .iter()
+                .map(|register| register_hex(self.cpu, *register))
+                .collect(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => match usize::from_str_radix(&packet[1..], 16) {
+                Ok(index) if index < REGISTERS.len() => register_hex(self.cpu, REGISTERS[index]),
+                _ => "E01".to_string(),
+            },
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b's') => self.resume_at(&packet[1..], |debugger, cpu| debugger.step(cpu, 1)),
+            Some(b'c') => self.resume_at(&packet[1..], |debugger, cpu| debugger.cont(cpu)),
+            Some(b'Z') => self.breakpoint(&packet[1..], true),
+            Some(b'z') => self.breakpoint(&packet[1..], false),
+            Some(b'H') => "OK".to_string(),
+            Some(b'D') => {
+                self.detached = true;
+                "OK".to_string()
+            }
+            Some(b'k') => {
+                self.detached = true;
+                return None;
+            }
+            _ => self.query(packet),
+        };
+        Some(reply)
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;swbreak+", PACKET_SIZE);
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_pair(range, ',') {
+                Some((offset, length)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + length as usize).min(TARGET_XML.len());
+                    let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                    format!("{}{}", marker, &TARGET_XML[offset..end])
+                }
+                None => "E01".to_string(),
+            };
+        }
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        let bytes = match decode_hex(hex) {
+            Some(bytes) if bytes.len() == 7 => bytes,
+            _ => return "E01".to_string(),
+        };
+        for (register, value) in REGISTERS.iter().zip(&bytes[..5]) {
+            register.set(self.cpu, *value as u16);
+        }
+        Register::PC.set(self.cpu, u16::from_le_bytes([bytes[5], bytes[6]]));
+        "OK".to_string()
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let (index, value) = match args.split_once('=') {
+            Some((index, value)) => (usize::from_str_radix(index, 16), decode_hex(value)),
+            None => return "E01".to_string(),
+        };
+        match (index, value) {
+            (Ok(index), Some(bytes)) if index < REGISTERS.len() && !bytes.is_empty() => {
+                let value = bytes
+                    .iter()
+                    .rev()
+                    .fold(0u16, |value, byte| value << 8 | *byte as u16);
+                REGISTERS[index].set(self.cpu, value);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Addresses past $FFFF wrap around, as they do on the 6502.
+    fn read_memory(&self, args: &str) -> String {
+        match parse_pair(args, ',') {
+            Some((addr, length)) if length <= MAX_MEMORY_LENGTH => (0..length)
+                .map(|offset| {
+                    let addr = (addr as u16).wrapping_add(offset as u16);
+                    format!("{:02x}", self.cpu.mem_read(addr))
+                })
+                .collect(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let (range, data) = match args.split_once(':') {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        match (parse_pair(range, ','), decode_hex(data)) {
+            (Some((addr, length)), Some(bytes))
+                if length <= MAX_MEMORY_LENGTH && bytes.len() == length as usize =>
+            {
+                for (offset, byte) in bytes.iter().enumerate() {
+                    self.cpu
+                        .mem_write((addr as u16).wrapping_add(offset as u16), *byte);
+                }
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Handles `s [addr]` and `c [addr]`.
+    fn resume_at<F>(&mut self, addr: &str, run: F) -> String
+    where
+        F: FnOnce(&Debugger, &mut CPU) -> Event,
+    {
+        if !addr.is_empty() {
+            match u16::from_str_radix(addr, 16) {
+                Ok(addr) => self.cpu.program_counter = addr,
+                Err(_) => return "E01".to_string(),
+            }
+        }
+        match run(&self.debugger, self.cpu) {
+            Event::Stepped | Event::Brk(_) => SIGTRAP.to_string(),
+            Event::Fault(_) => SIGILL.to_string(),
+            Event::Breakpoint(_) => "T05swbreak:;".to_string(),
+            Event::Watchpoint { id, address, .. } => {
+                let kind = self
+                    .debugger
+                    .watchpoints()
+                    .iter()
+                    .find(|watchpoint| watchpoint.id == id)
+                    .map_or("awatch", |watchpoint| match watchpoint.access {
+                        Access::Write => "watch",
+                        Access::Read => "rwatch",
+                        Access::ReadWrite => "awatch",
+                    });
+                format!("T05{}:{:04x};", kind, address)
+            }
+        }
+    }
+
+    /// Handles `Z`/`z` packets: `type,addr,kind`, where kind is the
+    /// breakpoint size or the watched length.
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        let (kind, addr, length) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(kind), Some(addr), Some(length)) => (
+                kind,
+                u16::from_str_radix(addr, 16),
+                u16::from_str_radix(length, 16),
+            ),
+            _ => return "E01".to_string(),
+        };
+        let (addr, length) = match (addr, length) {
+            (Ok(addr), Ok(length)) => (addr, length.max(1)),
+            _ => return "E01".to_string(),
+        };
+        let access = match kind {
+            "0" | "1" => None,
+            "2" => Some(Access::Write),
+            "3" => Some(Access::Read),
+            "4" => Some(Access::ReadWrite),
+            _ => return String::new(),
+        };
+        let end = addr.wrapping_add(length - 1);
+
+        match (insert, access) {
+            (true, None) => {
+                self.debugger.add_breakpoint(addr, None);
+            }
+            (true, Some(access)) => {
+                self.debugger.add_watchpoint(addr, end, access);
+            }
+            (false, None) => {
+                let found = self
+                    .debugger
+                    .breakpoints()
+                    .iter()
+                    .find(|breakpoint| breakpoint.address == addr && breakpoint.condition.is_none())
+                    .map(|breakpoint| breakpoint.id);
+                if let Some(id) = found {
+                    self.debugger.remove(id);
+                }
+            }
+            (false, Some(access)) => {
+                let found = self
+                    .debugger
+                    .watchpoints()
+                    .iter()
+                    .find(|watchpoint| {
+                        watchpoint.start == addr
+                            && watchpoint.end == end
+                            && watchpoint.access == access
+                    })
+                    .map(|watchpoint| watchpoint.id);
+                if let Some(id) = found {
+                    self.debugger.remove(id);
+                }
+            }
+        }
+        "OK".to_string()
+    }
+}
+
+fn register_hex(cpu: &CPU, register: Register) -> String {
+    match register {
+        Register::PC => {
+            let [lo, hi] = cpu.program_counter.to_le_bytes();
+            format!("{:02x}{:02x}", lo, hi)
+        }
+        _ => format!("{:02x}", register.get(cpu)),
+    }
+}
+
+fn parse_pair(text: &str, separator: char) -> Option<(u32, u32)> {
+    let (first, second) = text.split_once(separator)?;
+    Some((
+        u32::from_str_radix(first, 16).ok()?,
+        u32::from_str_radix(second, 16).ok()?,
+    ))
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn read_byte<S: Read>(stream: &mut S) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+/// Reads the next `$data#cs` packet, acknowledging it. Packets with a bad
+/// checksum are NAKed and skipped. Returns `None` when the stream closes.
+fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Option<String>> {
+    loop {
+        // Skip acks and anything else between packets.
+        loop {
+            match read_byte(stream)? {
+                Some(b'$') => break,
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                Some(b'#') => break,
+                Some(byte) => data.push(byte),
+                None => return Ok(None),
+            }
+        }
+        let mut sum = [0u8; 2];
+        for digit in sum.iter_mut() {
+            match read_byte(stream)? {
+                Some(byte) => *digit = byte,
+                None => return Ok(None),
+            }
+        }
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+        if expected == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+fn write_packet<S: Write>(stream: &mut S, data: &str) -> io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum(data))?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+
+    const PROGRAM: &str = "
+        main:   LDA #$42
+                STA $0200
+                JSR sub
+                BRK
+        sub:    INX
+                RTS
+    ";
+
+    fn setup() -> CPU {
+        let mut cpu = CPU::default();
+        cpu.load(asm::assemble(PROGRAM).unwrap());
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut cpu = setup();
+        let mut stub = GdbStub::new(&mut cpu, Debugger::new(Default::default()));
+
+        assert_eq!(stub.handle("g").unwrap(), "00000000fd0080");
+        assert_eq!(stub.handle("P0=7f").unwrap(), "OK");
+        assert_eq!(stub.handle("P5=0280").unwrap(), "OK");
+        assert_eq!(stub.handle("p5").unwrap(), "0280");
+        assert_eq!(stub.handle("G0102030405").unwrap(), "E01");
+        assert_eq!(stub.handle("G01020304050080").unwrap(), "OK");
+        assert_eq!(stub.handle("g").unwrap(), "01020304050080");
+
+        assert_eq!(stub.handle("m8000,2").unwrap(), "a942");
+        assert_eq!(stub.handle("M0010,2:beef").unwrap(), "OK");
+        assert_eq!(stub.handle("m10,3").unwrap(), "beef00");
+        assert_eq!(stub.handle("Mffff,2:1234").unwrap(), "OK");
+        assert_eq!(stub.handle("m0,1").unwrap(), "34");
+        assert_eq!(stub.handle("mffffffff,2").unwrap(), "1234");
+        assert_eq!(stub.handle("m0,ffffffff").unwrap(), "E01");
+        assert_eq!(stub.handle("m0,2001").unwrap(), "E01");
+        assert_eq!(stub.handle("m0,2000").unwrap().len(), PACKET_SIZE);
+        assert_eq!(stub.handle("vMustReplyEmpty").unwrap(), "");
+    }
+
+    #[test]
+    fn test_breakpoints_watchpoints_and_stepping() {
+        let mut cpu = setup();
+        let mut stub = GdbStub::new(&mut cpu, Debugger::new(Default::default()));
+
+        assert_eq!(stub.handle("s").unwrap(), "S05");
+        assert_eq!(stub.handle("p5").unwrap(), "0280");
+
+        assert_eq!(stub.handle("Z2,200,1").unwrap(), "OK");
+        assert_eq!(stub.handle("c").unwrap(), "T05watch:0200;");
+        assert_eq!(stub.handle("z2,200,1").unwrap(), "OK");
+
+        assert_eq!(stub.handle("Z0,8009,1").unwrap(), "OK");
+        assert_eq!(stub.handle("c").unwrap(), "T05swbreak:;");
+        assert_eq!(stub.handle("p5").unwrap(), "0980");
+        assert_eq!(stub.handle("z0,8009,1").unwrap(), "OK");
+
+        assert_eq!(stub.handle("c").unwrap(), "S05");
+        assert_eq!(stub.handle("p1").unwrap(), "01");
+    }
+
+    #[test]
+    fn test_loopback_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut cpu = setup();
+            let (stream, _) = listener.accept().unwrap();
+            GdbStub::new(&mut cpu, Debugger::new(Default::default()))
+                .serve(stream)
+                .unwrap();
+            cpu
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut request = |data: &str| {
+            write!(writer, "${}#{:02x}", data, checksum(data)).unwrap();
+            let mut ack = [0u8];
+            reader.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            let mut reply = Vec::new();
+            reader.read_until(b'#', &mut reply).unwrap();
+            let mut sum = [0u8; 2];
+            reader.read_exact(&mut sum).unwrap();
+            let reply = String::from_utf8(reply[1..reply.len() - 1].to_vec()).unwrap();
+            assert_eq!(
+                u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap(),
+                checksum(&reply)
+            );
+            reply
+        };
+
+        assert!(request("qSupported:multiprocess+").contains("qXfer:features:read+"));
+        assert!(request("qXfer:features:read:target.xml:0,fff").starts_with("l<?xml"));
+        assert_eq!(request("?"), "S05");
+        assert_eq!(request("Z0,8005,1"), "OK");
+        assert_eq!(request("c"), "T05swbreak:;");
+        assert_eq!(request("m0200,1"), "42");
+        assert_eq!(request("D"), "OK");
+
+        let cpu = server.join().unwrap();
+        assert_eq!(cpu.program_counter, 0x8005);
+    }
+
+    #[test]
+    fn test_bad_checksum_is_nacked() {
+        let mut input = io::Cursor::new(b"+$g#00$g#67".to_vec());
+        let mut log = Vec::new();
+        let mut stream = ReadWrite {
+            input: &mut input,
+            output: &mut log,
+        };
+        assert_eq!(read_packet(&mut stream).unwrap().as_deref(), Some("g"));
+        assert_eq!(log, b"-+");
+    }
+
+    struct ReadWrite<'a> {
+        input: &'a mut io::Cursor<Vec<u8>>,
+        output: &'a mut Vec<u8>,
+    }
+
+    impl Read for ReadWrite<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for ReadWrite<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
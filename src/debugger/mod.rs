@@ -0,0 +1,365 @@
+pub mod expr;
+pub mod gdb;
+pub mod repl;
+
+pub use expr::{Expr, Register};
+
+use crate::cheats::CheatList;
+use crate::cpu::{AddressingMode, CpuError, Hook, Mem, CPU};
+use crate::opcodes;
+use crate::ramsearch::RamSearch;
+use crate::symbols::SymbolTable;
+use crate::trace;
+
+const OPCODE_JSR: u8 = 0x20;
+const OPCODE_RTS: u8 = 0x60;
+
+/// Kind of memory access a watchpoint reacts to, or an instruction makes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn reads(self) -> bool {
+        self != Access::Write
+    }
+
+    fn writes(self) -> bool {
+        self != Access::Read
+    }
+
+    fn overlaps(self, other: Access) -> bool {
+        (self.reads() && other.reads()) || (self.writes() && other.writes())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub address: u16,
+    /// Only stop when this evaluates to non-zero.
+    pub condition: Option<Expr>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    pub id: usize,
+    /// First and last watched address, inclusive.
+    pub start: u16,
+    pub end: u16,
+    pub access: Access,
+}
+
+/// Why execution stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The requested step, step-over or step-out finished.
+    Stepped,
+    Breakpoint(usize),
+    /// The instruction that just ran touched a watched address.
+    Watchpoint {
+        id: usize,
+        address: u16,
+        access: Access,
+    },
+    /// Execution reached the BRK at this address, which the debugger
+    /// treats as a breakpoint; it has not run.
+    Brk(u16),
+    /// The CPU could not execute the instruction at the program counter.
+    Fault(CpuError),
+}
+
+/// Breakpoints, watchpoints and stepping, built on [`CPU::run_with_hook`].
+///
+/// Breakpoints stop before the instruction at their address runs, and so
+/// does BRK; watchpoints stop after the instruction that made the access. Cheats are
+/// applied before every instruction.
+#[derive(Debug)]
+pub struct Debugger {
+    pub symbols: SymbolTable,
+    pub cheats: CheatList,
+    /// RAM search in progress, if any.
+    pub search: Option<RamSearch>,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: usize,
+}
+
+impl Debugger {
+    pub fn new(symbols: SymbolTable) -> Self {
+        Debugger {
+            symbols,
+            cheats: CheatList::new(),
+            search: None,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16, condition: Option<Expr>) -> usize {
+        let id = self.allocate_id();
+        self.breakpoints.push(Breakpoint {
+            id,
+            address,
+            condition,
+        });
+        id
+    }
+
+    pub fn add_watchpoint(&mut self, start: u16, end: u16, access: Access) -> usize {
+        let id = self.allocate_id();
+        self.watchpoints.push(Watchpoint {
+            id,
+            start: start.min(end),
+            end: start.max(end),
+            access,
+        });
+        id
+    }
+
+    /// Removes the breakpoint or watchpoint numbered `id`.
+    pub fn remove(&mut self, id: usize) -> bool {
+        let before = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.watchpoints.retain(|watchpoint| watchpoint.id != id);
+        before != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    /// Executes `count` instructions.
+    pub fn step(&self, cpu: &mut CPU, count: usize) -> Event {
+        self.resume(cpu, |_, executed| executed >= count)
+    }
+
+    /// Executes one instruction, running a JSR through to its return.
+    pub fn step_over(&self, cpu: &mut CPU) -> Event {
+        if cpu.mem_read(cpu.program_counter) != OPCODE_JSR {
+            return self.step(cpu, 1);
+        }
+        let return_address = cpu.program_counter.wrapping_add(3);
+        let stack_pointer = cpu.stack_pointer;
+        self.resume(cpu, |cpu, executed| {
+            executed > 0
+                && cpu.program_counter == return_address
+                && cpu.stack_pointer >= stack_pointer
+        })
+    }
+
+    /// Runs until the current subroutine returns to its caller.
+    pub fn step_out(&self, cpu: &mut CPU) -> Event {
+        let stack_pointer = cpu.stack_pointer;
+        let mut returned = false;
+        self.resume(cpu, |cpu, _| {
+            if returned && cpu.stack_pointer > stack_pointer {
+                return true;
+            }
+            returned = cpu.mem_read(cpu.program_counter) == OPCODE_RTS;
+            false
+        })
+    }
+
+    /// Runs until a breakpoint, watchpoint or BRK.
+    pub fn cont(&self, cpu: &mut CPU) -> Event {
+        self.resume(cpu, |_, _| false)
+    }
+
+    /// Runs until `done`, given the number of instructions executed so far,
+    /// returns true before the next instruction. The instruction at the
+    /// program counter always runs, so resuming from a breakpoint does not
+    /// hit it again.
+    fn resume<F>(&self, cpu: &mut CPU, mut done: F) -> Event
+    where
+        F: FnMut(&CPU, usize) -> bool,
+    {
+        let mut executed = 0;
+        let mut pending: Option<(u16, Access)> = None;
+        let mut event = None;
+        let stopped = cpu.run_with_hook(|cpu| {
+            self.cheats.apply(cpu);
+            if let Some(hit) = pending.take().and_then(|access| self.watch_hit(access)) {
+                event = Some(hit);
+                return Hook::Break;
+            }
+            if executed > 0 {
+                if let Some(id) = self.breakpoint_hit(cpu) {
+                    event = Some(Event::Breakpoint(id));
+                    return Hook::Break;
+                }
+            }
+            if done(cpu, executed) {
+                event = Some(Event::Stepped);
+                return Hook::Break;
+            }
+            if executed > 0 && cpu.at_brk() {
+                event = Some(Event::Brk(cpu.program_counter));
+                return Hook::Break;
+            }
+            pending = memory_access(cpu);
+            executed += 1;
+            Hook::Continue
+        });
+
+        match stopped {
+            Ok(_) => event.expect("hook stopped without an event"),
+            Err(err) => Event::Fault(err),
+        }
+    }
+
+    fn breakpoint_hit(&self, cpu: &CPU) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .find(|breakpoint| {
+                breakpoint.address == cpu.program_counter
+                    && breakpoint.condition.as_ref().is_none_or(|condition| {
+                        // A condition that cannot be evaluated stops so the
+                        // user gets to see why.
+                        condition.eval(cpu, &self.symbols) != Ok(0)
+                    })
+            })
+            .map(|breakpoint| breakpoint.id)
+    }
+
+    fn watch_hit(&self, (address, access): (u16, Access)) -> Option<Event> {
+        self.watchpoints
+            .iter()
+            .find(|watchpoint| {
+                (watchpoint.start..=watchpoint.end).contains(&address)
+                    && watchpoint.access.overlaps(access)
+            })
+            .map(|watchpoint| Event::Watchpoint {
+                id: watchpoint.id,
+                address,
+                access,
+            })
+    }
+
+    fn allocate_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+/// Data address the instruction at the program counter is about to access.
+/// Stack pushes and instruction fetches are not included.
+pub fn memory_access(cpu: &CPU) -> Option<(u16, Access)> {
+    let opcode = opcodes::OPCODES_MAP.get(&cpu.mem_read(cpu.program_counter))?;
+    match opcode.mode {
+        AddressingMode::Immediate
+        | AddressingMode::NoneAddressing
+        | AddressingMode::Relative
+        | AddressingMode::Accumulator
+        | AddressingMode::Indirect => return None,
+        _ if opcode.mnemonic == "JMP" || opcode.mnemonic == "JSR" => return None,
+        _ => {}
+    }
+    let (address, _) = trace::effective_address(cpu, &opcode.mode, cpu.program_counter);
+    let access = match opcode.mnemonic {
+        "STA" | "STX" | "STY" => Access::Write,
+        "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC" => Access::ReadWrite,
+        _ => Access::Read,
+    };
+    Some((address, access))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    const PROGRAM: &str = "
+        main:   LDA #$01
+                JSR sub
+                STA $0200
+                LDA $0201
+                BRK
+        sub:    TAX
+                INX
+                RTS
+    ";
+
+    fn setup() -> (Debugger, CPU) {
+        let program = asm::assemble_program(PROGRAM).unwrap();
+        let mut cpu = CPU::default();
+        cpu.load(program.bytes);
+        cpu.reset();
+        (Debugger::new(program.symbols), cpu)
+    }
+
+    #[test]
+    fn test_step_and_step_over() {
+        let (debugger, mut cpu) = setup();
+        assert_eq!(debugger.step(&mut cpu, 2), Event::Stepped);
+        assert_eq!(cpu.program_counter, debugger.symbols.lookup("sub").unwrap());
+
+        let (debugger, mut cpu) = setup();
+        debugger.step(&mut cpu, 1);
+        assert_eq!(debugger.step_over(&mut cpu), Event::Stepped);
+        assert_eq!(cpu.program_counter, 0x8005);
+        assert_eq!(cpu.register_x, 0x02);
+    }
+
+    #[test]
+    fn test_step_out() {
+        let (debugger, mut cpu) = setup();
+        debugger.step(&mut cpu, 3);
+        assert_eq!(debugger.step_out(&mut cpu), Event::Stepped);
+        assert_eq!(cpu.program_counter, 0x8005);
+        assert_eq!(cpu.stack_pointer, 0xfd);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let (mut debugger, mut cpu) = setup();
+        let sub = debugger.symbols.lookup("sub").unwrap();
+        let id = debugger.add_breakpoint(sub, None);
+        assert_eq!(debugger.cont(&mut cpu), Event::Breakpoint(id));
+        assert_eq!(cpu.program_counter, sub);
+        // Resuming from a breakpoint runs past it.
+        assert_eq!(debugger.cont(&mut cpu), Event::Brk(0x800b));
+
+        let (mut debugger, mut cpu) = setup();
+        let conditional = debugger.add_breakpoint(sub, Some(Expr::parse("A == $40").unwrap()));
+        assert_eq!(debugger.cont(&mut cpu), Event::Brk(0x800b));
+        assert!(debugger.remove(conditional));
+        assert!(debugger.breakpoints().is_empty());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let (mut debugger, mut cpu) = setup();
+        let write = debugger.add_watchpoint(0x0200, 0x02ff, Access::Write);
+        assert_eq!(
+            debugger.cont(&mut cpu),
+            Event::Watchpoint {
+                id: write,
+                address: 0x0200,
+                access: Access::Write
+            }
+        );
+        // Stopped after the store.
+        assert_eq!(cpu.program_counter, 0x8008);
+        assert_eq!(cpu.mem_read(0x0200), 0x01);
+
+        let read = debugger.add_watchpoint(0x0201, 0x0201, Access::Read);
+        assert_eq!(
+            debugger.cont(&mut cpu),
+            Event::Watchpoint {
+                id: read,
+                address: 0x0201,
+                access: Access::Read
+            }
+        );
+    }
+}
@@ -0,0 +1,653 @@
+use super::{Access, Debugger, Event, Expr, Register};
+use crate::cheats::{Cheat, CheatKind};
+use crate::cpu::{Mem, CPU};
+use crate::disasm::{self, Instruction};
+use crate::ramsearch::{Filter, RamSearch, Relation, ValueType};
+use std::io::{self, BufRead, Write};
+
+const PROMPT: &str = "(nes) ";
+
+/// Candidates printed by `search list`.
+const SEARCH_LIST_LIMIT: usize = 20;
+
+const HELP: &str = "\
+step [n]               execute n instructions (s)
+next                   step over subroutine calls (n)
+finish                 run until the current subroutine returns
+continue               run until a breakpoint, watchpoint or BRK (c)
+break <addr> [if <condition>]
+                       stop before the instruction at addr (b)
+watch <addr>[..<addr>] [r|w|rw]
+                       stop after an access to the range, default w
+delete <id>            remove a breakpoint or watchpoint (d)
+info                   list breakpoints and watchpoints (i)
+regs                   show registers (r)
+set <reg|addr> <value> change a register or a memory byte
+x <addr> [count]       dump memory
+dis [addr] [count]     disassemble, by default around PC
+search new [u8|i8|u16|i16]
+                       start a RAM search over work and cartridge RAM
+search <op> [value]    keep candidates whose value is op the previous
+                       one, or value if given; op is == != < > <= >=
+search +<n> | -<n>     keep candidates that changed by n
+search list            show the remaining candidates
+search freeze          freeze the remaining candidates at their values
+freeze <addr> [value]  hold a memory byte, by default at its current value
+cheat <code> [description]
+                       add a Game Genie or AAAA:VV Pro Action Replay code
+toggle <n>             turn cheat n off or back on
+unfreeze <n>           remove cheat n
+quit                   leave the debugger (q)
+
+Addresses and values are expressions: $c000, %1010, 42, labels,
+registers (A X Y P SP PC), [addr] for memory, + - == != < <= > >= && || !.
+An empty line repeats the last command.
+";
+
+/// Failure of a single command: bad input is reported and the session
+/// goes on, while an I/O error ends it.
+enum CommandError {
+    Usage(String),
+    Io(io::Error),
+}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        CommandError::Usage(message)
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(err: io::Error) -> Self {
+        CommandError::Io(err)
+    }
+}
+
+/// Reads debugger commands from `input` until `quit` or end of input.
+pub fn run<R: BufRead, W: Write>(
+    debugger: &mut Debugger,
+    cpu: &mut CPU,
+    input: R,
+    mut output: W,
+) -> io::Result<()> {
+    let mut last = String::new();
+    write!(output, "{}", PROMPT)?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        let command = if line.trim().is_empty() {
+            last.clone()
+        } else {
+            line.trim().to_string()
+        };
+        if !command.is_empty() {
+            match execute(debugger, cpu, &command, &mut output) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(CommandError::Usage(message)) => writeln!(output, "error: {}", message)?,
+                Err(CommandError::Io(err)) => return Err(err),
+            }
+        }
+        last = command;
+        write!(output, "{}", PROMPT)?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Runs one command, returning `true` when the user quits.
+fn execute<W: Write>(
+    debugger: &mut Debugger,
+    cpu: &mut CPU,
+    command: &str,
+    output: &mut W,
+) -> Result<bool, CommandError> {
+    let (name, args) = match command.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (command, ""),
+    };
+    let eval = |debugger: &Debugger, cpu: &CPU, text: &str| {
+        Expr::parse(text).and_then(|expr| expr.eval(cpu, &debugger.symbols))
+    };
+    let address = |debugger: &Debugger, cpu: &CPU, text: &str| {
+        Expr::parse(text).and_then(|expr| expr.address(cpu, &debugger.symbols))
+    };
+
+    match name {
+        "step" | "s" => {
+            let count = if args.is_empty() {
+                1
+            } else {
+                match eval(debugger, cpu, args)? {
+                    count if count > 0 => count as usize,
+                    count => return Err(format!("cannot step {} times", count).into()),
+                }
+            };
+            let event = debugger.step(cpu, count);
+            report(debugger, cpu, &event, output)?;
+        }
+        "next" | "n" => {
+            let event = debugger.step_over(cpu);
+            report(debugger, cpu, &event, output)?;
+        }
+        "finish" => {
+            let event = debugger.step_out(cpu);
+            report(debugger, cpu, &event, output)?;
+        }
+        "continue" | "c" => {
+            let event = debugger.cont(cpu);
+            report(debugger, cpu, &event, output)?;
+        }
+        "break" | "b" => {
+            let (target, condition) = match args.split_once(" if ") {
+                Some((target, condition)) => (target, Some(condition)),
+                None => (args, None),
+            };
+            let addr = address(debugger, cpu, target)?;
+            let condition = condition.map(Expr::parse).transpose()?;
+            let id = debugger.add_breakpoint(addr, condition);
+            writeln!(
+                output,
+                "Breakpoint {} at {}",
+                id,
+                name_address(debugger, addr)
+            )?;
+        }
+        "watch" | "w" => {
+            let (range, kind) = match args.rsplit_once(char::is_whitespace) {
+                Some((range, kind @ ("r" | "w" | "rw"))) => (range.trim(), kind),
+                _ => (args, "w"),
+            };
+            let access = match kind {
+                "r" => Access::Read,
+                "rw" => Access::ReadWrite,
+                _ => Access::Write,
+            };
+            let (start, end) = range.split_once("..").unwrap_or((range, range));
+            let (start, end) = (address(debugger, cpu, start)?, address(debugger, cpu, end)?);
+            let id = debugger.add_watchpoint(start, end, access);
+            writeln!(
+                output,
+                "Watchpoint {} on ${:04X}..${:04X} ({})",
+                id,
+                start.min(end),
+                start.max(end),
+                kind
+            )?;
+        }
+        "delete" | "d" => match args.parse::<usize>() {
+            Ok(id) if debugger.remove(id) => writeln!(output, "Deleted {}", id)?,
+            _ => return Err(format!("no breakpoint or watchpoint {}", args).into()),
+        },
+        "info" | "i" => {
+            for breakpoint in debugger.breakpoints() {
+                write!(
+                    output,
+                    "{:3}  break  {}",
+                    breakpoint.id,
+                    name_address(debugger, breakpoint.address)
+                )?;
+                match &breakpoint.condition {
+                    Some(_) => writeln!(output, " (conditional)")?,
+                    None => writeln!(output)?,
+                }
+            }
+            for watchpoint in debugger.watchpoints() {
+                writeln!(
+                    output,
+                    "{:3}  watch  ${:04X}..${:04X} {:?}",
+                    watchpoint.id, watchpoint.start, watchpoint.end, watchpoint.access
+                )?;
+            }
+            for (index, cheat) in debugger.cheats.cheats().iter().enumerate() {
+                writeln!(
+                    output,
+                    "{:3}  cheat  {}{}",
+                    index,
+                    describe_cheat(debugger, cheat),
+                    if cheat.enabled { "" } else { " (off)" }
+                )?;
+            }
+        }
+        "regs" | "r" => print_registers(cpu, output)?,
+        "set" => {
+            let (target, value) = match args.split_once(char::is_whitespace) {
+                Some((target, value)) => (target, value.trim()),
+                None => return Err("usage: set <reg|addr> <value>".to_string().into()),
+            };
+            let value = eval(debugger, cpu, value)?;
+            match Register::from_name(target) {
+                Some(register) => register.set(cpu, value as u16),
+                None => cpu.mem_write(address(debugger, cpu, target)?, value as u8),
+            }
+        }
+        "x" => {
+            let mut words = args.split_whitespace();
+            let start = match words.next() {
+                Some(text) => address(debugger, cpu, text)?,
+                None => return Err("usage: x <addr> [count]".to_string().into()),
+            };
+            let count = match words.next() {
+                Some(text) => eval(debugger, cpu, text)?.max(1) as usize,
+                None => 16,
+            };
+            let bytes = read_bytes(cpu, start, count);
+            for (row, chunk) in bytes.chunks(16).enumerate() {
+                let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+                writeln!(
+                    output,
+                    "{:04X}  {}",
+                    start.wrapping_add(row as u16 * 16),
+                    hex.join(" ")
+                )?;
+            }
+        }
+        "dis" => {
+            let mut words = args.split_whitespace();
+            let start = match words.next() {
+                Some(text) => Some(address(debugger, cpu, text)?),
+                None => None,
+            };
+            let count = match words.next() {
+                Some(text) => eval(debugger, cpu, text)?.max(1) as usize,
+                None => 10,
+            };
+            let listing = match start {
+                Some(start) => disassemble(debugger, cpu, start, count),
+                None => disassemble_around(debugger, cpu, 4, count.saturating_sub(4).max(1)),
+            };
+            for instruction in listing {
+                print_instruction(cpu, &instruction, output)?;
+            }
+        }
+        "search" => search(debugger, cpu, args, output)?,
+        "freeze" => {
+            let mut words = args.split_whitespace();
+            let addr = match words.next() {
+                Some(text) => address(debugger, cpu, text)?,
+                None => return Err("usage: freeze <addr> [value]".to_string().into()),
+            };
+            let value = match words.next() {
+                Some(text) => eval(debugger, cpu, text)? as u8,
+                None => cpu.mem_read(addr),
+            };
+            let cheat = Cheat::freeze(addr, value);
+            let index = debugger.cheats.add(cheat.clone());
+            writeln!(
+                output,
+                "Cheat {}: {}",
+                index,
+                describe_cheat(debugger, &cheat)
+            )?;
+        }
+        "cheat" => {
+            let (code, description) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            let mut cheat = Cheat::parse(code).map_err(|err| err.to_string())?;
+            cheat.description = description.trim().to_string();
+            let index = debugger.cheats.add(cheat.clone());
+            writeln!(
+                output,
+                "Cheat {}: {}",
+                index,
+                describe_cheat(debugger, &cheat)
+            )?;
+        }
+        "toggle" => {
+            let cheat = args
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| Some((index, debugger.cheats.cheats().get(index)?.enabled)));
+            match cheat {
+                Some((index, enabled)) => {
+                    debugger.cheats.set_enabled(index, !enabled);
+                    let state = if enabled { "off" } else { "on" };
+                    writeln!(output, "Cheat {} {}", index, state)?;
+                }
+                None => return Err(format!("no cheat {}", args).into()),
+            }
+        }
+        "unfreeze" => match args.parse::<usize>() {
+            Ok(index) if debugger.cheats.remove(index).is_some() => {
+                writeln!(output, "Removed cheat {}", index)?
+            }
+            _ => return Err(format!("no cheat {}", args).into()),
+        },
+        "help" | "h" | "?" => write!(output, "{}", HELP)?,
+        "quit" | "q" => return Ok(true),
+        _ => return Err(format!("unknown command {}, try help", name).into()),
+    }
+    Ok(false)
+}
+
+fn search<W: Write>(
+    debugger: &mut Debugger,
+    cpu: &CPU,
+    args: &str,
+    output: &mut W,
+) -> Result<(), CommandError> {
+    let (action, rest) = match args.split_once(char::is_whitespace) {
+        Some((action, rest)) => (action, rest.trim()),
+        None => (args, ""),
+    };
+    if action == "new" {
+        let value_type = match rest {
+            "" => ValueType::U8,
+            name => ValueType::from_name(name).ok_or(format!("unknown value type {}", name))?,
+        };
+        let search = RamSearch::new(cpu, value_type);
+        writeln!(output, "candidates: {}", search.candidates().len())?;
+        debugger.search = Some(search);
+        return Ok(());
+    }
+
+    let search = debugger
+        .search
+        .as_mut()
+        .ok_or_else(|| "no search in progress, start one with search new".to_string())?;
+    let value = |text: &str| {
+        Expr::parse(text)
+            .and_then(|expr| expr.eval(cpu, &debugger.symbols))
+            .map(|value| value as i32)
+    };
+    let filter = match action {
+        "list" => {
+            let value_type = search.value_type();
+            for candidate in search.candidates().iter().take(SEARCH_LIST_LIMIT) {
+                writeln!(
+                    output,
+                    "${:04X}  {} (was {})",
+                    candidate.address,
+                    value_type.read(cpu, candidate.address),
+                    candidate.previous
+                )?;
+            }
+            if search.candidates().len() > SEARCH_LIST_LIMIT {
+                writeln!(
+                    output,
+                    "... {} more",
+                    search.candidates().len() - SEARCH_LIST_LIMIT
+                )?;
+            }
+            return Ok(());
+        }
+        "freeze" => {
+            for cheat in search.freeze(cpu) {
+                debugger.cheats.add(cheat);
+            }
+            writeln!(output, "cheats: {}", debugger.cheats.len())?;
+            return Ok(());
+        }
+        _ if args.starts_with('+') => Filter::ChangedBy(value(&args[1..])?),
+        _ if args.starts_with('-') => Filter::ChangedBy(value(&args[1..])?.wrapping_neg()),
+        _ => {
+            let relation = Relation::from_symbol(action)
+                .ok_or(format!("unknown search filter {}, try help", action))?;
+            match rest {
+                "" => Filter::Previous(relation),
+                rest => Filter::Value(relation, value(rest)?),
+            }
+        }
+    };
+    let remaining = search.filter(cpu, filter);
+    writeln!(output, "candidates: {}", remaining)?;
+    Ok(())
+}
+
+fn report<W: Write>(
+    debugger: &Debugger,
+    cpu: &CPU,
+    event: &Event,
+    output: &mut W,
+) -> io::Result<()> {
+    match event {
+        Event::Stepped => {}
+        Event::Breakpoint(id) => writeln!(
+            output,
+            "Breakpoint {} at {}",
+            id,
+            name_address(debugger, cpu.program_counter)
+        )?,
+        Event::Watchpoint {
+            id,
+            address,
+            access,
+        } => writeln!(
+            output,
+            "Watchpoint {}: {:?} ${:04X} = {:02X}",
+            id,
+            access,
+            address,
+            cpu.mem_read(*address)
+        )?,
+        Event::Brk(address) => writeln!(output, "BRK at ${:04X}", address)?,
+        Event::Fault(err) => writeln!(output, "CPU fault: {}", err)?,
+    }
+    let instruction = disassemble(debugger, cpu, cpu.program_counter, 1).remove(0);
+    print_instruction(cpu, &instruction, output)
+}
+
+fn print_registers<W: Write>(cpu: &CPU, output: &mut W) -> io::Result<()> {
+    let flags: String = "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(index, flag)| {
+            if cpu.status & (0x80 >> index) != 0 {
+                flag
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    writeln!(
+        output,
+        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} [{}] SP:{:02X} PC:{:04X} CYC:{}",
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status,
+        flags,
+        cpu.stack_pointer,
+        cpu.program_counter,
+        cpu.cycles
+    )
+}
+
+fn print_instruction<W: Write>(
+    cpu: &CPU,
+    instruction: &Instruction,
+    output: &mut W,
+) -> io::Result<()> {
+    if let Some(label) = &instruction.label {
+        writeln!(output, "{}:", label)?;
+    }
+    let marker = if instruction.address == cpu.program_counter {
+        "=>"
+    } else {
+        "  "
+    };
+    writeln!(
+        output,
+        "{} {:04X}  {:8}  {}",
+        marker,
+        instruction.address,
+        instruction.hex(),
+        instruction
+    )
+}
+
+fn name_address(debugger: &Debugger, addr: u16) -> String {
+    match debugger.symbols.describe(addr) {
+        Some(name) => format!("${:04X} <{}>", addr, name),
+        None => format!("${:04X}", addr),
+    }
+}
+
+/// `SXIOPO $91D9 = AD Infinite lives`, or without the code for a freeze.
+fn describe_cheat(debugger: &Debugger, cheat: &Cheat) -> String {
+    let code = match cheat.kind {
+        CheatKind::Freeze => String::new(),
+        CheatKind::ReadPatch => format!("{} ", cheat.code()),
+    };
+    let compare = match cheat.compare {
+        Some(compare) => format!(" if {:02X}", compare),
+        None => String::new(),
+    };
+    format!(
+        "{}{} = {:02X}{} {}",
+        code,
+        name_address(debugger, cheat.address),
+        cheat.value,
+        compare,
+        cheat.description
+    )
+    .trim_end()
+    .to_string()
+}
+
+fn read_bytes(cpu: &CPU, start: u16, count: usize) -> Vec<u8> {
+    (0..count)
+        .map(|offset| cpu.mem_read(start.wrapping_add(offset as u16)))
+        .collect()
+}
+
+fn disassemble(debugger: &Debugger, cpu: &CPU, start: u16, count: usize) -> Vec<Instruction> {
+    let mut result = Vec::with_capacity(count);
+    let mut addr = start;
+    for _ in 0..count {
+        let instruction = disasm::decode(&read_bytes(cpu, addr, 3), addr, Some(&debugger.symbols));
+        addr = instruction.next_address();
+        result.push(instruction);
+    }
+    result
+}
+
+/// Disassembles `before` instructions leading up to PC and `after` from PC.
+/// Decoding backwards is ambiguous, so this picks the furthest start that
+/// decodes cleanly into PC.
+fn disassemble_around(
+    debugger: &Debugger,
+    cpu: &CPU,
+    before: usize,
+    after: usize,
+) -> Vec<Instruction> {
+    let pc = cpu.program_counter;
+    let mut leading = Vec::new();
+    for distance in (1..=before as u16 * 3).rev() {
+        let start = pc.wrapping_sub(distance);
+        let bytes = read_bytes(cpu, start, distance as usize);
+        let listing = disasm::disassemble_with_symbols(&bytes, start, Some(&debugger.symbols));
+        let lands_on_pc = listing.last().map(Instruction::next_address) == Some(pc);
+        if lands_on_pc && listing.iter().all(|i| i.mnemonic != ".byte") {
+            leading = listing;
+            break;
+        }
+    }
+    let skip = leading.len().saturating_sub(before);
+    leading.drain(..skip);
+    leading.extend(disassemble(debugger, cpu, pc, after));
+    leading
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn session(commands: &str) -> (String, CPU) {
+        let program = asm::assemble_program(
+            "
+            main:   LDA #$01
+                    JSR sub
+                    STA $0200
+                    BRK
+            sub:    TAX
+                    INX
+                    RTS
+            ",
+        )
+        .unwrap();
+        let mut cpu = CPU::default();
+        cpu.load(program.bytes);
+        cpu.reset();
+        let mut debugger = Debugger::new(program.symbols);
+        let mut output = Vec::new();
+        run(&mut debugger, &mut cpu, commands.as_bytes(), &mut output).unwrap();
+        (String::from_utf8(output).unwrap(), cpu)
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        let (output, cpu) = session("break sub if A == 1\ncontinue\nregs\nquit\n");
+        assert!(output.contains("Breakpoint 1 at $8009 <sub>\n"));
+        assert!(output.contains("sub:\n=> 8009  AA        TAX\n"));
+        assert!(output.contains("A:01 X:00 Y:00 P:00 [........] SP:FB PC:8009"));
+        assert_eq!(cpu.program_counter, 0x8009);
+    }
+
+    #[test]
+    fn test_step_next_and_repeat() {
+        let (output, cpu) = session("s\nn\n\nx $0200 1\n");
+        assert!(output.contains("=> 8002  20 09 80  JSR sub\n"));
+        assert!(output.contains("=> 8005  8D 00 02  STA $0200\n"));
+        assert!(output.contains("0200  01\n"));
+        assert_eq!(cpu.program_counter, 0x8008);
+    }
+
+    #[test]
+    fn test_watch_set_and_errors() {
+        let (output, cpu) =
+            session("watch $0200..$02ff\nset X $10\nset $10 $aa\nc\nbogus\nb nowhere\n");
+        assert!(output.contains("Watchpoint 1 on $0200..$02FF (w)\n"));
+        assert!(output.contains("Watchpoint 1: Write $0200 = 01\n"));
+        assert!(output.contains("error: unknown command bogus, try help\n"));
+        assert!(output.contains("error: unknown symbol nowhere\n"));
+        assert_eq!(cpu.mem_read(0x10), 0xaa);
+    }
+
+    #[test]
+    fn test_disassemble_around_pc() {
+        let (output, _) = session("s 2\ndis\n");
+        let listing = output.split("(nes) ").nth(2).unwrap();
+        assert!(listing.starts_with("main:\n   8000  A9 01     LDA #$01\n"));
+        assert!(listing.contains("   8002  20 09 80  JSR sub\n"));
+        assert!(listing.contains("sub:\n=> 8009  AA        TAX\n"));
+    }
+
+    #[test]
+    fn test_ram_search_and_freeze() {
+        let (output, cpu) = session(
+            "search new\nset $0200 5\nsearch !=\nset $0200 9\nsearch +4\nsearch list\n\
+             search freeze\nc\ni\nunfreeze 0\nunfreeze 0\nsearch < 3\nsearch -$80000000\n",
+        );
+        assert!(output.contains("candidates: 10240\n(nes) (nes) candidates: 1\n"));
+        assert!(output.contains("$0200  9 (was 9)\n"));
+        assert!(output.contains("  0  cheat  $0200 = 09\n"));
+        assert!(output.contains("Removed cheat 0\n"));
+        assert!(output.contains("error: no cheat 0\n"));
+        assert!(output.contains("candidates: 0\n"));
+        // Negating i32::MIN wraps instead of overflowing.
+        assert!(output.ends_with("candidates: 0\n(nes) "));
+        // The program stores 1 at $0200, but the cheat held it at 9.
+        assert_eq!(cpu.mem_read(0x0200), 9);
+    }
+
+    #[test]
+    fn test_game_genie_cheats() {
+        // LDA #$01 at $8000 becomes LDA #$09 with the operand patched.
+        let code = crate::cheats::game_genie::encode(0x8001, 0x09, Some(0x01));
+        let (output, cpu) = session(&format!(
+            "cheat {} Start with 9\ni\ntoggle 0\ni\ntoggle 0\nc\ncheat NOPE\ntoggle 3\n",
+            code
+        ));
+        assert!(output.contains(&format!(
+            "Cheat 0: {} $8001 <main+1> = 09 if 01 Start with 9\n",
+            code
+        )));
+        assert!(output.contains(" Start with 9 (off)\n"));
+        assert!(output.contains("Cheat 0 off\n"));
+        assert!(output.contains("Cheat 0 on\n"));
+        assert!(output.contains("error: bad cheat code NOPE\n"));
+        assert!(output.contains("error: no cheat 3\n"));
+        assert_eq!(cpu.register_a, 0x09);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::cpu::AddressingMode;
+use crate::opcodes;
+use crate::symbols::{name_or_hex, SymbolTable};
+use std::fmt;
+
+/// One decoded instruction, or a single `.byte` for anything that is not a
+/// known opcode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    /// Operand in assembler syntax, e.g. `($12),Y`; empty for implied ops.
+    pub operand: String,
+    /// Address referenced by the operand, if it has one.
+    pub target: Option<u16>,
+    /// Symbol naming this instruction's own address.
+    pub label: Option<String>,
+}
+
+impl Instruction {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Address of the byte following this instruction.
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.bytes.len() as u16)
+    }
+
+    /// Hex dump of the instruction bytes, e.g. `B1 33`.
+    pub fn hex(&self) -> String {
+        self.bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.operand.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operand)
+        }
+    }
+}
+
+/// Disassembles `bytes` as if they were mapped at `start_addr`.
+pub fn disassemble(bytes: &[u8], start_addr: u16) -> Vec<Instruction> {
+    disassemble_with_symbols(bytes, start_addr, None)
+}
+
+/// Like [`disassemble`], naming addresses found in `symbols`.
+pub fn disassemble_with_symbols(
+    bytes: &[u8],
+    start_addr: u16,
+    symbols: Option<&SymbolTable>,
+) -> Vec<Instruction> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let instruction = decode(
+            &bytes[offset..],
+            start_addr.wrapping_add(offset as u16),
+            symbols,
+        );
+        offset += instruction.len();
+        result.push(instruction);
+    }
+    result
+}
+
+/// Decodes the instruction at the start of `bytes`. An opcode whose operand
+/// runs past the end of `bytes` is treated as unknown.
+///
+/// Panics if `bytes` is empty.
+pub fn decode(bytes: &[u8], address: u16, symbols: Option<&SymbolTable>) -> Instruction {
+    let code = bytes[0];
+    let label = symbols.and_then(|s| s.get(address)).map(str::to_string);
+    let opcode = match opcodes::OPCODES_MAP.get(&code) {
+        Some(opcode) if opcode.len as usize <= bytes.len() => opcode,
+        _ => {
+            return Instruction {
+                address,
+                bytes: vec![code],
+                mnemonic: ".byte",
+                operand: format!("${:02X}", code),
+                target: None,
+                label,
+            }
+        }
+    };
+
+    let bytes = bytes[..opcode.len as usize].to_vec();
+    let name = |addr: u16, width: usize| name_or_hex(symbols, addr, width);
+
+    let (operand, target) = match opcode.mode {
+        AddressingMode::NoneAddressing => (String::new(), None),
+        AddressingMode::Accumulator => ("A".to_string(), None),
+        AddressingMode::Immediate => (format!("#${:02X}", bytes[1]), None),
+        AddressingMode::ZeroPage
+        | AddressingMode::ZeroPage_X
+        | AddressingMode::ZeroPage_Y
+        | AddressingMode::Indirect_X
+        | AddressingMode::Indirect_Y => {
+            let addr = bytes[1] as u16;
+            let operand = match opcode.mode {
+                AddressingMode::ZeroPage => name(addr, 2),
+                AddressingMode::ZeroPage_X => format!("{},X", name(addr, 2)),
+                AddressingMode::ZeroPage_Y => format!("{},Y", name(addr, 2)),
+                AddressingMode::Indirect_X => format!("({},X)", name(addr, 2)),
+                _ => format!("({}),Y", name(addr, 2)),
+            };
+            (operand, Some(addr))
+        }
+        AddressingMode::Absolute
+        | AddressingMode::Absolute_X
+        | AddressingMode::Absolute_Y
+        | AddressingMode::Indirect => {
+            let addr = (bytes[2] as u16) << 8 | (bytes[1] as u16);
+            let operand = match opcode.mode {
+                AddressingMode::Absolute => name(addr, 4),
+                AddressingMode::Absolute_X => format!("{},X", name(addr, 4)),
+                AddressingMode::Absolute_Y => format!("{},Y", name(addr, 4)),
+                _ => format!("({})", name(addr, 4)),
+            };
+            (operand, Some(addr))
+        }
+        AddressingMode::Relative => {
+            let offset = bytes[1] as i8;
+            let addr = address.wrapping_add(2).wrapping_add(offset as u16);
+            (name(addr, 4), Some(addr))
+        }
+    };
+
+    Instruction {
+        address,
+        bytes,
+        mnemonic: opcode.mnemonic,
+        operand,
+        target,
+        label,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(bytes: &[u8], start_addr: u16) -> Vec<String> {
+        disassemble(bytes, start_addr)
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_addressing_mode_syntax() {
+        let bytes = [
+            0xa9, 0x01, // LDA #$01
+            0xb1, 0x12, // LDA ($12),Y
+            0xa1, 0x12, // LDA ($12,X)
+            0x9d, 0x55, 0x44, // STA $4455,X
+            0xb6, 0x10, // LDX $10,Y
+            0x0a, // ASL A
+            0x6c, 0x00, 0x02, // JMP ($0200)
+            0xe8, // INX
+        ];
+        assert_eq!(
+            listing(&bytes, 0x8000),
+            vec![
+                "LDA #$01",
+                "LDA ($12),Y",
+                "LDA ($12,X)",
+                "STA $4455,X",
+                "LDX $10,Y",
+                "ASL A",
+                "JMP ($0200)",
+                "INX",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_branch_targets_are_resolved() {
+        // BNE -4; BPL +2
+        let instructions = disassemble(&[0xd0, 0xfc, 0x10, 0x02], 0xc000);
+        assert_eq!(instructions[0].to_string(), "BNE $BFFE");
+        assert_eq!(instructions[0].target, Some(0xbffe));
+        assert_eq!(instructions[1].to_string(), "BPL $C006");
+    }
+
+    #[test]
+    fn test_unknown_and_truncated_bytes() {
+        let instructions = disassemble(&[0x02, 0xea, 0xad, 0x00], 0x8000);
+        assert_eq!(
+            instructions
+                .iter()
+                .map(|instruction| (instruction.address, instruction.to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0x8000, ".byte $02".to_string()),
+                (0x8001, "NOP".to_string()),
+                (0x8002, ".byte $AD".to_string()),
+                (0x8003, "BRK".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_symbols_label_addresses() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x8000, "main");
+        symbols.insert(0x0010, "counter");
+
+        // main: INC counter; JMP main; LDA counter+1
+        let instructions = disassemble_with_symbols(
+            &[0xe6, 0x10, 0x4c, 0x00, 0x80, 0xa5, 0x11],
+            0x8000,
+            Some(&symbols),
+        );
+        assert_eq!(instructions[0].label.as_deref(), Some("main"));
+        assert_eq!(instructions[0].to_string(), "INC counter");
+        assert_eq!(instructions[1].label, None);
+        assert_eq!(instructions[1].to_string(), "JMP main");
+        assert_eq!(instructions[1].hex(), "4C 00 80");
+        assert_eq!(instructions[2].to_string(), "LDA counter+1");
+    }
+
+    #[test]
+    fn test_opcode_table_covers_official_set() {
+        assert_eq!(opcodes::CPU_OPS_CODES.len(), 151);
+        assert_eq!(opcodes::OPCODES_MAP.len(), 151);
+    }
+}
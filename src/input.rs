@@ -0,0 +1,634 @@
+use crate::render::frame::Frame;
+use crate::render::palette::SYSTEM_PALETTE;
+use crate::savestate::{ChunkReader, StateError};
+use std::fmt;
+
+/// # Standard controller buttons, in the order they are shifted out
+///
+///  7 6 5 4 3 2 1 0
+///  R L D U T S B A
+///  | | | | | | | +--- A
+///  | | | | | | +----- B
+///  | | | | | +------- Select
+///  | | | | +--------- Start
+///  | | | +----------- Up
+///  | | +------------- Down
+///  | +--------------- Left
+///  +----------------- Right
+///
+pub const BUTTON_A: u8 = 0b0000_0001;
+pub const BUTTON_B: u8 = 0b0000_0010;
+pub const BUTTON_SELECT: u8 = 0b0000_0100;
+pub const BUTTON_START: u8 = 0b0000_1000;
+pub const BUTTON_UP: u8 = 0b0001_0000;
+pub const BUTTON_DOWN: u8 = 0b0010_0000;
+pub const BUTTON_LEFT: u8 = 0b0100_0000;
+pub const BUTTON_RIGHT: u8 = 0b1000_0000;
+
+/// Four Score signature bytes, shifted out LSB first after the two
+/// controllers of a port, so reads 16-23 (counting from 0) are
+/// 0,0,0,1,0,0,0,0 on `$4016` and 0,0,1,0,0,0,0,0 on `$4017`.
+const FOUR_SCORE_SIGNATURE_PORT1: u8 = 0b0000_1000;
+const FOUR_SCORE_SIGNATURE_PORT2: u8 = 0b0000_0100;
+
+/// Minimum luminance (0-255) around the aim point for the Zapper to see light.
+const ZAPPER_LIGHT_THRESHOLD: u32 = 0x80;
+/// Radius in pixels of the area the Zapper photodiode looks at.
+const ZAPPER_SENSE_RADIUS: usize = 2;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InputError {
+    /// Power Pad buttons are numbered 1 to 12.
+    InvalidButton(u8),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::InvalidButton(button) => {
+                write!(f, "Power Pad has no button {}, expected 1-12", button)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+/// Standard NES controller.
+#[derive(Default)]
+pub struct Joypad {
+    strobe: bool,
+    button_index: u8,
+    pub button_status: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_button_pressed_status(&mut self, button: u8, pressed: bool) {
+        if pressed {
+            self.button_status |= button;
+        } else {
+            self.button_status &= !button;
+        }
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index = 0;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        let response = (self.button_status >> self.button_index) & 1;
+        if !self.strobe {
+            self.button_index += 1;
+        }
+        response
+    }
+}
+
+/// One half of the NES Four Score multitap.
+///
+/// The adapter spans both ports: port 1 carries players 1 and 3, port 2
+/// carries players 2 and 4. Each port shifts out 8 bits for the first
+/// player, 8 bits for the second, then an 8-bit signature.
+pub struct FourScore {
+    strobe: bool,
+    bit_index: u8,
+    signature: u8,
+    /// Button state of the first (players 1/2) and second (players 3/4) controller.
+    pub button_status: [u8; 2],
+}
+
+impl FourScore {
+    /// Four Score half plugged into port 1 (players 1 and 3).
+    pub fn port1() -> Self {
+        Self::with_signature(FOUR_SCORE_SIGNATURE_PORT1)
+    }
+
+    /// Four Score half plugged into port 2 (players 2 and 4).
+    pub fn port2() -> Self {
+        Self::with_signature(FOUR_SCORE_SIGNATURE_PORT2)
+    }
+
+    fn with_signature(signature: u8) -> Self {
+        FourScore {
+            strobe: false,
+            bit_index: 0,
+            signature,
+            button_status: [0; 2],
+        }
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.bit_index = 0;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let response = match self.bit_index {
+            0..=7 => (self.button_status[0] >> self.bit_index) & 1,
+            8..=15 => (self.button_status[1] >> (self.bit_index - 8)) & 1,
+            16..=23 => (self.signature >> (self.bit_index - 16)) & 1,
+            _ => return 1,
+        };
+        if !self.strobe {
+            self.bit_index += 1;
+        }
+        response
+    }
+}
+
+/// NES Zapper light gun.
+///
+/// Reads return the light sense on D3 (0 = light detected) and the trigger
+/// on D4 (1 = pulled). Light is sampled from the rendered frame around the
+/// aim point with [`Zapper::sense_light`].
+#[derive(Default)]
+pub struct Zapper {
+    pub x: usize,
+    pub y: usize,
+    pub trigger: bool,
+    light: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn aim(&mut self, x: usize, y: usize) {
+        self.x = x;
+        self.y = y;
+    }
+
+    pub fn sense_light(&mut self, frame: &Frame) {
+        self.light = false;
+        if self.x >= Frame::WIDTH || self.y >= Frame::HEIGHT {
+            return;
+        }
+
+        let x_range = self.x.saturating_sub(ZAPPER_SENSE_RADIUS)
+            ..=(self.x + ZAPPER_SENSE_RADIUS).min(Frame::WIDTH - 1);
+        let y_range = self.y.saturating_sub(ZAPPER_SENSE_RADIUS)
+            ..=(self.y + ZAPPER_SENSE_RADIUS).min(Frame::HEIGHT - 1);
+
+        let mut total = 0;
+        let mut count = 0;
+        for y in y_range {
+            for x in x_range.clone() {
+                let (r, g, b) = SYSTEM_PALETTE[(frame.get_pixel(x, y) & 0x3f) as usize];
+                total += (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+                count += 1;
+            }
+        }
+        self.light = total / count >= ZAPPER_LIGHT_THRESHOLD;
+    }
+
+    fn read(&self) -> u8 {
+        let light = if self.light { 0 } else { 1 };
+        (light << 3) | ((self.trigger as u8) << 4)
+    }
+}
+
+/// Arkanoid "Vaus" paddle controller (NES version).
+///
+/// On strobe the paddle position is latched and then shifted out on D4,
+/// most significant bit first and inverted. The fire button is on D3.
+#[derive(Default)]
+pub struct Vaus {
+    strobe: bool,
+    shift_register: u8,
+    pub position: u8,
+    pub button: bool,
+}
+
+impl Vaus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.shift_register = !self.position;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let response = ((self.shift_register >> 7) << 4) | ((self.button as u8) << 3);
+        if !self.strobe {
+            self.shift_register <<= 1;
+        }
+        response
+    }
+}
+
+/// Power Pad (Family Trainer) floor mat with 12 buttons.
+///
+/// Buttons 2, 1, 5, 9, 6, 10, 11, 7 are shifted out on D3 and buttons
+/// 4, 3, 12, 8 on D4; once exhausted both lines read 1.
+#[derive(Default)]
+pub struct PowerPad {
+    strobe: bool,
+    low_shift: u8,
+    high_shift: u8,
+    /// Bit `n - 1` is set while button `n` is pressed.
+    pub button_status: u16,
+}
+
+impl PowerPad {
+    const LOW_ORDER: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+    const HIGH_ORDER: [u8; 4] = [4, 3, 12, 8];
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_button_pressed_status(
+        &mut self,
+        button: u8,
+        pressed: bool,
+    ) -> Result<(), InputError> {
+        if !(1..=12).contains(&button) {
+            return Err(InputError::InvalidButton(button));
+        }
+        let mask = 1 << (button - 1);
+        if pressed {
+            self.button_status |= mask;
+        } else {
+            self.button_status &= !mask;
+        }
+        Ok(())
+    }
+
+    fn is_pressed(&self, button: u8) -> bool {
+        self.button_status & (1 << (button - 1)) != 0
+    }
+
+    fn latch(&mut self) {
+        self.low_shift = 0;
+        for (bit, button) in PowerPad::LOW_ORDER.iter().enumerate() {
+            if self.is_pressed(*button) {
+                self.low_shift |= 1 << bit;
+            }
+        }
+        self.high_shift = 0xf0;
+        for (bit, button) in PowerPad::HIGH_ORDER.iter().enumerate() {
+            if self.is_pressed(*button) {
+                self.high_shift |= 1 << bit;
+            }
+        }
+    }
+
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let response = ((self.low_shift & 1) << 3) | ((self.high_shift & 1) << 4);
+        if !self.strobe {
+            self.low_shift = (self.low_shift >> 1) | 0x80;
+            self.high_shift = (self.high_shift >> 1) | 0x80;
+        }
+        response
+    }
+}
+
+/// Device plugged into one of the two controller ports.
+#[derive(Default)]
+pub enum InputDevice {
+    #[default]
+    None,
+    Joypad(Joypad),
+    FourScore(FourScore),
+    Zapper(Zapper),
+    Vaus(Vaus),
+    PowerPad(PowerPad),
+}
+
+impl InputDevice {
+    fn write(&mut self, data: u8) {
+        match self {
+            InputDevice::None | InputDevice::Zapper(_) => {}
+            InputDevice::Joypad(joypad) => joypad.write(data),
+            InputDevice::FourScore(four_score) => four_score.write(data),
+            InputDevice::Vaus(vaus) => vaus.write(data),
+            InputDevice::PowerPad(power_pad) => power_pad.write(data),
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        match self {
+            InputDevice::None => 0,
+            InputDevice::Joypad(joypad) => joypad.read(),
+            InputDevice::FourScore(four_score) => four_score.read(),
+            InputDevice::Zapper(zapper) => zapper.read(),
+            InputDevice::Vaus(vaus) => vaus.read(),
+            InputDevice::PowerPad(power_pad) => power_pad.read(),
+        }
+    }
+
+    /// Appends a kind byte and the device's fields, shift registers
+    /// included, to a save state chunk.
+    fn save_state(&self, out: &mut Vec<u8>) {
+        match self {
+            InputDevice::None => out.push(0),
+            InputDevice::Joypad(joypad) => out.extend_from_slice(&[
+                1,
+                joypad.strobe as u8,
+                joypad.button_index,
+                joypad.button_status,
+            ]),
+            InputDevice::FourScore(four_score) => out.extend_from_slice(&[
+                2,
+                four_score.strobe as u8,
+                four_score.bit_index,
+                four_score.signature,
+                four_score.button_status[0],
+                four_score.button_status[1],
+            ]),
+            InputDevice::Zapper(zapper) => {
+                out.push(3);
+                out.extend_from_slice(&(zapper.x as u64).to_le_bytes());
+                out.extend_from_slice(&(zapper.y as u64).to_le_bytes());
+                out.extend_from_slice(&[zapper.trigger as u8, zapper.light as u8]);
+            }
+            InputDevice::Vaus(vaus) => out.extend_from_slice(&[
+                4,
+                vaus.strobe as u8,
+                vaus.shift_register,
+                vaus.position,
+                vaus.button as u8,
+            ]),
+            InputDevice::PowerPad(power_pad) => {
+                out.extend_from_slice(&[
+                    5,
+                    power_pad.strobe as u8,
+                    power_pad.low_shift,
+                    power_pad.high_shift,
+                ]);
+                out.extend_from_slice(&power_pad.button_status.to_le_bytes());
+            }
+        }
+    }
+
+    fn load_state(reader: &mut ChunkReader) -> Result<Self, StateError> {
+        Ok(match reader.u8()? {
+            0 => InputDevice::None,
+            1 => InputDevice::Joypad(Joypad {
+                strobe: reader.u8()? != 0,
+                button_index: reader.u8()?,
+                button_status: reader.u8()?,
+            }),
+            2 => InputDevice::FourScore(FourScore {
+                strobe: reader.u8()? != 0,
+                bit_index: reader.u8()?,
+                signature: reader.u8()?,
+                button_status: [reader.u8()?, reader.u8()?],
+            }),
+            3 => InputDevice::Zapper(Zapper {
+                x: reader.u64()? as usize,
+                y: reader.u64()? as usize,
+                trigger: reader.u8()? != 0,
+                light: reader.u8()? != 0,
+            }),
+            4 => InputDevice::Vaus(Vaus {
+                strobe: reader.u8()? != 0,
+                shift_register: reader.u8()?,
+                position: reader.u8()?,
+                button: reader.u8()? != 0,
+            }),
+            5 => InputDevice::PowerPad(PowerPad {
+                strobe: reader.u8()? != 0,
+                low_shift: reader.u8()?,
+                high_shift: reader.u8()?,
+                button_status: reader.u16()?,
+            }),
+            kind => return Err(StateError::UnknownDevice(kind)),
+        })
+    }
+}
+
+/// The two controller ports, `$4016` and `$4017`.
+///
+/// A write to `$4016` strobes both ports; each port is read at its own
+/// address and returns the device bits in D0-D4.
+#[derive(Default)]
+pub struct InputPorts {
+    pub port1: InputDevice,
+    pub port2: InputDevice,
+}
+
+impl InputPorts {
+    pub fn new(port1: InputDevice, port2: InputDevice) -> Self {
+        InputPorts { port1, port2 }
+    }
+
+    /// Standard controllers in both ports.
+    pub fn joypads() -> Self {
+        Self::new(
+            InputDevice::Joypad(Joypad::new()),
+            InputDevice::Joypad(Joypad::new()),
+        )
+    }
+
+    /// Four Score connected across both ports.
+    pub fn four_score() -> Self {
+        Self::new(
+            InputDevice::FourScore(FourScore::port1()),
+            InputDevice::FourScore(FourScore::port2()),
+        )
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.port1.write(data);
+        self.port2.write(data);
+    }
+
+    /// Device bits for a read of `addr`. Other addresses drive no bits,
+    /// leaving the whole byte to open bus.
+    pub fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4016 => self.port1.read(),
+            0x4017 => self.port2.read(),
+            _ => 0,
+        }
+    }
+
+    /// Appends both ports to a save state chunk.
+    pub(crate) fn save_state(&self, out: &mut Vec<u8>) {
+        self.port1.save_state(out);
+        self.port2.save_state(out);
+    }
+
+    /// Reads ports written by [`InputPorts::save_state`].
+    pub(crate) fn load_state(reader: &mut ChunkReader) -> Result<Self, StateError> {
+        Ok(InputPorts {
+            port1: InputDevice::load_state(reader)?,
+            port2: InputDevice::load_state(reader)?,
+        })
+    }
+
+    /// Samples the rendered frame for any Zapper plugged into either port.
+    pub fn sense_light(&mut self, frame: &Frame) {
+        for device in [&mut self.port1, &mut self.port2] {
+            if let InputDevice::Zapper(zapper) = device {
+                zapper.sense_light(frame);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_bits(ports: &mut InputPorts, addr: u16, count: usize) -> Vec<u8> {
+        (0..count).map(|_| ports.read(addr)).collect()
+    }
+
+    #[test]
+    fn test_joypad_shifts_buttons_in_order() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(BUTTON_A, true);
+        joypad.set_button_pressed_status(BUTTON_START, true);
+        joypad.set_button_pressed_status(BUTTON_RIGHT, true);
+        let mut ports = InputPorts::new(InputDevice::Joypad(joypad), InputDevice::None);
+
+        ports.write(1);
+        ports.write(0);
+
+        assert_eq!(
+            read_bits(&mut ports, 0x4016, 10),
+            vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]
+        );
+    }
+
+    #[test]
+    fn test_joypad_strobe_keeps_returning_a() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(BUTTON_A, true);
+        let mut ports = InputPorts::new(InputDevice::Joypad(joypad), InputDevice::None);
+
+        ports.write(1);
+
+        assert_eq!(read_bits(&mut ports, 0x4016, 3), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn test_four_score_signatures() {
+        let mut ports = InputPorts::four_score();
+        if let InputDevice::FourScore(four_score) = &mut ports.port1 {
+            four_score.button_status = [BUTTON_A, BUTTON_B];
+        }
+        if let InputDevice::FourScore(four_score) = &mut ports.port2 {
+            four_score.button_status = [BUTTON_SELECT, BUTTON_START];
+        }
+
+        ports.write(1);
+        ports.write(0);
+
+        let port1 = read_bits(&mut ports, 0x4016, 25);
+        let port2 = read_bits(&mut ports, 0x4017, 25);
+
+        assert_eq!(port1[0], 1);
+        assert_eq!(port1[9], 1);
+        assert_eq!(port1[19], 1);
+        assert_eq!(port2[18], 1);
+        assert_eq!(port1[16..24], [0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(port1[24], 1);
+
+        assert_eq!(port2[2], 1);
+        assert_eq!(port2[11], 1);
+        assert_eq!(port2[16..24], [0, 0, 1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_other_addresses_drive_no_bits() {
+        let mut ports = InputPorts::joypads();
+        ports.write(1);
+        assert_eq!(ports.read(0x4018), 0);
+        assert_eq!(ports.read(0x0000), 0);
+    }
+
+    #[test]
+    fn test_zapper_senses_bright_pixels() {
+        let mut frame = Frame::new();
+        for y in 98..=102 {
+            for x in 98..=102 {
+                frame.set_pixel(x, y, 0x30);
+            }
+        }
+        let mut zapper = Zapper::new();
+        zapper.aim(100, 100);
+        zapper.trigger = true;
+        let mut ports = InputPorts::new(InputDevice::None, InputDevice::Zapper(zapper));
+
+        ports.sense_light(&frame);
+        assert_eq!(ports.read(0x4017), 0b0001_0000);
+
+        let dark = Frame {
+            data: vec![0x0f; Frame::WIDTH * Frame::HEIGHT],
+        };
+        ports.sense_light(&dark);
+        assert_eq!(ports.read(0x4017), 0b0001_1000);
+    }
+
+    #[test]
+    fn test_vaus_shifts_inverted_position_msb_first() {
+        let mut vaus = Vaus::new();
+        vaus.position = 0b1010_0000;
+        vaus.button = true;
+        let mut ports = InputPorts::new(InputDevice::None, InputDevice::Vaus(vaus));
+
+        ports.write(1);
+        ports.write(0);
+
+        let bits: Vec<u8> = read_bits(&mut ports, 0x4017, 8)
+            .iter()
+            .map(|value| {
+                assert_eq!(value & 0b0000_1000, 0b0000_1000);
+                value >> 4
+            })
+            .collect();
+        assert_eq!(bits, vec![0, 1, 0, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_power_pad_streams() {
+        let mut power_pad = PowerPad::new();
+        power_pad.set_button_pressed_status(1, true).unwrap();
+        power_pad.set_button_pressed_status(12, true).unwrap();
+        assert_eq!(
+            power_pad.set_button_pressed_status(0, true),
+            Err(InputError::InvalidButton(0))
+        );
+        assert_eq!(
+            power_pad.set_button_pressed_status(13, true),
+            Err(InputError::InvalidButton(13))
+        );
+        let mut ports = InputPorts::new(InputDevice::None, InputDevice::PowerPad(power_pad));
+
+        ports.write(1);
+        ports.write(0);
+
+        let reads = read_bits(&mut ports, 0x4017, 10);
+        let low: Vec<u8> = reads.iter().map(|value| (value >> 3) & 1).collect();
+        let high: Vec<u8> = reads.iter().map(|value| (value >> 4) & 1).collect();
+        assert_eq!(low, vec![0, 1, 0, 0, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(high, vec![0, 0, 1, 0, 1, 1, 1, 1, 1, 1]);
+    }
+}
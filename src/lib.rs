@@ -0,0 +1,20 @@
+pub mod asm;
+pub mod blargg;
+pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod input;
+pub mod movie;
+pub mod opcodes;
+pub mod profiler;
+pub mod ramsearch;
+pub mod regression;
+pub mod render;
+pub mod rewind;
+pub mod runner;
+pub mod savestate;
+pub mod symbols;
+pub mod trace;
@@ -1,6 +1,433 @@
-pub mod cpu;
-pub mod opcodes;
+use nes_emulator::asm;
+use nes_emulator::blargg::{self, Verdict};
+use nes_emulator::cartridge::{Rom, PRG_ROM_PAGE_SIZE};
+use nes_emulator::cdl;
+use nes_emulator::cheats::CheatList;
+use nes_emulator::cpu::{Mem, CPU};
+use nes_emulator::debugger::gdb::GdbStub;
+use nes_emulator::debugger::{self, Debugger};
+use nes_emulator::disasm;
+use nes_emulator::movie::fm2;
+use nes_emulator::profiler::Profiler;
+use nes_emulator::regression::Suite;
+use nes_emulator::render::palette::SYSTEM_PALETTE;
+use nes_emulator::render::screenshot::{Crop, Image};
+use nes_emulator::runner::{frame_of, ExitCondition, InputScript, Runner, StopReason};
+use nes_emulator::symbols::{ld65, nesasm, SymbolTable};
+use std::io;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "\
+usage: nes_emulator disasm <rom.nes> [bank] [--org <hex address>]
+       nes_emulator debug <rom.nes | source.s> [--symbols <file.dbg | file.fns>] [--cheats <file>]
+       nes_emulator gdb <rom.nes | source.s> [port] [--symbols <file>] [--cheats <file>]
+       nes_emulator profile <rom.nes | source.s> [--symbols <file>] [--collapsed <file>]
+                            [--frames <n>]
+       nes_emulator run <rom.nes | source.s> [--frames <n>] [--input <script | movie.fm2>]
+                        [--until-pc <hex address>] [--until-mem <hex address>=<hex value>]
+                        [--until-halt] [--cheats <file>] [--screenshot-at <frame>]
+                        [--screenshot <file.png | file.ppm>] [--crop] [--blargg]
+       nes_emulator regress <rom dir> [--golden <dir>] [--frames <n>] [--crop] [--bless]
+                            [--junit <file>]";
+
+/// Hottest addresses listed by the `profile` subcommand.
+const PROFILE_LINES: usize = 20;
+
+/// Frames `profile` runs for at most unless `--frames` says otherwise, a
+/// minute of emulated time.
+const PROFILE_FRAMES: u64 = 3600;
+
+/// Port the GDB stub listens on unless one is given.
+const GDB_PORT: u16 = 2345;
+
+/// Exit status of `run` when the CPU faults, to tell it apart from bad
+/// arguments.
+const FAULT_EXIT_CODE: i32 = 2;
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("disasm") => disasm_command(&args[1..]),
+        Some("debug") => debug_command(&args[1..]),
+        Some("gdb") => gdb_command(&args[1..]),
+        Some("profile") => profile_command(&args[1..]),
+        Some("run") => run_command(&args[1..]),
+        Some("regress") => regress_command(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+/// Prints a listing of one 16 KiB PRG bank. Without `--org`, even banks are
+/// placed at $8000 and odd ones at $C000, which is where NROM maps them.
+fn disasm_command(args: &[String]) -> Result<(), String> {
+    let mut path = None;
+    let mut bank = 0;
+    let mut origin = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--org" => origin = Some(parse_hex(args.next().ok_or(USAGE)?)?),
+            _ if path.is_none() => path = Some(arg),
+            _ => {
+                bank = arg
+                    .parse()
+                    .map_err(|_| format!("bad bank number {}", arg))?
+            }
+        }
+    }
+    let path = path.ok_or(USAGE)?;
+
+    let raw = std::fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    let rom = Rom::new(&raw)?;
+    let banks = rom.prg_rom.len() / PRG_ROM_PAGE_SIZE;
+    if bank >= banks {
+        return Err(format!("{} has {} PRG banks", path, banks));
+    }
+
+    let data = &rom.prg_rom[bank * PRG_ROM_PAGE_SIZE..(bank + 1) * PRG_ROM_PAGE_SIZE];
+    let origin = origin.unwrap_or(0x8000 + (bank % 2) as u16 * PRG_ROM_PAGE_SIZE as u16);
+    for instruction in disasm::disassemble(data, origin) {
+        println!(
+            "{:04X}  {:8}  {}",
+            instruction.address,
+            instruction.hex(),
+            instruction
+        );
+    }
+    Ok(())
+}
+
+/// Starts the interactive debugger.
+fn debug_command(args: &[String]) -> Result<(), String> {
+    let (args, symbols_path) = take_option(args, "--symbols")?;
+    let (args, cheats_path) = take_option(&args, "--cheats")?;
+    let path = match args.as_slice() {
+        [path] => path,
+        _ => return Err(USAGE.to_string()),
+    };
+    let mut cpu = CPU::default();
+    let symbols = load_program(&mut cpu, path, symbols_path)?;
+
+    let mut debugger = Debugger::new(symbols);
+    debugger.cheats = load_cheats(path, cheats_path)?;
+    debugger::repl::run(&mut debugger, &mut cpu, io::stdin().lock(), io::stdout())
+        .map_err(|err| err.to_string())
+}
+
+/// Waits for a GDB connection on localhost and serves it.
+fn gdb_command(args: &[String]) -> Result<(), String> {
+    let (args, symbols_path) = take_option(args, "--symbols")?;
+    let (args, cheats_path) = take_option(&args, "--cheats")?;
+    let (path, port) = match args.as_slice() {
+        [path] => (path, GDB_PORT),
+        [path, port] => (
+            path,
+            port.parse().map_err(|_| format!("bad port {}", port))?,
+        ),
+        _ => return Err(USAGE.to_string()),
+    };
+    let mut cpu = CPU::default();
+    let symbols = load_program(&mut cpu, path, symbols_path)?;
+
+    let mut debugger = Debugger::new(symbols);
+    debugger.cheats = load_cheats(path, cheats_path)?;
+    eprintln!("waiting for gdb on localhost:{}", port);
+    GdbStub::new(&mut cpu, debugger)
+        .listen(("127.0.0.1", port))
+        .map_err(|err| err.to_string())
+}
+
+/// Runs the program up to a BRK, or until `--frames` runs out, and prints
+/// where its cycles went. The folded stacks can be written out for
+/// flamegraph tools.
+fn profile_command(args: &[String]) -> Result<(), String> {
+    let (args, symbols_path) = take_option(args, "--symbols")?;
+    let (args, collapsed) = take_option(&args, "--collapsed")?;
+    let (args, frames) = take_option(&args, "--frames")?;
+    let frames: u64 = match frames {
+        Some(frames) => frames
+            .parse()
+            .map_err(|_| format!("bad frame count {}", frames))?,
+        None => PROFILE_FRAMES,
+    };
+    let path = match args.as_slice() {
+        [path] => path,
+        _ => return Err(USAGE.to_string()),
+    };
+    let mut cpu = CPU::default();
+    let symbols = load_program(&mut cpu, path, symbols_path)?;
+
+    let mut profiler = Profiler::new();
+    let result = cpu.run_until(|cpu| {
+        profiler.record(cpu);
+        cpu.at_brk() || frame_of(cpu.cycles) >= frames
+    });
+    if !cpu.at_brk() && result.is_ok() {
+        eprintln!("stopped after {} frames without reaching a BRK", frames);
+    }
+    print!("{}", profiler.flat_report(Some(&symbols), PROFILE_LINES));
+    println!();
+    print!("{}", profiler.tree_report(Some(&symbols)));
+    if let Some(file) = collapsed {
+        std::fs::write(&file, profiler.collapsed_stacks(Some(&symbols)))
+            .map_err(|err| format!("{}: {}", file, err))?;
+    }
+    // The profile up to a fault is still worth printing.
+    result.map_err(|err| format!("CPU fault: {}", err))?;
+    Ok(())
+}
+
+/// Runs without a window until an exit condition and prints where the
+/// machine ended up. Exits with [`FAULT_EXIT_CODE`] if the CPU faulted.
+///
+/// With `--blargg` the program is a test ROM reporting at $6000, and the
+/// run lasts until it reports a result or `--frames` runs out.
+fn run_command(args: &[String]) -> Result<(), String> {
+    let (args, frames) = take_option(args, "--frames")?;
+    let (args, input_path) = take_option(&args, "--input")?;
+    let (args, until_pc) = take_option(&args, "--until-pc")?;
+    let (args, until_mem) = take_option(&args, "--until-mem")?;
+    let (args, until_halt) = take_flag(&args, "--until-halt");
+    let (args, cheats_path) = take_option(&args, "--cheats")?;
+    let (args, screenshot_at) = take_option(&args, "--screenshot-at")?;
+    let (args, screenshot_path) = take_option(&args, "--screenshot")?;
+    let (args, crop) = take_flag(&args, "--crop");
+    let (args, blargg_mode) = take_flag(&args, "--blargg");
+    let path = match args.as_slice() {
+        [path] => path,
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let mut runner = Runner::new();
+    if let Some(frame) = screenshot_at {
+        let frame = frame
+            .parse()
+            .map_err(|_| format!("bad frame number {}", frame))?;
+        runner.screenshot_frames.push(frame);
+    }
+    let frames: Option<u64> = frames
+        .map(|frames| {
+            frames
+                .parse()
+                .map_err(|_| format!("bad frame count {}", frames))
+        })
+        .transpose()?;
+    if let (Some(frames), false) = (frames, blargg_mode) {
+        runner.conditions.push(ExitCondition::Frames(frames));
+    }
+    if let Some(address) = until_pc {
+        runner
+            .conditions
+            .push(ExitCondition::Pc(parse_hex(&address)?));
+    }
+    if let Some(condition) = until_mem {
+        let (address, value) = condition
+            .split_once('=')
+            .ok_or_else(|| format!("expected address=value, got {}", condition))?;
+        let value = parse_hex(value)?;
+        runner.conditions.push(ExitCondition::Memory {
+            address: parse_hex(address)?,
+            value: u8::try_from(value).map_err(|_| format!("bad value {:X}", value))?,
+        });
+    }
+    if until_halt {
+        runner.conditions.push(ExitCondition::Halt);
+    }
+    if let Some(input_path) = input_path {
+        let text = std::fs::read_to_string(&input_path)
+            .map_err(|err| format!("{}: {}", input_path, err))?;
+        runner.input = if input_path.ends_with(".fm2") {
+            let movie = fm2::parse(&text).map_err(|err| format!("{}: {}", input_path, err))?;
+            InputScript::from_movie(&movie)
+        } else {
+            InputScript::parse(&text).map_err(|err| format!("{}: {}", input_path, err))?
+        };
+    }
+    runner.cheats = load_cheats(path, cheats_path)?;
+
+    let mut cpu = CPU::default();
+    load_program(&mut cpu, path, None)?;
+    let result = if blargg_mode {
+        let timeout = frames.unwrap_or(blargg::DEFAULT_TIMEOUT_FRAMES);
+        let result = blargg::run(&mut runner, &mut cpu, timeout);
+        if !result.message.is_empty() {
+            println!("{}", result.message);
+        }
+        println!("result: {}", result.verdict);
+        println!("frames: {}", result.frames);
+        println!("resets: {}", result.resets);
+        match result.verdict {
+            Verdict::Passed => Ok(()),
+            Verdict::Stopped(StopReason::Fault(_)) => Err(None),
+            verdict => Err(Some(format!("test {}", verdict))),
+        }
+    } else {
+        let summary = runner.run(&mut cpu);
+        println!("stop:   {}", summary.reason);
+        println!("frames: {}", summary.frames);
+        println!("cycles: {}", summary.cycles);
+        println!("hash:   {:016x}", summary.hash);
+        match summary.reason {
+            StopReason::Fault(_) => Err(None),
+            _ => Ok(()),
+        }
+    };
+
+    println!(
+        "regs:   A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}",
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status,
+        cpu.stack_pointer,
+        cpu.program_counter
+    );
+    let crop = if crop { Crop::Overscan } else { Crop::None };
+    for (frame, picture) in &runner.screenshots {
+        let file = screenshot_path
+            .clone()
+            .unwrap_or_else(|| format!("{}.{}.png", path, frame));
+        Image::from_frame(picture, &SYSTEM_PALETTE, crop)
+            .save(Path::new(&file))
+            .map_err(|err| format!("{}: {}", file, err))?;
+        println!("screenshot of frame {}: {}", frame, file);
+    }
+    match result {
+        Ok(()) => Ok(()),
+        Err(Some(message)) => Err(message),
+        // A fault gets its own exit status; the reason is already printed.
+        Err(None) => process::exit(FAULT_EXIT_CODE),
+    }
+}
+
+/// Checks every ROM in a directory against its golden state and picture hash,
+/// or writes the goldens with `--bless`.
+fn regress_command(args: &[String]) -> Result<(), String> {
+    let (args, golden) = take_option(args, "--golden")?;
+    let (args, frames) = take_option(&args, "--frames")?;
+    let (args, junit) = take_option(&args, "--junit")?;
+    let (args, crop) = take_flag(&args, "--crop");
+    let (args, bless) = take_flag(&args, "--bless");
+    let dir = match args.as_slice() {
+        [dir] => dir,
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let mut suite = Suite::new(dir);
+    if let Some(golden) = golden {
+        suite.golden = golden.into();
+    }
+    if let Some(frames) = frames {
+        suite.frames = frames
+            .parse()
+            .map_err(|_| format!("bad frame count {}", frames))?;
+    }
+    if crop {
+        suite.crop = Crop::Overscan;
+    }
+    let report = suite
+        .run(bless)
+        .map_err(|err| format!("{}: {}", dir, err))?;
+    print!("{}", report.summary());
+    if let Some(junit) = junit {
+        std::fs::write(&junit, report.junit_xml()).map_err(|err| format!("{}: {}", junit, err))?;
+    }
+    if !report.is_success() {
+        return Err(format!(
+            "{} of {} failed",
+            report.failures(),
+            report.results.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Parses a hex number, with or without a `$` or `0x` prefix.
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("bad hex number {}", text))
+}
+
+/// Removes `flag` and the value after it from `args`.
+fn take_option(args: &[String], flag: &str) -> Result<(Vec<String>, Option<String>), String> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or(USAGE)?.clone();
+            let rest = [&args[..index], &args[index + 2..]].concat();
+            Ok((rest, Some(value)))
+        }
+        None => Ok((args.to_vec(), None)),
+    }
+}
+
+/// Removes `flag` from `args` and tells whether it was there.
+fn take_flag(args: &[String], flag: &str) -> (Vec<String>, bool) {
+    let rest: Vec<String> = args.iter().filter(|arg| *arg != flag).cloned().collect();
+    let found = rest.len() != args.len();
+    (rest, found)
+}
+
+/// Loads a ROM, or assembles source so its labels can be used, and resets
+/// the CPU. Labels from an ld65 `.dbg` or NESASM `.fns` file are added on
+/// top.
+fn load_program(
+    cpu: &mut CPU,
+    path: &str,
+    symbols_path: Option<String>,
+) -> Result<SymbolTable, String> {
+    let mut prg_len = 0;
+    let mut symbols = if path.ends_with(".nes") {
+        let raw = std::fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        let rom = Rom::new(&raw)?;
+        prg_len = rom.prg_rom.len();
+        cpu.load_rom(&rom);
+        SymbolTable::new()
+    } else {
+        let source = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        let program = asm::assemble_program(&source).map_err(|err| format!("{}: {}", path, err))?;
+        for (offset, byte) in program.bytes.iter().enumerate() {
+            cpu.mem_write(program.origin.wrapping_add(offset as u16), *byte);
+        }
+        if program.origin as usize + program.bytes.len() <= 0xfffc {
+            cpu.mem_write_u16(0xfffc, program.origin);
+        }
+        program.symbols
+    };
+
+    if let Some(symbols_path) = symbols_path {
+        let text = std::fs::read_to_string(&symbols_path)
+            .map_err(|err| format!("{}: {}", symbols_path, err))?;
+        let imported = if symbols_path.ends_with(".fns") {
+            nesasm::parse(&text)
+        } else {
+            ld65::DebugInfo::parse(&text)
+                .map(|info| info.symbol_table(|addr| cdl::nrom_prg_offset(prg_len, addr)))
+        };
+        symbols.extend(imported.map_err(|err| format!("{}: {}", symbols_path, err))?);
+    }
+    cpu.reset();
+    Ok(symbols)
+}
+
+/// Reads the cheat file given, or the `.cht` file next to the program if
+/// there is one.
+fn load_cheats(path: &str, cheats_path: Option<String>) -> Result<CheatList, String> {
+    let cheats_path = match cheats_path {
+        Some(cheats_path) => cheats_path,
+        None => {
+            let default = Path::new(path).with_extension("cht");
+            if !default.exists() {
+                return Ok(CheatList::new());
+            }
+            default.to_string_lossy().into_owned()
+        }
+    };
+    let text =
+        std::fs::read_to_string(&cheats_path).map_err(|err| format!("{}: {}", cheats_path, err))?;
+    CheatList::parse(&text).map_err(|err| format!("{}: {}", cheats_path, err))
 }
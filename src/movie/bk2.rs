@@ -0,0 +1,526 @@
+//! BizHawk BK2 movies.
+//!
+//! A BK2 file is a zip archive holding `Header.txt` (`key value` lines),
+//! `Input Log.txt` and optional `Comments.txt`. The input log starts with a
+//! `LogKey` line naming every button column, followed by one line per frame
+//! where each button is a mnemonic character or `.` when released.
+//!
+//! The MD5 ROM checksum and the per-frame state hashes taken while
+//! recording go into the header as `MD5 <hex>` and `FrameHash <frame>
+//! <hash>` lines, which BizHawk keeps like any other unknown key.
+
+use super::{FrameHashes, FrameInput, Movie, PortDevice, COMMAND_POWER, COMMAND_RESET};
+use crate::input::{
+    BUTTON_A, BUTTON_B, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT, BUTTON_SELECT, BUTTON_START,
+    BUTTON_UP,
+};
+use std::fmt;
+use std::io::{Cursor, Read, Write};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+const HEADER_FILE: &str = "Header.txt";
+const INPUT_LOG_FILE: &str = "Input Log.txt";
+const COMMENTS_FILE: &str = "Comments.txt";
+
+const MOVIE_VERSION: &str = "BizHawk v2.0.0";
+const CORE: &str = "NesHawk";
+/// Core that polls input more than once per frame.
+const SUBFRAME_CORE: &str = "SubNESHawk";
+
+/// NES controller buttons in BizHawk's log order, with their mnemonics.
+const PAD_BUTTONS: [(&str, char, u8); 8] = [
+    ("Up", 'U', BUTTON_UP),
+    ("Down", 'D', BUTTON_DOWN),
+    ("Left", 'L', BUTTON_LEFT),
+    ("Right", 'R', BUTTON_RIGHT),
+    ("Start", 'S', BUTTON_START),
+    ("Select", 's', BUTTON_SELECT),
+    ("B", 'B', BUTTON_B),
+    ("A", 'A', BUTTON_A),
+];
+
+/// Console buttons in BizHawk's log order, with their mnemonics.
+const CONSOLE_BUTTONS: [(&str, char, u8); 2] =
+    [("Reset", 'r', COMMAND_RESET), ("Power", 'P', COMMAND_POWER)];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Bk2Error {
+    /// The zip container could not be read or written.
+    Archive(String),
+    MissingFile(&'static str),
+    /// Malformed text inside the archive; `line` is 1-based.
+    Parse {
+        file: &'static str,
+        line: usize,
+        message: String,
+    },
+    UnsupportedPlatform(String),
+    /// A log column for something other than a standard controller, such
+    /// as a Zapper or Arkanoid paddle.
+    UnsupportedController(String),
+    /// The movie was recorded with a core that polls input within a frame.
+    SubframeInput,
+    /// The movie starts from a save state. BizHawk and this emulator cannot
+    /// load each other's.
+    StartsFromSavestate,
+}
+
+impl fmt::Display for Bk2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bk2Error::Archive(message) => write!(f, "invalid BK2 archive: {}", message),
+            Bk2Error::MissingFile(file) => write!(f, "BK2 archive has no {}", file),
+            Bk2Error::Parse {
+                file,
+                line,
+                message,
+            } => write!(f, "{} line {}: {}", file, line, message),
+            Bk2Error::UnsupportedPlatform(platform) => {
+                write!(f, "unsupported platform {}", platform)
+            }
+            Bk2Error::UnsupportedController(key) => {
+                write!(f, "unsupported controller input {:?}", key)
+            }
+            Bk2Error::SubframeInput => write!(f, "subframe input is not supported"),
+            Bk2Error::StartsFromSavestate => {
+                write!(f, "BK2 movies starting from a savestate are not supported")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Bk2Error {}
+
+impl From<zip::result::ZipError> for Bk2Error {
+    fn from(err: zip::result::ZipError) -> Self {
+        Bk2Error::Archive(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for Bk2Error {
+    fn from(err: std::io::Error) -> Self {
+        Bk2Error::Archive(err.to_string())
+    }
+}
+
+/// Where one column of the input log goes.
+#[derive(Clone, Copy)]
+enum Column {
+    Command(u8),
+    Pad(usize, u8),
+}
+
+pub fn import(data: &[u8]) -> Result<Movie, Bk2Error> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    let header = read_file(&mut archive, HEADER_FILE)?.ok_or(Bk2Error::MissingFile(HEADER_FILE))?;
+    let input_log =
+        read_file(&mut archive, INPUT_LOG_FILE)?.ok_or(Bk2Error::MissingFile(INPUT_LOG_FILE))?;
+    let comments = read_file(&mut archive, COMMENTS_FILE)?.unwrap_or_default();
+
+    let mut movie = Movie {
+        ports: [PortDevice::None; 2],
+        comments: comments
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_string)
+            .collect(),
+        ..Movie::default()
+    };
+    let mut frame_hashes = FrameHashes::default();
+
+    for (index, line) in header.lines().enumerate() {
+        let error = |message: &str| Bk2Error::Parse {
+            file: HEADER_FILE,
+            line: index + 1,
+            message: message.to_string(),
+        };
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        let value = value.trim();
+        match key {
+            "Platform" if value != "NES" => {
+                return Err(Bk2Error::UnsupportedPlatform(value.to_string()))
+            }
+            "Core" if value == SUBFRAME_CORE => return Err(Bk2Error::SubframeInput),
+            "StartsFromSavestate" if value.eq_ignore_ascii_case("true") => {
+                return Err(Bk2Error::StartsFromSavestate)
+            }
+            "GameName" => movie.rom_filename = value.to_string(),
+            "SHA1" => {
+                let hex = value.strip_prefix("SHA1:").unwrap_or(value);
+                movie.rom_sha1 = Some(parse_hex(hex).ok_or_else(|| error("invalid SHA1"))?);
+            }
+            "MD5" => movie.rom_md5 = Some(parse_hex(value).ok_or_else(|| error("invalid MD5"))?),
+            "FrameHash" => frame_hashes
+                .parse(index + 1, value)
+                .map_err(|message| error(&message))?,
+            "rerecordCount" => {
+                movie.rerecord_count = value.parse().map_err(|_| error("invalid rerecordCount"))?
+            }
+            "PAL" => movie.pal = value.eq_ignore_ascii_case("true"),
+            _ => {}
+        }
+    }
+
+    let mut columns = None;
+    for (index, line) in input_log.lines().enumerate() {
+        let error = |message: &str| Bk2Error::Parse {
+            file: INPUT_LOG_FILE,
+            line: index + 1,
+            message: message.to_string(),
+        };
+        let line = line.trim_end_matches('\r');
+
+        if let Some(keys) = line.strip_prefix("LogKey:") {
+            let parsed = parse_log_key(keys)?;
+            movie.four_score = parsed
+                .iter()
+                .any(|column| matches!(column, Column::Pad(pad, _) if *pad >= 2));
+            for column in &parsed {
+                if let Column::Pad(pad @ 0..=1, _) = column {
+                    movie.ports[*pad] = PortDevice::Gamepad;
+                }
+            }
+            columns = Some(parsed);
+        } else if line.starts_with('|') {
+            let columns = columns
+                .as_ref()
+                .ok_or_else(|| error("input before LogKey"))?;
+            let input = parse_frame(line, columns).map_err(|message| error(&message))?;
+            movie.frames.push(input);
+        }
+    }
+    frame_hashes
+        .apply(&mut movie)
+        .map_err(|(line, message)| Bk2Error::Parse {
+            file: HEADER_FILE,
+            line,
+            message,
+        })?;
+    Ok(movie)
+}
+
+/// Writes `movie` as a BK2 archive. A movie that starts from a save state
+/// cannot be written, since BizHawk would not be able to load the state.
+pub fn export(movie: &Movie) -> Result<Vec<u8>, Bk2Error> {
+    if movie.savestate.is_some() {
+        return Err(Bk2Error::StartsFromSavestate);
+    }
+
+    let mut header = String::new();
+    header.push_str(&format!("MovieVersion {}\n", MOVIE_VERSION));
+    header.push_str(&format!(
+        "emuVersion {} {}\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    ));
+    header.push_str("Platform NES\n");
+    header.push_str(&format!("GameName {}\n", movie.rom_filename));
+    if let Some(sha1) = movie.rom_sha1 {
+        let hex: String = sha1.iter().map(|byte| format!("{:02X}", byte)).collect();
+        header.push_str(&format!("SHA1 {}\n", hex));
+    }
+    if let Some(md5) = movie.rom_md5 {
+        let hex: String = md5.iter().map(|byte| format!("{:02X}", byte)).collect();
+        header.push_str(&format!("MD5 {}\n", hex));
+    }
+    header.push_str(&format!("Core {}\n", CORE));
+    header.push_str(&format!("rerecordCount {}\n", movie.rerecord_count));
+    if movie.pal {
+        header.push_str("PAL True\n");
+    }
+    for (frame, hash) in movie.frame_hashes.iter().enumerate() {
+        if let Some(hash) = hash {
+            header.push_str(&format!("FrameHash {} {:016x}\n", frame, hash));
+        }
+    }
+
+    let pads: Vec<usize> = if movie.four_score {
+        (0..4).collect()
+    } else {
+        (0..2)
+            .filter(|pad| movie.ports[*pad] == PortDevice::Gamepad)
+            .collect()
+    };
+    let mut input_log = String::from("[Input]\nLogKey:#");
+    for (name, _, _) in CONSOLE_BUTTONS {
+        input_log.push_str(&format!("{}|", name));
+    }
+    for &pad in &pads {
+        input_log.push('#');
+        for (name, _, _) in PAD_BUTTONS {
+            input_log.push_str(&format!("P{} {}|", pad + 1, name));
+        }
+    }
+    input_log.push('\n');
+    for input in &movie.frames {
+        input_log.push('|');
+        for (_, mnemonic, command) in CONSOLE_BUTTONS {
+            input_log.push(if input.commands & command != 0 {
+                mnemonic
+            } else {
+                '.'
+            });
+        }
+        for pad in pads.iter().map(|pad| input.pads[*pad]) {
+            input_log.push('|');
+            for (_, mnemonic, button) in PAD_BUTTONS {
+                input_log.push(if pad & button != 0 { mnemonic } else { '.' });
+            }
+        }
+        input_log.push_str("|\n");
+    }
+    input_log.push_str("[/Input]\n");
+
+    let mut comments = String::new();
+    for comment in &movie.comments {
+        comments.push_str(comment);
+        comments.push('\n');
+    }
+
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, contents) in [
+        (HEADER_FILE, header),
+        (INPUT_LOG_FILE, input_log),
+        (COMMENTS_FILE, comments),
+    ] {
+        writer.start_file(name, options)?;
+        writer.write_all(contents.as_bytes())?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+fn read_file(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &'static str,
+) -> Result<Option<String>, Bk2Error> {
+    let mut file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(Some(contents))
+}
+
+/// Parses a digest of exactly `N` bytes written as hex.
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 {
+        return None;
+    }
+    let mut digest = [0; N];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+fn parse_log_key(keys: &str) -> Result<Vec<Column>, Bk2Error> {
+    keys.split('|')
+        .map(|key| key.trim_start_matches('#'))
+        .filter(|key| !key.is_empty())
+        .map(|key| {
+            if key.contains("Cycle") {
+                return Err(Bk2Error::SubframeInput);
+            }
+            if let Some((_, _, command)) = CONSOLE_BUTTONS.iter().find(|(name, _, _)| *name == key)
+            {
+                return Ok(Column::Command(*command));
+            }
+            key.strip_prefix('P')
+                .and_then(|rest| rest.split_once(' '))
+                .and_then(|(player, button)| {
+                    let pad = player.parse::<usize>().ok()?.checked_sub(1)?;
+                    let (_, _, bit) = PAD_BUTTONS.iter().find(|(name, _, _)| *name == button)?;
+                    (pad < 4).then_some(Column::Pad(pad, *bit))
+                })
+                .ok_or_else(|| Bk2Error::UnsupportedController(key.to_string()))
+        })
+        .collect()
+}
+
+fn parse_frame(line: &str, columns: &[Column]) -> Result<FrameInput, String> {
+    let states: Vec<char> = line.chars().filter(|c| *c != '|').collect();
+    if states.len() != columns.len() {
+        return Err(format!(
+            "expected {} inputs, found {}",
+            columns.len(),
+            states.len()
+        ));
+    }
+
+    let mut input = FrameInput::default();
+    for (state, column) in states.iter().zip(columns) {
+        if *state == '.' || *state == ' ' {
+            continue;
+        }
+        match column {
+            Column::Command(command) => input.commands |= command,
+            Column::Pad(pad, button) => input.pads[*pad] |= button,
+        }
+    }
+    Ok(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "MovieVersion BizHawk v2.0.0
+Author someone
+emuVersion Version 2.9.1
+Platform NES
+GameName Super Mario Bros.
+SHA1 EA343F4E445A9050D4B4FBAC2C77D0693B1D0922
+Core NesHawk
+rerecordCount 77
+";
+
+    const INPUT_LOG: &str = "[Input]
+LogKey:#Reset|Power|#P1 Up|P1 Down|P1 Left|P1 Right|P1 Start|P1 Select|P1 B|P1 A|#P2 Up|P2 Down|P2 Left|P2 Right|P2 Start|P2 Select|P2 B|P2 A|
+|.P|........|........|
+|..|...RS...|.......A|
+|r.|U.....BA|........|
+[/Input]
+";
+
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_import() {
+        let movie = import(&archive(&[
+            (HEADER_FILE, HEADER),
+            (INPUT_LOG_FILE, INPUT_LOG),
+        ]))
+        .unwrap();
+
+        assert_eq!(movie.rom_filename, "Super Mario Bros.");
+        assert_eq!(movie.rerecord_count, 77);
+        assert_eq!(movie.rom_sha1.unwrap()[0], 0xea);
+        assert_eq!(movie.rom_md5, None);
+        assert!(!movie.four_score);
+        assert_eq!(movie.frames.len(), 3);
+        assert_eq!(movie.frames[0].commands, COMMAND_POWER);
+        assert_eq!(movie.frames[1].pads[0], BUTTON_RIGHT | BUTTON_START);
+        assert_eq!(movie.frames[1].pads[1], BUTTON_A);
+        assert_eq!(movie.frames[2].commands, COMMAND_RESET);
+        assert_eq!(movie.frames[2].pads[0], BUTTON_UP | BUTTON_B | BUTTON_A);
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let mut movie = import(&archive(&[
+            (HEADER_FILE, HEADER),
+            (INPUT_LOG_FILE, INPUT_LOG),
+        ]))
+        .unwrap();
+        movie.four_score = true;
+        movie.frames[1].pads[3] = BUTTON_SELECT;
+        movie.comments = vec!["first".to_string(), "second".to_string()];
+        movie.pal = true;
+        movie.rom_md5 = Some([0x5a; 16]);
+        movie.frame_hashes = vec![Some(0x0123_4567_89ab_cdef), None, Some(42)];
+
+        assert_eq!(import(&export(&movie).unwrap()).unwrap(), movie);
+
+        movie.four_score = false;
+        movie.ports[1] = PortDevice::None;
+        movie
+            .frames
+            .iter_mut()
+            .for_each(|input| input.pads[1..].fill(0));
+        assert_eq!(import(&export(&movie).unwrap()).unwrap(), movie);
+
+        movie.savestate = Some(vec![1, 2, 3]);
+        assert_eq!(export(&movie), Err(Bk2Error::StartsFromSavestate));
+    }
+
+    #[test]
+    fn test_frame_hashes_past_the_input() {
+        for frame in ["3", "1000000000000", &usize::MAX.to_string()] {
+            let header = format!("{}FrameHash {} 00\n", HEADER, frame);
+            assert_eq!(
+                import(&archive(&[
+                    (HEADER_FILE, &header),
+                    (INPUT_LOG_FILE, INPUT_LOG)
+                ])),
+                Err(Bk2Error::Parse {
+                    file: HEADER_FILE,
+                    line: 9,
+                    message: format!("frame hash for frame {} past the end of the input", frame)
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_unsupported_features() {
+        let zapper_log = INPUT_LOG.replace(
+            "#P2 Up|P2 Down|P2 Left|P2 Right|P2 Start|P2 Select|P2 B|P2 A|",
+            "#P2 Zapper X|P2 Zapper Y|P2 Fire|",
+        );
+        assert_eq!(
+            import(&archive(&[
+                (HEADER_FILE, HEADER),
+                (INPUT_LOG_FILE, &zapper_log)
+            ])),
+            Err(Bk2Error::UnsupportedController("P2 Zapper X".to_string()))
+        );
+
+        let subframe = HEADER.replace("Core NesHawk", "Core SubNESHawk");
+        assert_eq!(
+            import(&archive(&[
+                (HEADER_FILE, &subframe),
+                (INPUT_LOG_FILE, INPUT_LOG)
+            ])),
+            Err(Bk2Error::SubframeInput)
+        );
+
+        let snes = HEADER.replace("Platform NES", "Platform SNES");
+        assert_eq!(
+            import(&archive(&[
+                (HEADER_FILE, &snes),
+                (INPUT_LOG_FILE, INPUT_LOG)
+            ])),
+            Err(Bk2Error::UnsupportedPlatform("SNES".to_string()))
+        );
+
+        let savestate = format!("{}StartsFromSavestate True\n", HEADER);
+        assert_eq!(
+            import(&archive(&[
+                (HEADER_FILE, &savestate),
+                (INPUT_LOG_FILE, INPUT_LOG)
+            ])),
+            Err(Bk2Error::StartsFromSavestate)
+        );
+    }
+
+    #[test]
+    fn test_malformed_archives() {
+        assert!(matches!(import(b"not a zip"), Err(Bk2Error::Archive(_))));
+        assert_eq!(
+            import(&archive(&[(HEADER_FILE, HEADER)])),
+            Err(Bk2Error::MissingFile(INPUT_LOG_FILE))
+        );
+
+        let short_line = INPUT_LOG.replace("|..|...RS...|", "|..|...RS|");
+        assert!(matches!(
+            import(&archive(&[
+                (HEADER_FILE, HEADER),
+                (INPUT_LOG_FILE, &short_line)
+            ])),
+            Err(Bk2Error::Parse { line: 4, .. })
+        ));
+    }
+}
@@ -0,0 +1,392 @@
+//! FCEUX FM2 text movies.
+//!
+//! The header is a list of `key value` lines followed by one `|`-separated
+//! input line per frame. Besides the FCEUX fields, the per-frame state
+//! hashes taken while recording are stored as `frameHash <frame> <hash>`
+//! lines, which FCEUX ignores like any other unknown key.
+
+use super::{FrameHashes, FrameInput, Movie, MovieError, PortDevice};
+
+const FM2_VERSION: u32 = 3;
+/// FCEUX 2.2.2, whose FM2 layout this writer follows.
+const EMU_VERSION: u32 = 22020;
+
+const PORT_NONE: u8 = 0;
+const PORT_GAMEPAD: u8 = 1;
+const PORT_ZAPPER: u8 = 2;
+
+/// Button characters in the order they appear in an input field, from
+/// bit 7 (Right) down to bit 0 (A).
+const GAMEPAD_MNEMONICS: &[u8; 8] = b"RLDUTSBA";
+
+pub fn parse(text: &str) -> Result<Movie, MovieError> {
+    let mut movie = Movie::default();
+    let mut frame_hashes = FrameHashes::default();
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |message: &str| MovieError::Parse {
+            line: line_number,
+            message: message.to_string(),
+        };
+        let line = line.trim_end_matches('\r');
+
+        if line.starts_with('|') {
+            let input =
+                parse_input(line, movie.four_score, &movie.ports).map_err(|msg| error(&msg))?;
+            movie.frames.push(input);
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        let flag = || match value.trim() {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(error(&format!("{} must be 0 or 1", key))),
+        };
+        match key {
+            "version" if value.trim() != FM2_VERSION.to_string() => {
+                return Err(MovieError::Unsupported(format!("FM2 version {}", value)));
+            }
+            "rerecordCount" => {
+                movie.rerecord_count = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| error("invalid rerecordCount"))?
+            }
+            "palFlag" => movie.pal = flag()?,
+            "romFilename" => movie.rom_filename = value.to_string(),
+            "romChecksum" => {
+                let checksum =
+                    decode_base64_field(value).ok_or_else(|| error("invalid romChecksum"))?;
+                if checksum.len() != 16 {
+                    return Err(error("romChecksum must be an MD5 digest"));
+                }
+                let mut md5 = [0; 16];
+                md5.copy_from_slice(&checksum);
+                movie.rom_md5 = Some(md5);
+            }
+            "guid" => movie.guid = value.to_string(),
+            "fourscore" => movie.four_score = flag()?,
+            "port0" | "port1" => {
+                let port = value.trim().parse().map_err(|_| error("invalid port"))?;
+                match port {
+                    PORT_NONE | PORT_GAMEPAD => {}
+                    PORT_ZAPPER => return Err(MovieError::Unsupported("Zapper input".to_string())),
+                    _ => return Err(error("unknown port device")),
+                }
+                movie.ports[(key == "port1") as usize] = match port {
+                    PORT_NONE => PortDevice::None,
+                    _ => PortDevice::Gamepad,
+                };
+            }
+            "port2" if value.trim() != "0" => {
+                return Err(MovieError::Unsupported("expansion port device".to_string()));
+            }
+            "binary" if value.trim() != "0" => {
+                return Err(MovieError::Unsupported("binary input log".to_string()));
+            }
+            "FDS" if value.trim() != "0" => {
+                return Err(MovieError::Unsupported("Famicom Disk System".to_string()));
+            }
+            "comment" => movie.comments.push(value.to_string()),
+            "savestate" => {
+                movie.savestate =
+                    Some(decode_base64_field(value).ok_or_else(|| error("invalid savestate"))?)
+            }
+            "frameHash" => frame_hashes
+                .parse(line_number, value)
+                .map_err(|message| error(&message))?,
+            // emuVersion, microphone, NewPPU, length, subtitle and anything
+            // newer do not affect playback.
+            _ => {}
+        }
+    }
+
+    frame_hashes
+        .apply(&mut movie)
+        .map_err(|(line, message)| MovieError::Parse { line, message })?;
+
+    if movie.rom_md5.is_none() {
+        return Err(MovieError::MissingField("romChecksum"));
+    }
+    Ok(movie)
+}
+
+/// Writes `movie` as FM2 text; FM2 requires the MD5 ROM checksum.
+pub fn write(movie: &Movie) -> Result<String, MovieError> {
+    let rom_md5 = movie
+        .rom_md5
+        .ok_or(MovieError::MissingField("romChecksum"))?;
+
+    let mut out = String::new();
+    out.push_str(&format!("version {}\n", FM2_VERSION));
+    out.push_str(&format!("emuVersion {}\n", EMU_VERSION));
+    out.push_str(&format!("rerecordCount {}\n", movie.rerecord_count));
+    out.push_str(&format!("palFlag {}\n", movie.pal as u8));
+    out.push_str(&format!("romFilename {}\n", movie.rom_filename));
+    out.push_str(&format!("romChecksum base64:{}\n", encode_base64(&rom_md5)));
+    out.push_str(&format!("guid {}\n", movie.guid));
+    out.push_str(&format!("fourscore {}\n", movie.four_score as u8));
+    out.push_str("microphone 0\n");
+    for (index, device) in movie.ports.iter().enumerate() {
+        let port = match device {
+            PortDevice::None => PORT_NONE,
+            PortDevice::Gamepad => PORT_GAMEPAD,
+        };
+        out.push_str(&format!("port{} {}\n", index, port));
+    }
+    out.push_str(&format!("port2 {}\n", PORT_NONE));
+    out.push_str("FDS 0\n");
+    out.push_str("NewPPU 0\n");
+    for comment in &movie.comments {
+        out.push_str(&format!("comment {}\n", comment));
+    }
+    if let Some(state) = &movie.savestate {
+        out.push_str(&format!("savestate base64:{}\n", encode_base64(state)));
+    }
+    for (frame, hash) in movie.frame_hashes.iter().enumerate() {
+        if let Some(hash) = hash {
+            out.push_str(&format!("frameHash {} {:016x}\n", frame, hash));
+        }
+    }
+
+    let pad_count = if movie.four_score { 4 } else { 2 };
+    for input in &movie.frames {
+        out.push_str(&format!("|{}|", input.commands));
+        for (index, pad) in input.pads[..pad_count].iter().enumerate() {
+            if movie.four_score || movie.ports[index] == PortDevice::Gamepad {
+                out.push_str(&write_gamepad(*pad));
+            }
+            out.push('|');
+        }
+        out.push_str("|\n");
+    }
+    Ok(out)
+}
+
+fn parse_input(
+    line: &str,
+    four_score: bool,
+    ports: &[PortDevice; 2],
+) -> Result<FrameInput, String> {
+    let fields: Vec<&str> = line.split('|').collect();
+    let pad_fields = if four_score { 4 } else { 2 };
+    // Leading empty field, commands, pads, expansion port, trailing empty field.
+    if fields.len() < pad_fields + 3 {
+        return Err("input line has too few fields".to_string());
+    }
+
+    let mut input = FrameInput {
+        commands: fields[1]
+            .trim()
+            .parse()
+            .map_err(|_| "invalid commands field".to_string())?,
+        ..Default::default()
+    };
+    for pad in 0..pad_fields {
+        let field = fields[2 + pad];
+        if !four_score && ports[pad] == PortDevice::None {
+            continue;
+        }
+        input.pads[pad] = parse_gamepad(field)?;
+    }
+    Ok(input)
+}
+
+fn parse_gamepad(field: &str) -> Result<u8, String> {
+    if field.len() != GAMEPAD_MNEMONICS.len() {
+        return Err(format!("gamepad field {:?} is not 8 characters", field));
+    }
+    Ok(field
+        .bytes()
+        .enumerate()
+        .filter(|(_, c)| *c != b'.' && *c != b' ')
+        .fold(0, |buttons, (index, _)| buttons | (0b1000_0000 >> index)))
+}
+
+fn write_gamepad(buttons: u8) -> String {
+    GAMEPAD_MNEMONICS
+        .iter()
+        .enumerate()
+        .map(|(index, mnemonic)| {
+            if buttons & (0b1000_0000 >> index) != 0 {
+                *mnemonic as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let value = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(value >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes a `base64:`-prefixed field; FCEUX also accepts `0x` hex.
+fn decode_base64_field(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    if let Some(hex) = value.strip_prefix("0x") {
+        return (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect();
+    }
+    let encoded = value.strip_prefix("base64:")?.trim_end_matches('=');
+    let mut out = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded.bytes() {
+        let digit = BASE64_ALPHABET.iter().position(|a| *a == c)? as u32;
+        buffer = buffer << 6 | digit;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{
+        BUTTON_A, BUTTON_B, BUTTON_LEFT, BUTTON_RIGHT, BUTTON_SELECT, BUTTON_START, BUTTON_UP,
+    };
+
+    const SAMPLE: &str = "version 3
+emuVersion 22020
+rerecordCount 42
+palFlag 0
+romFilename smb
+romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==
+guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B
+fourscore 0
+microphone 0
+port0 1
+port1 1
+port2 0
+FDS 0
+NewPPU 0
+comment author someone
+|1|........|........||
+|0|R......A|........||
+|0|...U.S..|.L....B.||
+";
+
+    #[test]
+    fn test_parse_sample() {
+        let movie = parse(SAMPLE).unwrap();
+        assert_eq!(movie.rerecord_count, 42);
+        assert_eq!(movie.rom_filename, "smb");
+        assert_eq!(movie.guid, "452DE2C3-EF43-2FA9-77AC-0677FC51543B");
+        assert_eq!(movie.comments, vec!["author someone"]);
+        assert_eq!(movie.rom_md5.unwrap()[0], 0x8e);
+        assert_eq!(movie.rom_md5.unwrap()[15], 0xdd);
+        assert_eq!(movie.frames.len(), 3);
+        assert_eq!(movie.frames[0].commands, 1);
+        assert_eq!(movie.frames[1].pads[0], BUTTON_RIGHT | BUTTON_A);
+        assert_eq!(movie.frames[2].pads[0], BUTTON_UP | BUTTON_SELECT);
+        assert_eq!(movie.frames[2].pads[1], BUTTON_LEFT | BUTTON_B);
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let mut movie = parse(SAMPLE).unwrap();
+        movie.savestate = Some(vec![1, 2, 3, 4, 5]);
+        movie.frame_hashes = vec![Some(0x0123_4567_89ab_cdef), None, Some(7)];
+
+        let text = write(&movie).unwrap();
+        assert!(text.contains("|0|R......A|........||\n"));
+        assert_eq!(parse(&text).unwrap(), movie);
+    }
+
+    #[test]
+    fn test_port_devices_round_trip() {
+        let movie = parse(&SAMPLE.replace("port1 1", "port1 0")).unwrap();
+        assert_eq!(movie.ports, [PortDevice::Gamepad, PortDevice::None]);
+        assert_eq!(movie.frames[2].pads[1], 0);
+
+        let text = write(&movie).unwrap();
+        assert!(text.contains("port0 1\nport1 0\n"));
+        assert!(text.contains("|0|R......A|||\n"));
+        assert_eq!(parse(&text).unwrap(), movie);
+    }
+
+    #[test]
+    fn test_four_score_round_trip() {
+        let mut movie = parse(SAMPLE).unwrap();
+        movie.four_score = true;
+        movie.frames = vec![FrameInput {
+            commands: 0,
+            pads: [BUTTON_A, BUTTON_B, BUTTON_UP, BUTTON_START],
+        }];
+
+        let text = write(&movie).unwrap();
+        assert!(text.contains("|0|.......A|......B.|...U....|....T...||\n"));
+        assert_eq!(parse(&text).unwrap(), movie);
+    }
+
+    #[test]
+    fn test_base64() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            let encoded = format!("base64:{}", encode_base64(data));
+            assert_eq!(decode_base64_field(&encoded).unwrap(), data);
+        }
+        assert_eq!(encode_base64(b"foob"), "Zm9vYg==");
+        assert_eq!(decode_base64_field("0x0aff").unwrap(), vec![0x0a, 0xff]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let zapper = SAMPLE.replace("port1 1", "port1 2");
+        assert!(matches!(parse(&zapper), Err(MovieError::Unsupported(_))));
+
+        let bad_line = SAMPLE.replace("|0|R......A|", "|0|R..A|");
+        assert!(matches!(
+            parse(&bad_line),
+            Err(MovieError::Parse { line: 17, .. })
+        ));
+
+        for frame in ["3", "1000000000000", &usize::MAX.to_string()] {
+            let hash = format!("{}frameHash {} 00\n", SAMPLE, frame);
+            assert_eq!(
+                parse(&hash),
+                Err(MovieError::Parse {
+                    line: 19,
+                    message: format!("frame hash for frame {} past the end of the input", frame)
+                })
+            );
+        }
+
+        let no_checksum = SAMPLE.replace("romChecksum", "romSum");
+        assert_eq!(
+            parse(&no_checksum),
+            Err(MovieError::MissingField("romChecksum"))
+        );
+    }
+}
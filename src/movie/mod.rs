@@ -0,0 +1,492 @@
+pub mod bk2;
+pub mod fm2;
+
+use crate::cartridge::Rom;
+use crate::cpu::{CPU, RESET_CYCLES};
+use crate::input::{InputDevice, InputPorts};
+use crate::savestate::StateError;
+use std::fmt;
+
+/// # Frame commands, as numbered by FM2
+///
+///  4 3 2 1 0
+///  V S I P R
+///  | | | | +--- Soft reset
+///  | | | +----- Power cycle
+///  | | +------- FDS disk insert
+///  | +--------- FDS disk side select
+///  +----------- VS coin insert
+///
+pub const COMMAND_RESET: u8 = 0b0000_0001;
+pub const COMMAND_POWER: u8 = 0b0000_0010;
+pub const COMMAND_FDS_INSERT: u8 = 0b0000_0100;
+pub const COMMAND_FDS_SELECT: u8 = 0b0000_1000;
+pub const COMMAND_VS_INSERT_COIN: u8 = 0b0001_0000;
+
+/// Input for a single frame: console commands and up to four standard
+/// controllers, using the button bits from [`crate::input`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInput {
+    pub commands: u8,
+    pub pads: [u8; 4],
+}
+
+impl FrameInput {
+    /// Carries out the console commands of the frame on `cpu`: a soft reset
+    /// or a power cycle. Disk and coin commands have nothing to act on.
+    pub fn apply_commands(&self, cpu: &mut CPU) {
+        if self.commands & COMMAND_POWER != 0 {
+            cpu.power_cycle();
+        } else if self.commands & COMMAND_RESET != 0 {
+            cpu.soft_reset();
+        }
+    }
+
+    /// Presses the recorded buttons on the controllers plugged into `ports`.
+    pub fn apply(&self, ports: &mut InputPorts) {
+        for (index, device) in [&mut ports.port1, &mut ports.port2].into_iter().enumerate() {
+            match device {
+                InputDevice::Joypad(joypad) => joypad.button_status = self.pads[index],
+                InputDevice::FourScore(four_score) => {
+                    four_score.button_status = [self.pads[index], self.pads[index + 2]]
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Device a movie was recorded with in one of the controller ports.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PortDevice {
+    None,
+    #[default]
+    Gamepad,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MovieError {
+    /// Malformed movie text; `line` is 1-based.
+    Parse {
+        line: usize,
+        message: String,
+    },
+    MissingField(&'static str),
+    /// A feature the movie uses that this emulator cannot play back.
+    Unsupported(String),
+    RomChecksumMismatch {
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            MovieError::MissingField(field) => write!(f, "missing header field {}", field),
+            MovieError::Unsupported(feature) => write!(f, "unsupported movie feature: {}", feature),
+            MovieError::RomChecksumMismatch { .. } => {
+                write!(f, "movie was recorded with a different ROM")
+            }
+            MovieError::State(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<StateError> for MovieError {
+    fn from(err: StateError) -> Self {
+        MovieError::State(err)
+    }
+}
+
+/// Playback diverged from the recording.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Desync {
+    pub frame: usize,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "desync at frame {}: expected hash {:016x}, got {:016x}",
+            self.frame, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for Desync {}
+
+/// FNV-1a hash of the complete machine state, used to detect desyncs.
+pub fn state_hash(cpu: &CPU) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in cpu.save_state() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Recorded controller input, starting either at power-on or from a save state.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_filename: String,
+    /// MD5 of the PRG and CHR data, as stored by FCEUX.
+    pub rom_md5: Option<[u8; 16]>,
+    /// SHA-1 of the PRG and CHR data, as stored by BizHawk.
+    pub rom_sha1: Option<[u8; 20]>,
+    pub guid: String,
+    pub rerecord_count: u32,
+    pub pal: bool,
+    pub four_score: bool,
+    /// Devices in ports 1 and 2 when there is no Four Score.
+    pub ports: [PortDevice; 2],
+    pub comments: Vec<String>,
+    /// State the movie starts from; `None` means power-on.
+    pub savestate: Option<Vec<u8>>,
+    pub frames: Vec<FrameInput>,
+    /// Hash of the machine state after each frame, taken while recording.
+    pub frame_hashes: Vec<Option<u64>>,
+}
+
+impl Movie {
+    /// Starts an empty recording from power-on.
+    pub fn new(rom_filename: &str, rom: &Rom) -> Self {
+        Movie {
+            rom_filename: rom_filename.to_string(),
+            rom_md5: Some(rom.md5()),
+            rom_sha1: Some(rom.sha1()),
+            guid: new_guid(&rom.md5()),
+            ..Movie::default()
+        }
+    }
+
+    /// Starts an empty recording from the current state of `cpu`.
+    pub fn from_save_state(rom_filename: &str, rom: &Rom, cpu: &CPU) -> Self {
+        Movie {
+            savestate: Some(cpu.save_state()),
+            ..Movie::new(rom_filename, rom)
+        }
+    }
+
+    /// Appends a frame; call after the frame has run with `input`.
+    pub fn record_frame(&mut self, input: FrameInput, cpu: &CPU) {
+        self.frames.push(input);
+        self.frame_hashes.push(Some(state_hash(cpu)));
+    }
+
+    /// Checks `rom` against every checksum the movie carries.
+    pub fn verify_rom(&self, rom: &Rom) -> Result<(), MovieError> {
+        if self.rom_md5.is_none() && self.rom_sha1.is_none() {
+            return Err(MovieError::MissingField("romChecksum"));
+        }
+        if let Some(expected) = self.rom_md5 {
+            check_checksum(&expected, &rom.md5())?;
+        }
+        if let Some(expected) = self.rom_sha1 {
+            check_checksum(&expected, &rom.sha1())?;
+        }
+        Ok(())
+    }
+
+    /// Puts `cpu` into the state the movie starts from. For power-on movies
+    /// the caller is expected to have loaded the ROM; RAM, registers and
+    /// the cycle counter start over whatever ran on `cpu` before.
+    pub fn start(&self, cpu: &mut CPU) -> Result<(), MovieError> {
+        match &self.savestate {
+            Some(state) => cpu.load_state(state)?,
+            None => {
+                cpu.power_cycle();
+                cpu.register_y = 0;
+                cpu.cycles = RESET_CYCLES;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Steps through a movie frame by frame.
+pub struct MoviePlayer<'a> {
+    movie: &'a Movie,
+    frame: usize,
+}
+
+impl<'a> MoviePlayer<'a> {
+    pub fn new(movie: &'a Movie) -> Self {
+        MoviePlayer { movie, frame: 0 }
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    /// Input for the frame about to run.
+    pub fn next_input(&self) -> Option<FrameInput> {
+        self.movie.frames.get(self.frame).copied()
+    }
+
+    /// Applies the console commands of the frame about to run to `cpu` and
+    /// returns its input.
+    pub fn begin_frame(&self, cpu: &mut CPU) -> Option<FrameInput> {
+        let input = self.next_input()?;
+        input.apply_commands(cpu);
+        Some(input)
+    }
+
+    /// Compares the state after the frame with the recorded hash and moves
+    /// on to the next frame.
+    pub fn end_frame(&mut self, cpu: &CPU) -> Result<(), Desync> {
+        let frame = self.frame;
+        self.frame += 1;
+        match self.movie.frame_hashes.get(frame).copied().flatten() {
+            Some(expected) => {
+                let actual = state_hash(cpu);
+                if actual != expected {
+                    return Err(Desync {
+                        frame,
+                        expected,
+                        actual,
+                    });
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Per-frame state hashes read from a movie header. They can come before
+/// the input, so they are only checked against the number of frames once
+/// the whole movie has been read.
+#[derive(Default)]
+pub(crate) struct FrameHashes {
+    /// Line, frame and hash of each entry.
+    entries: Vec<(usize, usize, u64)>,
+}
+
+impl FrameHashes {
+    /// Parses a `<frame> <hex hash>` value found on `line`.
+    pub(crate) fn parse(&mut self, line: usize, value: &str) -> Result<(), String> {
+        let (frame, hash) = value
+            .split_once(' ')
+            .and_then(|(frame, hash)| {
+                Some((
+                    frame.trim().parse::<usize>().ok()?,
+                    u64::from_str_radix(hash.trim(), 16).ok()?,
+                ))
+            })
+            .ok_or_else(|| format!("invalid frame hash {:?}", value))?;
+        self.entries.push((line, frame, hash));
+        Ok(())
+    }
+
+    /// Stores the hashes in `movie`, whose frames have all been read. On
+    /// error, gives the line of the first hash past the last frame.
+    pub(crate) fn apply(self, movie: &mut Movie) -> Result<(), (usize, String)> {
+        for (line, frame, hash) in self.entries {
+            if frame >= movie.frames.len() {
+                return Err((
+                    line,
+                    format!("frame hash for frame {} past the end of the input", frame),
+                ));
+            }
+            if movie.frame_hashes.len() <= frame {
+                movie.frame_hashes.resize(frame + 1, None);
+            }
+            movie.frame_hashes[frame] = Some(hash);
+        }
+        Ok(())
+    }
+}
+
+fn check_checksum(expected: &[u8], actual: &[u8]) -> Result<(), MovieError> {
+    if expected != actual {
+        return Err(MovieError::RomChecksumMismatch {
+            expected: expected.to_vec(),
+            actual: actual.to_vec(),
+        });
+    }
+    Ok(())
+}
+
+fn new_guid(seed: &[u8; 16]) -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos());
+    let mut context = md5::Context::new();
+    context.consume(seed);
+    context.consume(nanos.to_le_bytes());
+    let bytes = context.compute().0;
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+    use crate::input::{Joypad, BUTTON_A, BUTTON_START};
+
+    // LDA $00; ADC #$01; STA $00; BRK
+    const FRAME_PROGRAM: [u8; 7] = [0xa5, 0x00, 0x69, 0x01, 0x85, 0x00, 0x00];
+
+    fn run_frame(cpu: &mut CPU, input: FrameInput) {
+        cpu.mem_write(0x01, input.pads[0]);
+        cpu.program_counter = 0x8000;
+        cpu.run_until(CPU::at_brk).unwrap();
+    }
+
+    fn record(rom: &Rom) -> Movie {
+        let mut cpu = CPU::default();
+        cpu.load(FRAME_PROGRAM.to_vec());
+        let mut movie = Movie::new("test.nes", rom);
+        movie.start(&mut cpu).unwrap();
+        for frame in 0..10 {
+            let input = FrameInput {
+                commands: 0,
+                pads: [frame as u8, 0, 0, 0],
+            };
+            run_frame(&mut cpu, input);
+            movie.record_frame(input, &cpu);
+        }
+        movie
+    }
+
+    #[test]
+    fn test_playback_matches_recording() {
+        let rom = Rom::new(&test_rom(&FRAME_PROGRAM)).unwrap();
+        let movie = record(&rom);
+        movie.verify_rom(&rom).unwrap();
+
+        let mut cpu = CPU::default();
+        cpu.load(FRAME_PROGRAM.to_vec());
+        movie.start(&mut cpu).unwrap();
+        let mut player = MoviePlayer::new(&movie);
+        while let Some(input) = player.begin_frame(&mut cpu) {
+            run_frame(&mut cpu, input);
+            player.end_frame(&cpu).unwrap();
+        }
+        assert!(player.is_finished());
+        assert_eq!(cpu.mem_read(0x00), 10);
+    }
+
+    #[test]
+    fn test_power_on_playback_after_other_code_ran() {
+        let rom = Rom::new(&test_rom(&FRAME_PROGRAM)).unwrap();
+        let movie = record(&rom);
+
+        let mut cpu = CPU::default();
+        cpu.load(FRAME_PROGRAM.to_vec());
+        cpu.reset();
+        for _ in 0..5 {
+            run_frame(&mut cpu, FrameInput::default());
+        }
+        cpu.register_y = 0x42;
+        cpu.mem_write(0x0700, 0x99);
+
+        movie.start(&mut cpu).unwrap();
+        let mut player = MoviePlayer::new(&movie);
+        while let Some(input) = player.begin_frame(&mut cpu) {
+            run_frame(&mut cpu, input);
+            player.end_frame(&cpu).unwrap();
+        }
+        assert_eq!(cpu.mem_read(0x00), 10);
+        assert_eq!(cpu.mem_read(0x0700), 0);
+    }
+
+    #[test]
+    fn test_playback_reports_desync() {
+        let rom = Rom::new(&test_rom(&FRAME_PROGRAM)).unwrap();
+        let movie = record(&rom);
+
+        let mut cpu = CPU::default();
+        cpu.load(FRAME_PROGRAM.to_vec());
+        movie.start(&mut cpu).unwrap();
+        let mut player = MoviePlayer::new(&movie);
+        for _ in 0..3 {
+            run_frame(&mut cpu, player.next_input().unwrap());
+            player.end_frame(&cpu).unwrap();
+        }
+        cpu.mem_write(0x00, 0x80);
+        run_frame(&mut cpu, player.next_input().unwrap());
+
+        let desync = player.end_frame(&cpu).unwrap_err();
+        assert_eq!(desync.frame, 3);
+        assert_eq!(desync.expected, movie.frame_hashes[3].unwrap());
+    }
+
+    #[test]
+    fn test_rom_checksum_mismatch() {
+        let rom = Rom::new(&test_rom(&FRAME_PROGRAM)).unwrap();
+        let movie = record(&rom);
+        let other = Rom::new(&test_rom(&[0xea])).unwrap();
+
+        assert!(matches!(
+            movie.verify_rom(&other),
+            Err(MovieError::RomChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_apply_sets_pads() {
+        let mut ports = InputPorts::new(
+            InputDevice::Joypad(Joypad::new()),
+            InputDevice::Joypad(Joypad::new()),
+        );
+        let input = FrameInput {
+            commands: 0,
+            pads: [BUTTON_A, BUTTON_START, 0, 0],
+        };
+        input.apply(&mut ports);
+
+        ports.write(1);
+        assert_eq!(ports.read(0x4016), 1);
+        assert_eq!(ports.read(0x4017), 0);
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut cpu = CPU::default();
+        cpu.load(FRAME_PROGRAM.to_vec());
+        cpu.reset();
+        cpu.run_until(CPU::at_brk).unwrap();
+        let cycles = cpu.cycles;
+
+        let reset = FrameInput {
+            commands: COMMAND_RESET,
+            ..Default::default()
+        };
+        reset.apply_commands(&mut cpu);
+        assert_eq!(cpu.program_counter, 0x8000);
+        assert_eq!(cpu.stack_pointer, 0xfa);
+        assert_eq!(cpu.mem_read(0x00), 1);
+
+        let power = FrameInput {
+            commands: COMMAND_POWER,
+            ..Default::default()
+        };
+        power.apply_commands(&mut cpu);
+        assert_eq!(cpu.program_counter, 0x8000);
+        assert_eq!(cpu.stack_pointer, 0xfd);
+        assert_eq!(cpu.mem_read(0x00), 0);
+        assert_eq!(cpu.cycles, cycles + 14);
+    }
+}
@@ -0,0 +1,438 @@
+//! Cycle profiler: where CPU time goes, per instruction address and per
+//! subroutine.
+//!
+//! [`Profiler::record`] is called before every instruction, from
+//! [`CPU::run_with_callback`] or the predicate of a bounded run such as
+//! [`CPU::run_until`]. It charges the cycles the CPU counted since
+//! the previous call to the previous instruction's address and to the
+//! subroutine that was on top of a shadow call stack. Subroutines are
+//! entered on JSR and interrupts when three bytes are pushed by anything
+//! else. A frame is left once the stack pointer rises above where it was on
+//! entry, which covers RTS and RTI as well as code that drops its return
+//! address, and keeps the shadow stack as shallow as the real one.
+
+use crate::cpu::{Mem, CPU};
+use crate::symbols::SymbolTable;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const OPCODE_JSR: u8 = 0x20;
+
+/// Node of the call tree; the root stands for code outside any call.
+#[derive(Debug, Clone)]
+pub struct CallNode {
+    /// Address the subroutine or interrupt handler was entered at.
+    pub entry: Option<u16>,
+    pub interrupt: bool,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub calls: u64,
+    /// Cycles spent in this node, not counting callees.
+    pub self_cycles: u64,
+}
+
+/// Per-subroutine totals, merged over every place it was called from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubroutineStats {
+    pub entry: u16,
+    pub calls: u64,
+    pub self_cycles: u64,
+    /// Cycles including callees. Recursive calls are only counted once.
+    pub total_cycles: u64,
+}
+
+pub struct Profiler {
+    cycles: Vec<u64>,
+    executions: Vec<u64>,
+    nodes: Vec<CallNode>,
+    /// Shadow call stack: nodes with the stack pointer on entry.
+    stack: Vec<(usize, u8)>,
+    /// The previous instruction, charged on the next call.
+    last: Option<Last>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Last {
+    pc: u16,
+    code: u8,
+    stack_pointer: u8,
+    cycles: usize,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            cycles: vec![0; 0x10000],
+            executions: vec![0; 0x10000],
+            nodes: vec![CallNode {
+                entry: None,
+                interrupt: false,
+                parent: None,
+                children: Vec::new(),
+                calls: 1,
+                self_cycles: 0,
+            }],
+            stack: vec![(0, 0xff)],
+            last: None,
+        }
+    }
+
+    /// Charges the instruction run since the previous call and notes the
+    /// one at the program counter.
+    pub fn record(&mut self, cpu: &CPU) {
+        let pc = cpu.program_counter;
+        if let Some(last) = self.last {
+            let cycles = cpu.cycles.saturating_sub(last.cycles) as u64;
+            self.cycles[last.pc as usize] += cycles;
+            self.executions[last.pc as usize] += 1;
+            let (node, _) = *self.stack.last().unwrap();
+            self.nodes[node].self_cycles += cycles;
+
+            // The root frame is never left.
+            while self.stack.len() > 1 && self.stack.last().unwrap().1 < cpu.stack_pointer {
+                self.stack.pop();
+            }
+            let pushed = last.stack_pointer.wrapping_sub(cpu.stack_pointer);
+            match last.code {
+                OPCODE_JSR => self.enter(pc, false, cpu.stack_pointer),
+                _ if pushed == 3 => self.enter(pc, true, cpu.stack_pointer),
+                _ => {}
+            }
+        }
+
+        self.last = Some(Last {
+            pc,
+            code: cpu.mem_read(pc),
+            stack_pointer: cpu.stack_pointer,
+            cycles: cpu.cycles,
+        });
+    }
+
+    fn enter(&mut self, entry: u16, interrupt: bool, stack_pointer: u8) {
+        // Whatever was entered at the same depth has had its return
+        // address overwritten, as happens once runaway recursion wraps the
+        // stack.
+        while self.stack.len() > 1 && self.stack.last().unwrap().1 <= stack_pointer {
+            self.stack.pop();
+        }
+        let (parent, _) = *self.stack.last().unwrap();
+        let existing = self.nodes[parent].children.iter().copied().find(|&child| {
+            self.nodes[child].entry == Some(entry) && self.nodes[child].interrupt == interrupt
+        });
+        let node = existing.unwrap_or_else(|| {
+            self.nodes.push(CallNode {
+                entry: Some(entry),
+                interrupt,
+                parent: Some(parent),
+                children: Vec::new(),
+                calls: 0,
+                self_cycles: 0,
+            });
+            let node = self.nodes.len() - 1;
+            self.nodes[parent].children.push(node);
+            node
+        });
+        self.nodes[node].calls += 1;
+        self.stack.push((node, stack_pointer));
+    }
+
+    /// Cycles and execution count of the instruction at `addr`.
+    pub fn address_stats(&self, addr: u16) -> (u64, u64) {
+        (self.cycles[addr as usize], self.executions[addr as usize])
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.nodes.iter().map(|node| node.self_cycles).sum()
+    }
+
+    pub fn call_tree(&self) -> &[CallNode] {
+        &self.nodes
+    }
+
+    /// Cycles of `node` including everything it called.
+    pub fn inclusive_cycles(&self, node: usize) -> u64 {
+        self.inclusive_totals()[node]
+    }
+
+    /// Inclusive cycles of every node. Nodes are created after their
+    /// parent, so one backwards pass adds each subtree to its parent.
+    fn inclusive_totals(&self) -> Vec<u64> {
+        let mut totals: Vec<u64> = self.nodes.iter().map(|node| node.self_cycles).collect();
+        for (index, node) in self.nodes.iter().enumerate().rev() {
+            if let Some(parent) = node.parent {
+                totals[parent] += totals[index];
+            }
+        }
+        totals
+    }
+
+    /// Totals per subroutine, most expensive first.
+    pub fn subroutines(&self) -> Vec<SubroutineStats> {
+        let totals = self.inclusive_totals();
+        let mut stats: HashMap<u16, SubroutineStats> = HashMap::new();
+        // Depth-first over the tree, counting the entries on the current
+        // path so recursive calls are recognised without walking ancestors.
+        let mut on_path: HashMap<u16, usize> = HashMap::new();
+        let mut pending = vec![(0, false)];
+        while let Some((index, leaving)) = pending.pop() {
+            let node = &self.nodes[index];
+            let Some(entry) = node.entry else {
+                pending.extend(node.children.iter().map(|&child| (child, false)));
+                continue;
+            };
+            let depth = on_path.entry(entry).or_default();
+            if leaving {
+                *depth -= 1;
+                continue;
+            }
+            let total = if *depth > 0 { 0 } else { totals[index] };
+            *depth += 1;
+            let stat = stats.entry(entry).or_insert(SubroutineStats {
+                entry,
+                calls: 0,
+                self_cycles: 0,
+                total_cycles: 0,
+            });
+            stat.calls += node.calls;
+            stat.self_cycles += node.self_cycles;
+            stat.total_cycles += total;
+            pending.push((index, true));
+            pending.extend(node.children.iter().map(|&child| (child, false)));
+        }
+        let mut stats: Vec<SubroutineStats> = stats.into_values().collect();
+        stats.sort_by(|a, b| {
+            b.total_cycles
+                .cmp(&a.total_cycles)
+                .then(a.entry.cmp(&b.entry))
+        });
+        stats
+    }
+
+    fn ancestors(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(self.nodes[node].parent, move |&parent| {
+            self.nodes[parent].parent
+        })
+    }
+
+    /// Hottest `limit` instruction addresses, one per line.
+    pub fn flat_report(&self, symbols: Option<&SymbolTable>, limit: usize) -> String {
+        let total = self.total_cycles().max(1);
+        let mut addresses: Vec<usize> = (0..self.cycles.len())
+            .filter(|&addr| self.executions[addr] > 0)
+            .collect();
+        addresses.sort_by(|&a, &b| self.cycles[b].cmp(&self.cycles[a]).then(a.cmp(&b)));
+
+        let mut report = String::from("  cycles      %      count  address\n");
+        for addr in addresses.into_iter().take(limit) {
+            let _ = writeln!(
+                report,
+                "{:8} {:6.2} {:10}  {}",
+                self.cycles[addr],
+                self.cycles[addr] as f64 * 100.0 / total as f64,
+                self.executions[addr],
+                name(addr as u16, symbols)
+            );
+        }
+        report
+    }
+
+    /// Call tree with inclusive and self cycles, children sorted by cost.
+    pub fn tree_report(&self, symbols: Option<&SymbolTable>) -> String {
+        let totals = self.inclusive_totals();
+        let mut report = String::from("   total     self    calls  subroutine\n");
+        let mut pending = vec![(0, 0)];
+        while let Some((node, depth)) = pending.pop() {
+            let _ = writeln!(
+                report,
+                "{:8} {:8} {:8}  {}{}",
+                totals[node],
+                self.nodes[node].self_cycles,
+                self.nodes[node].calls,
+                "  ".repeat(depth),
+                self.node_name(node, symbols)
+            );
+            // Cheapest pushed first so the most expensive child comes next.
+            let mut children = self.nodes[node].children.clone();
+            children.sort_by_key(|&child| totals[child]);
+            pending.extend(children.into_iter().map(|child| (child, depth + 1)));
+        }
+        report
+    }
+
+    /// Folded stacks, `root;caller;callee cycles` per line, as read by
+    /// flamegraph.pl and compatible tools.
+    pub fn collapsed_stacks(&self, symbols: Option<&SymbolTable>) -> String {
+        let mut lines = Vec::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.self_cycles == 0 {
+                continue;
+            }
+            let mut path: Vec<String> = self
+                .ancestors(index)
+                .map(|ancestor| self.node_name(ancestor, symbols))
+                .collect();
+            path.reverse();
+            path.push(self.node_name(index, symbols));
+            lines.push(format!("{} {}", path.join(";"), node.self_cycles));
+        }
+        lines.sort();
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+
+    fn node_name(&self, node: usize, symbols: Option<&SymbolTable>) -> String {
+        match (self.nodes[node].entry, self.nodes[node].interrupt) {
+            (None, _) => "root".to_string(),
+            (Some(entry), true) => format!("[interrupt] {}", name(entry, symbols)),
+            (Some(entry), false) => name(entry, symbols),
+        }
+    }
+}
+
+fn name(addr: u16, symbols: Option<&SymbolTable>) -> String {
+    match symbols.and_then(|symbols| symbols.get(addr)) {
+        Some(label) => label.to_string(),
+        None => format!("${:04X}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::cpu::Hook;
+
+    const PROGRAM: &str = "
+        main:   JSR outer
+                JSR inner
+                BRK
+        outer:  JSR inner
+                RTS
+        inner:  INX
+                RTS
+    ";
+
+    fn profile() -> (Profiler, SymbolTable) {
+        let program = asm::assemble_program(PROGRAM).unwrap();
+        let mut cpu = CPU::default();
+        cpu.load(program.bytes);
+        cpu.reset();
+        let mut profiler = Profiler::new();
+        cpu.run_until(|cpu| {
+            profiler.record(cpu);
+            cpu.at_brk()
+        })
+        .unwrap();
+        (profiler, program.symbols)
+    }
+
+    #[test]
+    fn test_flat_profile() {
+        let (profiler, symbols) = profile();
+        let inner = symbols.lookup("inner").unwrap();
+        assert_eq!(profiler.address_stats(inner), (4, 2));
+        assert_eq!(profiler.address_stats(0x8000), (6, 1));
+        // 3 JSR + 3 RTS + 2 INX; the run stops before the BRK.
+        assert_eq!(profiler.total_cycles(), 18 + 18 + 4);
+
+        let report = profiler.flat_report(Some(&symbols), 2);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        // The RTS in inner runs for both calls.
+        assert_eq!(lines[1], "      12  30.00          2  $800C");
+        assert_eq!(lines[2], "       6  15.00          1  main");
+    }
+
+    #[test]
+    fn test_call_tree_and_subroutines() {
+        let (profiler, symbols) = profile();
+        let stats = profiler.subroutines();
+        let inner = symbols.lookup("inner").unwrap();
+        let outer = symbols.lookup("outer").unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                SubroutineStats {
+                    entry: outer,
+                    calls: 1,
+                    self_cycles: 12,
+                    total_cycles: 20,
+                },
+                SubroutineStats {
+                    entry: inner,
+                    calls: 2,
+                    self_cycles: 16,
+                    total_cycles: 16,
+                },
+            ]
+        );
+
+        let tree = profiler.tree_report(Some(&symbols));
+        assert!(tree.contains("      40       12        1  root\n"));
+        assert!(tree.contains("      20       12        1    outer\n"));
+        assert!(tree.contains("       8        8        1      inner\n"));
+    }
+
+    #[test]
+    fn test_collapsed_stacks() {
+        let (profiler, symbols) = profile();
+        assert_eq!(
+            profiler.collapsed_stacks(Some(&symbols)),
+            "root 12\nroot;inner 8\nroot;outer 12\nroot;outer;inner 8\n"
+        );
+        assert!(profiler.collapsed_stacks(None).contains("root;$8007 12\n"));
+    }
+
+    #[test]
+    fn test_interrupt_frames() {
+        let mut profiler = Profiler::new();
+        let mut cpu = CPU::default();
+        // NOP at $8000, then an interrupt pushes PC and P and enters $9000.
+        cpu.mem_write(0x8000, 0xea);
+        cpu.mem_write(0x9000, 0x40);
+        cpu.program_counter = 0x8000;
+        cpu.stack_pointer = 0xfd;
+        profiler.record(&cpu);
+        cpu.program_counter = 0x9000;
+        cpu.stack_pointer = 0xfa;
+        cpu.cycles += 2 + 7;
+        profiler.record(&cpu);
+        cpu.program_counter = 0x8001;
+        cpu.stack_pointer = 0xfd;
+        cpu.cycles += 6;
+        profiler.record(&cpu);
+
+        assert_eq!(
+            profiler.collapsed_stacks(None),
+            "root 9\nroot;[interrupt] $9000 6\n"
+        );
+    }
+
+    #[test]
+    fn test_runaway_recursion_stays_bounded() {
+        let program = asm::assemble_program("main: JSR main").unwrap();
+        let mut cpu = CPU::default();
+        cpu.load(program.bytes);
+        cpu.reset();
+        let mut profiler = Profiler::new();
+        cpu.run_with_hook(|cpu| {
+            profiler.record(cpu);
+            if cpu.cycles < 100_000 {
+                Hook::Continue
+            } else {
+                Hook::Break
+            }
+        })
+        .unwrap();
+        // Once the stack pointer wraps every frame is unwound, so the tree
+        // is no deeper than the 128 return addresses the stack can hold.
+        assert!(profiler.call_tree().len() <= 130);
+        assert!(profiler.stack.len() <= 129);
+        assert_eq!(profiler.total_cycles(), cpu.cycles as u64 - 7);
+    }
+}
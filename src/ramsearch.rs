@@ -0,0 +1,242 @@
+//! RAM search for finding where a game keeps a value, like lives or
+//! health, to watch or freeze it.
+//!
+//! A search starts with every address of work RAM and cartridge RAM as a
+//! candidate. Each [`RamSearch::filter`] compares the current value of
+//! the candidates with the one seen by the previous filter, keeps those
+//! that match and remembers the current values for the next round.
+
+use crate::cheats::Cheat;
+use crate::cpu::{Mem, CPU};
+use std::ops::Range;
+
+/// The console's 2 KiB of internal RAM, without its mirrors.
+pub const WORK_RAM: Range<u16> = 0x0000..0x0800;
+/// Battery-backed or work RAM on the cartridge.
+pub const CARTRIDGE_RAM: Range<u16> = 0x6000..0x8000;
+
+/// How the bytes at a candidate address are read. 16-bit values are little
+/// endian, low byte at the candidate address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    U8,
+    I8,
+    U16,
+    I16,
+}
+
+impl ValueType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "u8" => Some(ValueType::U8),
+            "i8" => Some(ValueType::I8),
+            "u16" => Some(ValueType::U16),
+            "i16" => Some(ValueType::I16),
+            _ => None,
+        }
+    }
+
+    /// Size in bytes.
+    pub fn size(self) -> u16 {
+        match self {
+            ValueType::U8 | ValueType::I8 => 1,
+            ValueType::U16 | ValueType::I16 => 2,
+        }
+    }
+
+    pub fn read(self, cpu: &CPU, addr: u16) -> i32 {
+        match self {
+            ValueType::U8 => cpu.mem_read(addr) as i32,
+            ValueType::I8 => cpu.mem_read(addr) as i8 as i32,
+            ValueType::U16 => cpu.mem_read_u16(addr) as i32,
+            ValueType::I16 => cpu.mem_read_u16(addr) as i16 as i32,
+        }
+    }
+
+    /// Wraps `value` into the range of this type, so an unsigned byte going
+    /// from 0 to 255 matches a change of -1.
+    fn wrap(self, value: i32) -> i32 {
+        match self {
+            ValueType::U8 => value as u8 as i32,
+            ValueType::I8 => value as i8 as i32,
+            ValueType::U16 => value as u16 as i32,
+            ValueType::I16 => value as i16 as i32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relation {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+impl Relation {
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol {
+            "==" => Some(Relation::Equal),
+            "!=" => Some(Relation::NotEqual),
+            "<" => Some(Relation::Less),
+            ">" => Some(Relation::Greater),
+            "<=" => Some(Relation::LessOrEqual),
+            ">=" => Some(Relation::GreaterOrEqual),
+            _ => None,
+        }
+    }
+
+    fn holds(self, lhs: i32, rhs: i32) -> bool {
+        match self {
+            Relation::Equal => lhs == rhs,
+            Relation::NotEqual => lhs != rhs,
+            Relation::Less => lhs < rhs,
+            Relation::Greater => lhs > rhs,
+            Relation::LessOrEqual => lhs <= rhs,
+            Relation::GreaterOrEqual => lhs >= rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// The current value relates to the previous one, e.g. it went down.
+    Previous(Relation),
+    /// The current value relates to a constant.
+    Value(Relation, i32),
+    /// The current value is the previous one plus this, wrapping around.
+    ChangedBy(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub address: u16,
+    /// Value at the last snapshot.
+    pub previous: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct RamSearch {
+    value_type: ValueType,
+    candidates: Vec<Candidate>,
+}
+
+impl RamSearch {
+    /// Starts a search over work RAM and cartridge RAM.
+    pub fn new(cpu: &CPU, value_type: ValueType) -> Self {
+        RamSearch::with_regions(cpu, value_type, &[WORK_RAM, CARTRIDGE_RAM])
+    }
+
+    /// Starts a search over `regions`. Multi-byte values never straddle
+    /// the end of a region.
+    pub fn with_regions(cpu: &CPU, value_type: ValueType, regions: &[Range<u16>]) -> Self {
+        let candidates = regions
+            .iter()
+            .flat_map(|region| region.start..region.end.saturating_sub(value_type.size() - 1))
+            .map(|address| Candidate {
+                address,
+                previous: value_type.read(cpu, address),
+            })
+            .collect();
+        RamSearch {
+            value_type,
+            candidates,
+        }
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    /// Keeps the candidates whose current value passes `filter`, takes a
+    /// new snapshot of them and returns how many are left.
+    pub fn filter(&mut self, cpu: &CPU, filter: Filter) -> usize {
+        let value_type = self.value_type;
+        self.candidates.retain_mut(|candidate| {
+            let current = value_type.read(cpu, candidate.address);
+            let keep = match filter {
+                Filter::Previous(relation) => relation.holds(current, candidate.previous),
+                Filter::Value(relation, value) => relation.holds(current, value),
+                Filter::ChangedBy(delta) => {
+                    value_type.wrap(current - candidate.previous) == value_type.wrap(delta)
+                }
+            };
+            candidate.previous = current;
+            keep
+        });
+        self.candidates.len()
+    }
+
+    /// Cheats holding every remaining candidate at its current value, one
+    /// per byte.
+    pub fn freeze(&self, cpu: &CPU) -> Vec<Cheat> {
+        self.candidates
+            .iter()
+            .flat_map(|candidate| {
+                (0..self.value_type.size()).map(move |offset| candidate.address + offset)
+            })
+            .map(|address| Cheat::freeze(address, cpu.mem_read(address)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_narrow_down_a_counter() {
+        let mut cpu = CPU::default();
+        cpu.mem_write(0x0075, 3);
+        cpu.mem_write(0x6010, 3);
+        let mut search = RamSearch::new(&cpu, ValueType::U8);
+        assert_eq!(search.candidates().len(), 0x800 + 0x2000);
+
+        assert_eq!(search.filter(&cpu, Filter::Value(Relation::Equal, 3)), 2);
+        cpu.mem_write(0x0075, 2);
+        assert_eq!(search.filter(&cpu, Filter::Previous(Relation::Less)), 1);
+        assert_eq!(search.candidates()[0].address, 0x0075);
+        cpu.mem_write(0x0075, 1);
+        assert_eq!(search.filter(&cpu, Filter::ChangedBy(-1)), 1);
+
+        assert_eq!(search.freeze(&cpu), vec![Cheat::freeze(0x0075, 1)]);
+    }
+
+    #[test]
+    fn test_wide_and_signed_values() {
+        let mut cpu = CPU::default();
+        cpu.mem_write_u16(0x0100, 0x00ff);
+        let mut search =
+            RamSearch::with_regions(&cpu, ValueType::U16, &[0x0100..0x0104, 0x0200..0x0201]);
+        // None start at $0103 or $0200, the last byte of their region.
+        assert_eq!(search.candidates().len(), 3);
+
+        // 16-bit little endian: $00FF + 5 carries into the high byte.
+        cpu.mem_write_u16(0x0100, 0x0104);
+        assert_eq!(search.filter(&cpu, Filter::ChangedBy(5)), 1);
+        assert_eq!(search.candidates()[0].address, 0x0100);
+        assert_eq!(
+            search.freeze(&cpu),
+            vec![Cheat::freeze(0x0100, 0x04), Cheat::freeze(0x0101, 0x01)]
+        );
+
+        cpu.mem_write(0x0200, 0xff);
+        let mut search = RamSearch::with_regions(&cpu, ValueType::I8, &[WORK_RAM]);
+        search.filter(&cpu, Filter::Value(Relation::Equal, -1));
+        assert_eq!(search.candidates()[0].previous, -1);
+        assert_eq!(search.filter(&cpu, Filter::Value(Relation::Less, 0)), 1);
+        cpu.mem_write(0x0200, 0x00);
+        assert_eq!(search.filter(&cpu, Filter::ChangedBy(1)), 1);
+        // +127 to -128 wraps around as a change of one.
+        cpu.mem_write(0x0200, 0x7f);
+        search.filter(&cpu, Filter::ChangedBy(0x7f));
+        cpu.mem_write(0x0200, 0x80);
+        assert_eq!(search.filter(&cpu, Filter::ChangedBy(1)), 1);
+    }
+}
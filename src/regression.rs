@@ -0,0 +1,415 @@
+//! Golden-image regression tests over a directory of ROMs.
+//!
+//! Every `.nes` ROM or `.s` source in the directory runs for a fixed number
+//! of frames, with input from a script or FM2 movie of the same name if
+//! there is one (`smb.nes` plays `smb.input` or `smb.fm2`). The golden
+//! `<golden>/<name>.state` holds the stop reason, cycle count, machine
+//! state hash and a hash of the last picture, and the run must match every
+//! line of it. Blessing writes the goldens instead of comparing. When only
+//! the picture differs, it is saved as `<golden>/<name>.actual.ppm` to look
+//! at.
+//!
+//! There is no PPU yet, so the picture only holds the backdrop and the
+//! state lines are what catch CPU regressions until then.
+
+use crate::asm;
+use crate::cartridge::Rom;
+use crate::cpu::{Mem, CPU};
+use crate::movie::fm2;
+use crate::render::palette::SYSTEM_PALETTE;
+use crate::render::screenshot::{Crop, Image};
+use crate::runner::{ExitCondition, InputScript, Runner, StopReason};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Ten seconds of NTSC frames.
+pub const DEFAULT_FRAMES: u64 = 600;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// The goldens were written from this run.
+    Blessed,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Suite {
+    pub roms: PathBuf,
+    pub golden: PathBuf,
+    pub frames: u64,
+    pub crop: Crop,
+}
+
+impl Suite {
+    /// A suite over `roms` with goldens in its `golden` subdirectory.
+    pub fn new(roms: impl Into<PathBuf>) -> Self {
+        let roms = roms.into();
+        Suite {
+            golden: roms.join("golden"),
+            roms,
+            frames: DEFAULT_FRAMES,
+            crop: Crop::None,
+        }
+    }
+
+    /// The ROMs and sources in the directory, sorted by name.
+    pub fn cases(&self) -> io::Result<Vec<PathBuf>> {
+        let mut cases = Vec::new();
+        for entry in fs::read_dir(&self.roms)? {
+            let path = entry?.path();
+            let ext = path.extension().and_then(|ext| ext.to_str());
+            if path.is_file() && matches!(ext, Some("nes" | "s")) {
+                cases.push(path);
+            }
+        }
+        cases.sort();
+        Ok(cases)
+    }
+
+    /// Runs every case, or with `bless` writes their goldens.
+    pub fn run(&self, bless: bool) -> io::Result<Report> {
+        if bless {
+            fs::create_dir_all(&self.golden)?;
+        }
+        let cases = self.cases()?;
+        let results = cases
+            .iter()
+            .map(|path| {
+                let start = Instant::now();
+                let outcome = self.run_case(path, bless).unwrap_or_else(Outcome::Failed);
+                CaseResult {
+                    name: case_name(path),
+                    outcome,
+                    duration: start.elapsed(),
+                }
+            })
+            .collect();
+        Ok(Report {
+            name: case_name(&self.roms),
+            results,
+        })
+    }
+
+    fn run_case(&self, path: &Path, bless: bool) -> Result<Outcome, String> {
+        let mut cpu = CPU::default();
+        load(&mut cpu, path)?;
+        let mut runner = Runner::new();
+        runner.input = load_input(path)?;
+        runner.conditions.push(ExitCondition::Frames(self.frames));
+        let summary = runner.run(&mut cpu);
+        if let StopReason::Fault(_) = summary.reason {
+            return Err(summary.reason.to_string());
+        }
+
+        let image = Image::from_frame(&runner.frame, &SYSTEM_PALETTE, self.crop);
+        let state = format!(
+            "stop: {}\ncycles: {}\nhash: {:016x}\nimage: {}x{} {:016x}\n",
+            summary.reason,
+            summary.cycles,
+            summary.hash,
+            image.width,
+            image.height,
+            image_hash(&image)
+        );
+        let name = case_name(path);
+        let state_path = self.golden.join(format!("{}.state", name));
+        if bless {
+            fs::write(&state_path, state).map_err(|err| io_error(&state_path, err))?;
+            return Ok(Outcome::Blessed);
+        }
+
+        let golden_state =
+            fs::read_to_string(&state_path).map_err(|err| missing(&state_path, err))?;
+        let expected: Vec<&str> = golden_state.lines().collect();
+        let actual: Vec<&str> = state.lines().collect();
+        if expected == actual {
+            return Ok(Outcome::Passed);
+        }
+        let Some((expected, actual)) = expected
+            .iter()
+            .zip(&actual)
+            .find(|(expected, actual)| expected != actual)
+        else {
+            return Err(format!(
+                "expected {} state lines, got {}",
+                expected.len(),
+                actual.len()
+            ));
+        };
+        let mut message = format!("expected {}, got {}", expected, actual);
+        if actual.starts_with("image: ") {
+            let actual_path = self.golden.join(format!("{}.actual.ppm", name));
+            fs::write(&actual_path, image.to_ppm()).map_err(|err| io_error(&actual_path, err))?;
+            let _ = write!(message, ", see {}", actual_path.display());
+        }
+        Err(message)
+    }
+}
+
+/// Results of a suite run.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub name: String,
+    pub results: Vec<CaseResult>,
+}
+
+impl Report {
+    pub fn failures(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| matches!(result.outcome, Outcome::Failed(_)))
+            .count()
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failures() == 0
+    }
+
+    /// One line per case and a count at the end.
+    pub fn summary(&self) -> String {
+        let mut text = String::new();
+        for result in &self.results {
+            let _ = match &result.outcome {
+                Outcome::Passed => writeln!(text, "pass     {}", result.name),
+                Outcome::Blessed => writeln!(text, "blessed  {}", result.name),
+                Outcome::Failed(message) => writeln!(text, "FAIL     {}: {}", result.name, message),
+            };
+        }
+        let _ = writeln!(
+            text,
+            "{} cases, {} failed",
+            self.results.len(),
+            self.failures()
+        );
+        text
+    }
+
+    /// The report as a JUnit XML test suite, for CI servers.
+    pub fn junit_xml(&self) -> String {
+        let total: Duration = self.results.iter().map(|result| result.duration).sum();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+            escape(&self.name),
+            self.results.len(),
+            self.failures(),
+            total.as_secs_f64()
+        );
+        for result in &self.results {
+            let _ = write!(
+                xml,
+                "  <testcase name=\"{}\" time=\"{:.3}\"",
+                escape(&result.name),
+                result.duration.as_secs_f64()
+            );
+            let _ = match &result.outcome {
+                Outcome::Failed(message) => writeln!(
+                    xml,
+                    ">\n    <failure message=\"{}\"/>\n  </testcase>",
+                    escape(message)
+                ),
+                _ => writeln!(xml, "/>"),
+            };
+        }
+        xml.push_str("</testsuite>\n");
+        xml
+    }
+}
+
+fn case_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Loads a ROM, or assembles a source file, and resets the CPU.
+fn load(cpu: &mut CPU, path: &Path) -> Result<(), String> {
+    if path.extension().is_some_and(|ext| ext == "nes") {
+        let raw = fs::read(path).map_err(|err| io_error(path, err))?;
+        cpu.load_rom(&Rom::new(&raw)?);
+    } else {
+        let source = fs::read_to_string(path).map_err(|err| io_error(path, err))?;
+        let program =
+            asm::assemble_program(&source).map_err(|err| format!("{}: {}", path.display(), err))?;
+        for (offset, byte) in program.bytes.iter().enumerate() {
+            cpu.mem_write(program.origin.wrapping_add(offset as u16), *byte);
+        }
+        if program.origin as usize + program.bytes.len() <= 0xfffc {
+            cpu.mem_write_u16(0xfffc, program.origin);
+        }
+    }
+    cpu.reset();
+    Ok(())
+}
+
+/// The `.input` script or `.fm2` movie next to the ROM, if any.
+fn load_input(path: &Path) -> Result<InputScript, String> {
+    let script = path.with_extension("input");
+    if script.exists() {
+        let text = fs::read_to_string(&script).map_err(|err| io_error(&script, err))?;
+        return InputScript::parse(&text).map_err(|err| format!("{}: {}", script.display(), err));
+    }
+    let movie = path.with_extension("fm2");
+    if movie.exists() {
+        let text = fs::read_to_string(&movie).map_err(|err| io_error(&movie, err))?;
+        let movie = fm2::parse(&text).map_err(|err| format!("{}: {}", movie.display(), err))?;
+        return Ok(InputScript::from_movie(&movie));
+    }
+    Ok(InputScript::default())
+}
+
+/// FNV-1a over the pixels, as [`movie::state_hash`](crate::movie::state_hash)
+/// does over the machine state.
+fn image_hash(image: &Image) -> u64 {
+    image.rgb.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn io_error(path: &Path, err: io::Error) -> String {
+    format!("{}: {}", path.display(), err)
+}
+
+fn missing(path: &Path, err: io::Error) -> String {
+    match err.kind() {
+        io::ErrorKind::NotFound => format!("no golden {}, run with --bless", path.display()),
+        _ => io_error(path, err),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nes_regression_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_bless_then_compare() {
+        let dir = scratch_dir("bless");
+        fs::write(dir.join("count.s"), "loop: ADC #1\nSTA $10\nJSR loop\n").unwrap();
+        fs::write(dir.join("fault.s"), ".byte $02\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not a ROM").unwrap();
+        let mut suite = Suite::new(&dir);
+        suite.frames = 2;
+
+        let report = suite.run(false).unwrap();
+        assert_eq!(report.results.len(), 2);
+        assert!(
+            matches!(&report.results[0].outcome, Outcome::Failed(message) if message.contains("--bless"))
+        );
+        assert_eq!(
+            report.results[1].outcome,
+            Outcome::Failed("CPU fault: JAM opcode $02 at $8000".to_string())
+        );
+
+        fs::remove_file(dir.join("fault.s")).unwrap();
+        assert_eq!(
+            suite.run(true).unwrap().results[0].outcome,
+            Outcome::Blessed
+        );
+        let report = suite.run(false).unwrap();
+        assert!(report.is_success(), "{}", report.summary());
+
+        // A program that runs differently no longer matches its golden state.
+        fs::write(dir.join("count.s"), "loop: ADC #2\nSTA $10\nJSR loop\n").unwrap();
+        let report = suite.run(false).unwrap();
+        assert!(
+            matches!(&report.results[0].outcome, Outcome::Failed(message) if message.starts_with("expected hash: "))
+        );
+
+        // So does a picture that differs, which is saved to look at.
+        suite.run(true).unwrap();
+        let golden = dir.join("golden/count.state");
+        let blessed = fs::read_to_string(&golden).unwrap();
+        let image_line = blessed.lines().last().unwrap();
+        assert!(image_line.starts_with("image: 256x240 "));
+        fs::write(
+            &golden,
+            blessed.replace(image_line, "image: 256x240 0000000000000000"),
+        )
+        .unwrap();
+        let report = suite.run(false).unwrap();
+        assert!(
+            matches!(&report.results[0].outcome, Outcome::Failed(message) if message.starts_with("expected image: 256x240 0000000000000000, got image: ") && message.ends_with("count.actual.ppm"))
+        );
+        assert!(dir.join("golden/count.actual.ppm").exists());
+
+        // A truncated or extended golden fails even where its lines match.
+        let lines: Vec<&str> = blessed.lines().collect();
+        fs::write(&golden, lines[..2].join("\n")).unwrap();
+        assert_eq!(
+            suite.run(false).unwrap().results[0].outcome,
+            Outcome::Failed("expected 2 state lines, got 4".to_string())
+        );
+        fs::write(&golden, format!("{}extra: 1\n", blessed)).unwrap();
+        assert_eq!(
+            suite.run(false).unwrap().results[0].outcome,
+            Outcome::Failed("expected 5 state lines, got 4".to_string())
+        );
+        fs::write(&golden, "").unwrap();
+        assert_eq!(
+            suite.run(false).unwrap().results[0].outcome,
+            Outcome::Failed("expected 0 state lines, got 4".to_string())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reports() {
+        let report = Report {
+            name: "roms".to_string(),
+            results: vec![
+                CaseResult {
+                    name: "a".to_string(),
+                    outcome: Outcome::Passed,
+                    duration: Duration::from_millis(250),
+                },
+                CaseResult {
+                    name: "b".to_string(),
+                    outcome: Outcome::Failed("expected <1> & got \"2\"".to_string()),
+                    duration: Duration::from_millis(500),
+                },
+            ],
+        };
+        assert_eq!(
+            report.summary(),
+            "pass     a\nFAIL     b: expected <1> & got \"2\"\n2 cases, 1 failed\n"
+        );
+        assert_eq!(
+            report.junit_xml(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <testsuite name=\"roms\" tests=\"2\" failures=\"1\" time=\"0.750\">\n  \
+             <testcase name=\"a\" time=\"0.250\"/>\n  \
+             <testcase name=\"b\" time=\"0.500\">\n    \
+             <failure message=\"expected &lt;1&gt; &amp; got &quot;2&quot;\"/>\n  \
+             </testcase>\n\
+             </testsuite>\n"
+        );
+    }
+}
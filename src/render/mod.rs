@@ -0,0 +1,3 @@
+pub mod frame;
+pub mod palette;
+pub mod screenshot;
@@ -0,0 +1,188 @@
+//! Saving a [`Frame`] as an image file.
+//!
+//! Palette indices go through a 64 colour palette, normally
+//! [`SYSTEM_PALETTE`](super::palette::SYSTEM_PALETTE), to RGB. PPM is written
+//! as binary `P6`. PNG is 8-bit RGB with the image data in stored (not
+//! compressed) deflate blocks, which every decoder reads and needs no
+//! compression library.
+
+use super::frame::Frame;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Scanlines hidden at the top and at the bottom by most NTSC televisions.
+const OVERSCAN_LINES: usize = 8;
+/// Largest stored deflate block.
+const MAX_STORED_BLOCK: usize = 0xffff;
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Crop {
+    /// All 256×240 pixels.
+    #[default]
+    None,
+    /// 256×224, without the top and bottom 8 scanlines.
+    Overscan,
+}
+
+/// An 8-bit RGB picture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// Three bytes per pixel, row by row.
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    pub fn from_frame(frame: &Frame, palette: &[(u8, u8, u8); 64], crop: Crop) -> Self {
+        let rows = match crop {
+            Crop::None => 0..Frame::HEIGHT,
+            Crop::Overscan => OVERSCAN_LINES..Frame::HEIGHT - OVERSCAN_LINES,
+        };
+        let height = rows.len();
+        let rgb = frame.data[rows.start * Frame::WIDTH..rows.end * Frame::WIDTH]
+            .iter()
+            .flat_map(|&index| {
+                let (r, g, b) = palette[index as usize & 0x3f];
+                [r, g, b]
+            })
+            .collect();
+        Image {
+            width: Frame::WIDTH,
+            height,
+            rgb,
+        }
+    }
+
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.extend_from_slice(&self.rgb);
+        data
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGB, deflate, adaptive filtering, no interlace.
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        // Every row starts with filter type 0, none.
+        let raw: Vec<u8> = self
+            .rgb
+            .chunks(self.width * 3)
+            .flat_map(|row| std::iter::once(0).chain(row.iter().copied()))
+            .collect();
+
+        let mut png = PNG_SIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &header);
+        write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// Writes a PPM file if `path` ends in `.ppm`, PNG otherwise.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let data = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("ppm") => self.to_ppm(),
+            _ => self.to_png(),
+        };
+        std::fs::File::create(path)?.write_all(&data)
+    }
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream of stored deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32 KiB window, no dictionary, fastest compression.
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::palette::SYSTEM_PALETTE;
+
+    #[test]
+    fn test_crop_and_ppm() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 8, 0x20);
+        frame.set_pixel(255, 239, 0x0f);
+
+        let full = Image::from_frame(&frame, &SYSTEM_PALETTE, Crop::None);
+        assert_eq!((full.width, full.height), (256, 240));
+        assert_eq!(full.rgb.len(), 256 * 240 * 3);
+        assert_eq!(&full.rgb[full.rgb.len() - 3..], &[0x05, 0x05, 0x05]);
+
+        let cropped = Image::from_frame(&frame, &SYSTEM_PALETTE, Crop::Overscan);
+        assert_eq!(cropped.height, 224);
+        assert_eq!(&cropped.rgb[..6], &[0xff, 0xff, 0xff, 0x80, 0x80, 0x80]);
+
+        let ppm = cropped.to_ppm();
+        assert!(ppm.starts_with(b"P6\n256 224\n255\n\xff\xff\xff"));
+        assert_eq!(ppm.len(), 15 + 256 * 224 * 3);
+    }
+
+    #[test]
+    fn test_png_layout() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+
+        let png = Image::from_frame(&Frame::new(), &SYSTEM_PALETTE, Crop::None).to_png();
+        assert!(png.starts_with(PNG_SIGNATURE));
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 1, 0, 0, 0, 0, 0xf0]);
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+
+        // 240 rows of a filter byte and 768 bytes of pixels in stored blocks.
+        let raw_len: usize = 240 * (1 + 256 * 3);
+        let blocks = raw_len.div_ceil(MAX_STORED_BLOCK);
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        assert_eq!(idat_len, 2 + blocks * 5 + raw_len + 4);
+        assert_eq!(&png[41..43], &[0x78, 0x01]);
+        assert_eq!(&png[44..48], &[0xff, 0xff, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,433 @@
+use crate::cpu::CPU;
+use crate::savestate::StateError;
+use std::collections::VecDeque;
+use std::fmt;
+
+pub struct RewindConfig {
+    /// Emulated frames between two snapshots.
+    pub frames_per_snapshot: u64,
+    /// Snapshots between two keyframes; the others are stored as deltas.
+    pub snapshots_per_keyframe: usize,
+    /// Upper bound for the compressed history, in bytes. The keyframe group
+    /// being filled is never dropped, so a budget smaller than one group is
+    /// exceeded by that group.
+    pub memory_budget: usize,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        RewindConfig {
+            frames_per_snapshot: 2,
+            snapshots_per_keyframe: 60,
+            memory_budget: 32 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RewindError {
+    /// The requested frame is older than the oldest snapshot or newer than
+    /// the most recent recorded frame.
+    OutOfHistory(u64),
+    State(StateError),
+}
+
+impl fmt::Display for RewindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RewindError::OutOfHistory(frame) => write!(f, "frame {} is not in history", frame),
+            RewindError::State(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for RewindError {}
+
+impl From<StateError> for RewindError {
+    fn from(err: StateError) -> Self {
+        RewindError::State(err)
+    }
+}
+
+struct Snapshot {
+    frame: u64,
+    is_keyframe: bool,
+    /// Compressed state for keyframes, compressed XOR against the
+    /// preceding keyframe for deltas.
+    data: Vec<u8>,
+}
+
+/// Ring buffer of save states for stepping emulation backwards.
+///
+/// Call [`RewindBuffer::record`] at the start of every frame. Every
+/// `frames_per_snapshot` frames a snapshot is taken; keyframes hold a whole
+/// state, the snapshots in between hold the XOR against their keyframe.
+/// Both are run-length compressed, which suits states that barely change
+/// between frames. Before a snapshot would take the history over the
+/// memory budget, the oldest keyframe and its deltas are dropped.
+pub struct RewindBuffer {
+    config: RewindConfig,
+    snapshots: VecDeque<Snapshot>,
+    keyframe: Vec<u8>,
+    since_keyframe: usize,
+    memory_used: usize,
+    current_frame: Option<u64>,
+}
+
+impl RewindBuffer {
+    pub fn new(config: RewindConfig) -> Self {
+        RewindBuffer {
+            config,
+            snapshots: VecDeque::new(),
+            keyframe: Vec::new(),
+            since_keyframe: 0,
+            memory_used: 0,
+            current_frame: None,
+        }
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    /// Oldest frame that can still be rewound to.
+    pub fn oldest_frame(&self) -> Option<u64> {
+        self.snapshots.front().map(|snapshot| snapshot.frame)
+    }
+
+    pub fn current_frame(&self) -> Option<u64> {
+        self.current_frame
+    }
+
+    /// Records the machine state at the start of `frame`.
+    pub fn record(&mut self, frame: u64, cpu: &CPU) {
+        self.current_frame = Some(frame);
+        if !frame.is_multiple_of(self.config.frames_per_snapshot) {
+            return;
+        }
+        // Recording again over rewound frames replaces the old future.
+        let kept = self
+            .snapshots
+            .iter()
+            .take_while(|snapshot| snapshot.frame < frame)
+            .count();
+        self.truncate(kept);
+
+        let state = cpu.save_state();
+        let is_keyframe = self.snapshots.is_empty()
+            || self.since_keyframe + 1 >= self.config.snapshots_per_keyframe
+            || state.len() != self.keyframe.len();
+
+        let data = if is_keyframe {
+            let data = compress(&state);
+            self.keyframe = state;
+            self.since_keyframe = 0;
+            data
+        } else {
+            self.since_keyframe += 1;
+            compress(&xor(&state, &self.keyframe))
+        };
+
+        self.evict(data.len(), is_keyframe);
+        self.memory_used += data.len();
+        self.snapshots.push_back(Snapshot {
+            frame,
+            is_keyframe,
+            data,
+        });
+    }
+
+    /// Restores the machine to the start of `target_frame`.
+    ///
+    /// The closest snapshot at or before the target is restored, then
+    /// `run_frame` is called with each frame number up to the target to
+    /// replay it. History after the target is discarded.
+    pub fn rewind_to<F>(
+        &mut self,
+        cpu: &mut CPU,
+        target_frame: u64,
+        mut run_frame: F,
+    ) -> Result<(), RewindError>
+    where
+        F: FnMut(&mut CPU, u64),
+    {
+        if self
+            .current_frame
+            .is_none_or(|current| target_frame > current)
+        {
+            return Err(RewindError::OutOfHistory(target_frame));
+        }
+        let index = self
+            .snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.frame <= target_frame)
+            .ok_or(RewindError::OutOfHistory(target_frame))?;
+
+        let state = self.decode(index);
+        cpu.load_state(&state)?;
+        let snapshot_frame = self.snapshots[index].frame;
+        for frame in snapshot_frame..target_frame {
+            run_frame(cpu, frame);
+        }
+
+        self.truncate(index + 1);
+        self.current_frame = Some(target_frame);
+        Ok(())
+    }
+
+    /// Steps `frames` frames back from the most recently recorded frame.
+    pub fn step_back<F>(
+        &mut self,
+        cpu: &mut CPU,
+        frames: u64,
+        run_frame: F,
+    ) -> Result<(), RewindError>
+    where
+        F: FnMut(&mut CPU, u64),
+    {
+        let current = self.current_frame.unwrap_or(0);
+        let target = current
+            .checked_sub(frames)
+            .ok_or(RewindError::OutOfHistory(0))?;
+        self.rewind_to(cpu, target, run_frame)
+    }
+
+    fn decode(&self, index: usize) -> Vec<u8> {
+        let keyframe_index = (0..=index)
+            .rev()
+            .find(|i| self.snapshots[*i].is_keyframe)
+            .expect("history always starts with a keyframe");
+        let keyframe = decompress(&self.snapshots[keyframe_index].data);
+        if keyframe_index == index {
+            keyframe
+        } else {
+            xor(&decompress(&self.snapshots[index].data), &keyframe)
+        }
+    }
+
+    /// Keeps the first `len` snapshots and rebuilds the delta base.
+    fn truncate(&mut self, len: usize) {
+        if len == self.snapshots.len() {
+            return;
+        }
+        for snapshot in self.snapshots.drain(len..) {
+            self.memory_used -= snapshot.data.len();
+        }
+        let Some(last) = len.checked_sub(1) else {
+            return;
+        };
+        let keyframe_index = (0..=last)
+            .rev()
+            .find(|i| self.snapshots[*i].is_keyframe)
+            .expect("history always starts with a keyframe");
+        self.keyframe = decompress(&self.snapshots[keyframe_index].data);
+        self.since_keyframe = last - keyframe_index;
+    }
+
+    /// Drops whole keyframe groups from the front until a snapshot of
+    /// `incoming` bytes fits in the budget. The group being filled is kept
+    /// unless the incoming snapshot is a keyframe that starts a new one.
+    fn evict(&mut self, incoming: usize, incoming_is_keyframe: bool) {
+        while self.memory_used + incoming > self.config.memory_budget {
+            let next_keyframe = self
+                .snapshots
+                .iter()
+                .skip(1)
+                .position(|snapshot| snapshot.is_keyframe);
+            let group_len = match next_keyframe {
+                Some(position) => position + 1,
+                None if incoming_is_keyframe && !self.snapshots.is_empty() => self.snapshots.len(),
+                None => break,
+            };
+            for snapshot in self.snapshots.drain(..group_len) {
+                self.memory_used -= snapshot.data.len();
+            }
+        }
+    }
+}
+
+fn xor(data: &[u8], base: &[u8]) -> Vec<u8> {
+    data.iter().zip(base).map(|(a, b)| a ^ b).collect()
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// Run-length encodes zero runs as `zeros, literal_len, literal bytes...`.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let zeros = data[pos..].iter().take_while(|byte| **byte == 0).count();
+        pos += zeros;
+        let literal_start = pos;
+        // Short zero runs are cheaper to keep inside the literal.
+        while pos < data.len() && data[pos..].iter().take(4).any(|byte| *byte != 0) {
+            pos += 1;
+        }
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, pos - literal_start);
+        out.extend_from_slice(&data[literal_start..pos]);
+    }
+    out
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let zeros = read_varint(data, &mut pos);
+        out.resize(out.len() + zeros, 0);
+        let literal_len = read_varint(data, &mut pos);
+        out.extend_from_slice(&data[pos..pos + literal_len]);
+        pos += literal_len;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Mem;
+
+    // LDA $00; ADC #$01; STA $00; STA $0200,X; INX; BRK
+    const FRAME_PROGRAM: [u8; 11] = [
+        0xa5, 0x00, 0x69, 0x01, 0x85, 0x00, 0x9d, 0x00, 0x02, 0xe8, 0x00,
+    ];
+
+    fn run_frame(cpu: &mut CPU, _frame: u64) {
+        // Everything up to the BRK, which would push to the stack.
+        cpu.program_counter = 0x8000;
+        cpu.run_for_instructions(5).unwrap();
+    }
+
+    fn record_frames(rewind: &mut RewindBuffer, cpu: &mut CPU, frames: u64) -> Vec<Vec<u8>> {
+        let mut states = Vec::new();
+        for frame in 0..frames {
+            rewind.record(frame, cpu);
+            states.push(cpu.save_state());
+            run_frame(cpu, frame);
+        }
+        states
+    }
+
+    fn new_cpu() -> CPU {
+        let mut cpu = CPU::default();
+        cpu.load(FRAME_PROGRAM.to_vec());
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let mut data = vec![0; 1000];
+        data[10] = 1;
+        data[11] = 0;
+        data[12] = 3;
+        data[999] = 0xff;
+        let compressed = compress(&data);
+        assert!(compressed.len() < 20);
+        assert_eq!(decompress(&compressed), data);
+        assert_eq!(decompress(&compress(&[])), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_step_back_is_frame_accurate() {
+        let mut cpu = new_cpu();
+        let mut rewind = RewindBuffer::new(RewindConfig {
+            frames_per_snapshot: 3,
+            snapshots_per_keyframe: 4,
+            ..Default::default()
+        });
+        let states = record_frames(&mut rewind, &mut cpu, 40);
+
+        rewind.step_back(&mut cpu, 5, run_frame).unwrap();
+        assert_eq!(cpu.save_state(), states[34]);
+        assert_eq!(rewind.current_frame(), Some(34));
+
+        rewind.rewind_to(&mut cpu, 13, run_frame).unwrap();
+        assert_eq!(cpu.save_state(), states[13]);
+        assert_eq!(cpu.mem_read(0x00), 13);
+    }
+
+    #[test]
+    fn test_recording_continues_after_rewind() {
+        let mut cpu = new_cpu();
+        let mut rewind = RewindBuffer::new(RewindConfig {
+            frames_per_snapshot: 1,
+            snapshots_per_keyframe: 5,
+            ..Default::default()
+        });
+        let states = record_frames(&mut rewind, &mut cpu, 20);
+
+        rewind.rewind_to(&mut cpu, 7, run_frame).unwrap();
+        for frame in 7..12 {
+            rewind.record(frame, &cpu);
+            run_frame(&mut cpu, frame);
+        }
+
+        rewind.rewind_to(&mut cpu, 9, run_frame).unwrap();
+        assert_eq!(cpu.save_state(), states[9]);
+    }
+
+    #[test]
+    fn test_memory_budget_drops_oldest_history() {
+        let mut cpu = new_cpu();
+        let mut rewind = RewindBuffer::new(RewindConfig {
+            frames_per_snapshot: 1,
+            snapshots_per_keyframe: 10,
+            memory_budget: 600,
+        });
+        let mut states = Vec::new();
+        for frame in 0..100 {
+            rewind.record(frame, &cpu);
+            assert!(
+                rewind.memory_used() <= 600,
+                "over budget at frame {}",
+                frame
+            );
+            states.push(cpu.save_state());
+            run_frame(&mut cpu, frame);
+        }
+
+        let oldest = rewind.oldest_frame().unwrap();
+        assert!(oldest > 0);
+        assert_eq!(
+            rewind.rewind_to(&mut cpu, oldest - 1, run_frame),
+            Err(RewindError::OutOfHistory(oldest - 1))
+        );
+        rewind.rewind_to(&mut cpu, oldest, run_frame).unwrap();
+        assert_eq!(cpu.save_state(), states[oldest as usize]);
+    }
+
+    #[test]
+    fn test_cannot_rewind_into_the_future() {
+        let mut cpu = new_cpu();
+        let mut rewind = RewindBuffer::new(RewindConfig::default());
+        record_frames(&mut rewind, &mut cpu, 4);
+
+        assert_eq!(
+            rewind.rewind_to(&mut cpu, 4, run_frame),
+            Err(RewindError::OutOfHistory(4))
+        );
+    }
+}
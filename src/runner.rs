@@ -0,0 +1,476 @@
+//! Headless runs for scripts and CI: a program runs frame by frame with
+//! scripted input until an exit condition is met.
+//!
+//! There is no PPU yet, so frames are counted off the CPU cycle counter at
+//! 341 × 262 / 3 cycles per frame. The controller ports are lent to the
+//! CPU for the run, which answers $4016/$4017 on its bus.
+
+use crate::cheats::CheatList;
+use crate::cpu::{CpuError, Hook, Mem, CPU};
+use crate::input::InputPorts;
+use crate::movie::{self, FrameInput, Movie};
+use crate::render::frame::Frame;
+use crate::trace::{DOTS_PER_SCANLINE, SCANLINES_PER_FRAME};
+use std::fmt;
+
+const DOTS_PER_FRAME: usize = DOTS_PER_SCANLINE * SCANLINES_PER_FRAME;
+
+/// Button characters accepted in input scripts, from bit 7 down to bit 0;
+/// the same letters FM2 movies use.
+const BUTTON_MNEMONICS: &[u8; 8] = b"RLDUTSBA";
+
+/// Frame number the CPU is in after `cycles` cycles since power-on.
+pub fn frame_of(cycles: usize) -> u64 {
+    (cycles * 3 / DOTS_PER_FRAME) as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCondition {
+    /// This many frames have run.
+    Frames(u64),
+    /// The program counter reached the address; the instruction there has
+    /// not run.
+    Pc(u16),
+    /// The byte at `address` holds `value`.
+    Memory { address: u16, value: u8 },
+    /// The CPU is at an opcode that would lock it up; it has not run.
+    Halt,
+}
+
+impl ExitCondition {
+    fn is_met(&self, cpu: &CPU) -> bool {
+        match *self {
+            ExitCondition::Frames(frames) => frame_of(cpu.cycles) >= frames,
+            ExitCondition::Pc(address) => cpu.program_counter == address,
+            ExitCondition::Memory { address, value } => cpu.mem_read(address) == value,
+            ExitCondition::Halt => cpu.at_jam(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    Condition(ExitCondition),
+    /// The CPU could not go on, e.g. on an opcode it does not implement.
+    Fault(CpuError),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Condition(ExitCondition::Frames(frames)) => {
+                write!(f, "ran {} frames", frames)
+            }
+            StopReason::Condition(ExitCondition::Pc(address)) => {
+                write!(f, "PC reached ${:04X}", address)
+            }
+            StopReason::Condition(ExitCondition::Memory { address, value }) => {
+                write!(f, "${:04X} is {:02X}", address, value)
+            }
+            StopReason::Condition(ExitCondition::Halt) => write!(f, "CPU halted"),
+            StopReason::Fault(err) => write!(f, "CPU fault: {}", err),
+        }
+    }
+}
+
+/// Where a run ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub reason: StopReason,
+    pub frames: u64,
+    pub cycles: usize,
+    /// [`movie::state_hash`] of the machine at the end.
+    pub hash: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScriptError {
+    /// 1-based script line.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// Controller input by frame. Each entry holds from its frame until the
+/// next one.
+///
+/// The text form has a frame number and one or two controllers per line,
+/// pressed buttons written with the letters `RLDUTSBA` (T is Start, S is
+/// Select) and `.` for none:
+///
+/// ```text
+/// # frame  pad 1  pad 2
+/// 60       T
+/// 62       .
+/// 300      RB     A
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InputScript {
+    changes: Vec<(u64, FrameInput)>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<Self, ScriptError> {
+        let mut script = InputScript::default();
+        for (index, line) in text.lines().enumerate() {
+            let err = |message: String| ScriptError {
+                line: index + 1,
+                message,
+            };
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let frame: u64 = fields
+                .next()
+                .and_then(|frame| frame.parse().ok())
+                .ok_or_else(|| err(format!("expected a frame number, got {}", line)))?;
+            if script
+                .changes
+                .last()
+                .is_some_and(|(last, _)| *last >= frame)
+            {
+                return Err(err(format!(
+                    "frame {} is not after the previous line",
+                    frame
+                )));
+            }
+            let mut input = FrameInput::default();
+            for (pad, field) in fields.enumerate() {
+                if pad >= input.pads.len() {
+                    return Err(err("too many controllers".to_string()));
+                }
+                input.pads[pad] = parse_buttons(field).map_err(err)?;
+            }
+            script.changes.push((frame, input));
+        }
+        Ok(script)
+    }
+
+    /// Plays back the input of a movie, one entry per frame.
+    pub fn from_movie(movie: &Movie) -> Self {
+        InputScript {
+            changes: (0..).zip(movie.frames.iter().copied()).collect(),
+        }
+    }
+
+    /// Input of the entry that starts at `frame`, if one does. Console
+    /// commands act only on that frame.
+    pub fn change_at(&self, frame: u64) -> Option<FrameInput> {
+        self.changes
+            .binary_search_by_key(&frame, |(start, _)| *start)
+            .ok()
+            .map(|index| self.changes[index].1)
+    }
+
+    /// Input held during `frame`.
+    pub fn input_at(&self, frame: u64) -> FrameInput {
+        let index = self.changes.partition_point(|(start, _)| *start <= frame);
+        match index {
+            0 => FrameInput::default(),
+            _ => self.changes[index - 1].1,
+        }
+    }
+}
+
+fn parse_buttons(field: &str) -> Result<u8, String> {
+    if field == "." {
+        return Ok(0);
+    }
+    field.bytes().try_fold(0, |buttons, letter| {
+        match BUTTON_MNEMONICS.iter().position(|&m| m == letter) {
+            Some(index) => Ok(buttons | 0x80 >> index),
+            None => Err(format!("unknown button {}", letter as char)),
+        }
+    })
+}
+
+pub struct Runner {
+    pub ports: InputPorts,
+    pub input: InputScript,
+    pub cheats: CheatList,
+    /// The run stops at the first condition met.
+    pub conditions: Vec<ExitCondition>,
+    /// The picture on screen. Nothing draws into it until there is a PPU.
+    pub frame: Frame,
+    /// Frame numbers to take a screenshot at, when that many frames have
+    /// run.
+    pub screenshot_frames: Vec<u64>,
+    /// Screenshots taken so far, with the frame number they were taken at.
+    pub screenshots: Vec<(u64, Frame)>,
+    /// Frame whose input was last applied, so a run resuming within it
+    /// does not reset the console or take its screenshot again.
+    applied_frame: Option<u64>,
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Runner::new()
+    }
+}
+
+impl Runner {
+    /// Standard controllers, no input, no cheats and no exit condition.
+    pub fn new() -> Self {
+        Runner {
+            ports: InputPorts::joypads(),
+            input: InputScript::default(),
+            cheats: CheatList::new(),
+            conditions: Vec::new(),
+            frame: Frame::new(),
+            screenshot_frames: Vec::new(),
+            screenshots: Vec::new(),
+            applied_frame: None,
+        }
+    }
+
+    /// Runs `cpu` from where it is.
+    pub fn run(&mut self, cpu: &mut CPU) -> Summary {
+        let Runner {
+            ports,
+            input,
+            cheats,
+            conditions,
+            frame: picture,
+            screenshot_frames,
+            screenshots,
+            applied_frame,
+        } = self;
+        let mut met = None;
+
+        let previous_ports = cpu.ports.replace(std::mem::take(ports));
+        let result = cpu.run_with_hook(|cpu| {
+            let current = frame_of(cpu.cycles);
+            if *applied_frame != Some(current) {
+                *applied_frame = Some(current);
+                if let Some(change) = input.change_at(current) {
+                    change.apply_commands(cpu);
+                }
+                if let Some(ports) = &mut cpu.ports {
+                    input.input_at(current).apply(ports);
+                }
+                cheats.apply(cpu);
+                if screenshot_frames.contains(&current) {
+                    screenshots.push((current, picture.clone()));
+                }
+            }
+            if let Some(condition) = conditions.iter().find(|condition| condition.is_met(cpu)) {
+                met = Some(*condition);
+                return Hook::Break;
+            }
+            Hook::Continue
+        });
+        *ports = std::mem::replace(&mut cpu.ports, previous_ports).unwrap_or_default();
+
+        let reason = match result {
+            Ok(_) => StopReason::Condition(met.expect("hook stopped without a condition")),
+            Err(err) => StopReason::Fault(err),
+        };
+        Summary {
+            reason,
+            frames: frame_of(cpu.cycles),
+            cycles: cpu.cycles,
+            hash: movie::state_hash(cpu),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::cartridge::test::test_rom;
+    use crate::cartridge::Rom;
+    use crate::input::{InputDevice, BUTTON_A, BUTTON_RIGHT, BUTTON_START};
+    use crate::movie::{COMMAND_POWER, COMMAND_RESET};
+
+    fn load(source: &str) -> CPU {
+        let mut cpu = CPU::default();
+        cpu.load(asm::assemble(source).unwrap());
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_exit_conditions() {
+        // JSR to itself never returns; the stack pointer just wraps around.
+        let mut cpu = load("loop: JSR loop");
+        let mut runner = Runner::new();
+        runner.conditions = vec![ExitCondition::Frames(3)];
+        runner.screenshot_frames = vec![0, 2, 5];
+        let summary = runner.run(&mut cpu);
+        let taken: Vec<u64> = runner.screenshots.iter().map(|(frame, _)| *frame).collect();
+        assert_eq!(taken, [0, 2]);
+        assert_eq!(
+            summary.reason,
+            StopReason::Condition(ExitCondition::Frames(3))
+        );
+        assert_eq!(summary.frames, 3);
+        // Three frames are 3 * 29780.67 cycles; JSR takes 6.
+        assert!((89342..89342 + 6).contains(&summary.cycles));
+
+        let mut cpu = load("LDA #5\nSTA $10\nhere: LDA #6\nSTA $10\nBRK");
+        runner.conditions = vec![
+            ExitCondition::Memory {
+                address: 0x10,
+                value: 6,
+            },
+            ExitCondition::Pc(0x8004),
+        ];
+        let summary = runner.run(&mut cpu);
+        assert_eq!(
+            summary.reason,
+            StopReason::Condition(ExitCondition::Pc(0x8004))
+        );
+        assert_eq!(summary.reason.to_string(), "PC reached $8004");
+        let summary = runner.run(&mut cpu);
+        assert_eq!(summary.reason.to_string(), "PC reached $8004");
+
+        runner.conditions.remove(1);
+        cpu.program_counter = 0x8004;
+        let summary = runner.run(&mut cpu);
+        assert_eq!(summary.reason.to_string(), "$0010 is 06");
+        assert_eq!(summary.hash, movie::state_hash(&cpu));
+    }
+
+    #[test]
+    fn test_fault() {
+        // LDX is not implemented yet.
+        let mut cpu = load("LDX #1\nBRK");
+        let summary = Runner::new().run(&mut cpu);
+        assert_eq!(
+            summary.reason,
+            StopReason::Fault(CpuError {
+                pc: 0x8000,
+                opcode: 0xa2,
+                kind: crate::cpu::FaultKind::Unimplemented
+            })
+        );
+        assert_eq!(
+            summary.reason.to_string(),
+            "CPU fault: LDX (opcode $A2) is not implemented at $8000"
+        );
+    }
+
+    #[test]
+    fn test_controller_reads() {
+        let mut cpu = load(
+            "
+            LDA #1
+            STA $4016
+            LDA #0
+            STA $4016
+            LDA $4016
+            STA $10
+            LDA #$16
+            STA $20
+            LDA #$40
+            STA $21
+            LDA ($20),Y
+            STA $11
+            LDA #$17
+            TAX
+            LDA $4000,X
+            STA $12
+            BRK
+            ",
+        );
+        let mut runner = Runner::new();
+        runner.conditions = vec![ExitCondition::Pc(0x8023)];
+        runner.input = InputScript::parse("0 A T").unwrap();
+        let summary = runner.run(&mut cpu);
+        assert_eq!(
+            summary.reason,
+            StopReason::Condition(ExitCondition::Pc(0x8023))
+        );
+        assert_eq!(cpu.mem_read(0x10), 0x41);
+        assert_eq!(cpu.mem_read(0x11), 0x40);
+        assert_eq!(cpu.mem_read(0x12), 0x40);
+        // The ports answered on the bus and nothing went into memory.
+        assert_eq!(cpu.mem_read(0x4016), 0);
+        assert_eq!(cpu.mem_read(0x4017), 0);
+        assert!(cpu.ports.is_none());
+        let InputDevice::Joypad(joypad) = &runner.ports.port1 else {
+            panic!("port 1 lost its controller");
+        };
+        assert_eq!(joypad.button_status, BUTTON_A);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut cpu = load("LDA #1\n.byte $02");
+        let mut runner = Runner::new();
+        runner.conditions = vec![ExitCondition::Halt];
+        let summary = runner.run(&mut cpu);
+        assert_eq!(summary.reason, StopReason::Condition(ExitCondition::Halt));
+        assert_eq!(summary.reason.to_string(), "CPU halted");
+        assert_eq!(cpu.program_counter, 0x8002);
+    }
+
+    #[test]
+    fn test_movie_commands() {
+        let movie = |commands| Movie {
+            frames: vec![
+                FrameInput::default(),
+                FrameInput {
+                    commands,
+                    ..Default::default()
+                },
+                FrameInput::default(),
+            ],
+            ..Movie::new("test.nes", &Rom::new(&test_rom(&[0xea])).unwrap())
+        };
+        let program = "LDA $10\nADC #1\nSTA $10\nloop: JSR loop";
+
+        // Stopping within the frame of the reset does not repeat it.
+        let mut cpu = load(program);
+        let mut runner = Runner::new();
+        runner.input = InputScript::from_movie(&movie(COMMAND_RESET));
+        for frames in 1..=3 {
+            runner.conditions = vec![ExitCondition::Frames(frames)];
+            runner.run(&mut cpu);
+            runner.conditions = vec![ExitCondition::Pc(0x8006)];
+            runner.run(&mut cpu);
+        }
+        assert_eq!(cpu.mem_read(0x10), 2);
+
+        let mut cpu = load(program);
+        let mut runner = Runner::new();
+        runner.input = InputScript::from_movie(&movie(COMMAND_POWER));
+        runner.conditions = vec![ExitCondition::Frames(3)];
+        runner.run(&mut cpu);
+        assert_eq!(cpu.mem_read(0x10), 1);
+    }
+
+    #[test]
+    fn test_input_script() {
+        let script = InputScript::parse("# start\n60 T\n62 . # release\n300 RA A\n").unwrap();
+        assert_eq!(script.input_at(0), FrameInput::default());
+        assert_eq!(script.input_at(61).pads[0], BUTTON_START);
+        assert_eq!(script.input_at(62).pads[0], 0);
+        assert_eq!(
+            script.input_at(1000).pads[..2],
+            [BUTTON_RIGHT | BUTTON_A, BUTTON_A]
+        );
+
+        assert_eq!(
+            InputScript::parse("10 A\n5 B").unwrap_err(),
+            ScriptError {
+                line: 2,
+                message: "frame 5 is not after the previous line".to_string()
+            }
+        );
+        assert_eq!(
+            InputScript::parse("10 X").unwrap_err().message,
+            "unknown button X"
+        );
+    }
+}
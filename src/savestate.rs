@@ -0,0 +1,444 @@
+use crate::cpu::{ReadPatch, CPU};
+use crate::input::InputPorts;
+use std::fmt;
+
+/// # Save state layout
+///
+///  "NESS" 0x1A        magic
+///  u16                format version (little endian)
+///  chunk*             until the end of the data
+///
+///  chunk:
+///  [u8; 4]            chunk id, e.g. "CPU "
+///  u32                payload length (little endian)
+///  [u8; length]       payload
+///
+/// Readers skip chunks they do not know and ignore bytes past the fields
+/// they understand at the end of a chunk, so fields can be added without a
+/// new format version. The version goes up only when existing fields
+/// change, and readers reject versions newer than their own.
+///
+const MAGIC: &[u8; 5] = b"NESS\x1a";
+pub const FORMAT_VERSION: u16 = 1;
+
+const CHUNK_CPU: &[u8; 4] = b"CPU ";
+const CHUNK_RAM: &[u8; 4] = b"RAM ";
+/// A presence byte, then the device in each controller port with its
+/// strobe and shift register state.
+const CHUNK_INPUT: &[u8; 4] = b"INPT";
+/// A u32 count, then per Game Genie read patch its address, value and a
+/// compare byte preceded by whether there is one.
+const CHUNK_PATCHES: &[u8; 4] = b"PTCH";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    Truncated,
+    MissingChunk([u8; 4]),
+    /// A controller port holds a device kind this reader does not know.
+    UnknownDevice(u8),
+    /// Written by a newer format version than this reader knows.
+    UnsupportedVersion(u16),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::MissingChunk(id) => {
+                write!(f, "chunk {:?} is missing", String::from_utf8_lossy(id))
+            }
+            StateError::UnknownDevice(kind) => write!(f, "unknown input device {}", kind),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state format version {} is newer than {}",
+                version, FORMAT_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        StateWriter { data }
+    }
+
+    pub fn chunk(&mut self, id: &[u8; 4], payload: &[u8]) {
+        self.data.extend_from_slice(id);
+        self.data
+            .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(payload);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    pub version: u16,
+    chunks: Vec<([u8; 4], &'a [u8])>,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, StateError> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let mut reader = ChunkReader::new(&data[MAGIC.len()..]);
+        let version = reader.u16()?;
+        if version > FORMAT_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let mut chunks = Vec::new();
+        while !reader.is_empty() {
+            let mut id = [0; 4];
+            id.copy_from_slice(reader.bytes(4)?);
+            let length = reader.u32()? as usize;
+            chunks.push((id, reader.bytes(length)?));
+        }
+        Ok(StateReader { version, chunks })
+    }
+
+    pub fn chunk(&self, id: &[u8; 4]) -> Result<ChunkReader<'a>, StateError> {
+        self.chunks
+            .iter()
+            .find(|(chunk_id, _)| chunk_id == id)
+            .map(|(_, payload)| ChunkReader::new(payload))
+            .ok_or(StateError::MissingChunk(*id))
+    }
+}
+
+pub struct ChunkReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ChunkReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        ChunkReader { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.pos < len {
+            return Err(StateError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+impl CPU {
+    /// Serializes the whole machine into a save state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+
+        let mut cpu = vec![
+            self.register_a,
+            self.register_x,
+            self.register_y,
+            self.status,
+        ];
+        cpu.extend_from_slice(&self.program_counter.to_le_bytes());
+        cpu.push(self.stack_pointer);
+        cpu.extend_from_slice(&(self.cycles as u64).to_le_bytes());
+        writer.chunk(CHUNK_CPU, &cpu);
+        writer.chunk(CHUNK_RAM, &self.memory);
+
+        let mut input = Vec::new();
+        match &self.ports {
+            Some(ports) => {
+                input.push(1);
+                ports.save_state(&mut input);
+            }
+            None => input.push(0),
+        }
+        writer.chunk(CHUNK_INPUT, &input);
+
+        let mut patches = (self.read_patches.len() as u32).to_le_bytes().to_vec();
+        for patch in &self.read_patches {
+            patches.extend_from_slice(&patch.address.to_le_bytes());
+            patches.extend_from_slice(&[
+                patch.value,
+                patch.compare.is_some() as u8,
+                patch.compare.unwrap_or(0),
+            ]);
+        }
+        writer.chunk(CHUNK_PATCHES, &patches);
+
+        writer.finish()
+    }
+
+    /// Restores a state produced by [`CPU::save_state`].
+    ///
+    /// The machine is left untouched if the state cannot be decoded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let state = StateReader::parse(data)?;
+
+        let mut cpu = state.chunk(CHUNK_CPU)?;
+        let register_a = cpu.u8()?;
+        let register_x = cpu.u8()?;
+        let register_y = cpu.u8()?;
+        let status = cpu.u8()?;
+        let program_counter = cpu.u16()?;
+        let stack_pointer = cpu.u8()?;
+        let cycles = cpu.u64()? as usize;
+
+        let mut ram = state.chunk(CHUNK_RAM)?;
+        let memory = ram.bytes(self.memory.len())?;
+
+        // States from before the ports and patches were saved leave them
+        // as they are.
+        let ports = match state.chunk(CHUNK_INPUT).ok() {
+            Some(mut input) => Some(match input.u8()? {
+                0 => None,
+                _ => Some(InputPorts::load_state(&mut input)?),
+            }),
+            None => None,
+        };
+        let read_patches = match state.chunk(CHUNK_PATCHES).ok() {
+            Some(mut patches) => {
+                let count = patches.u32()?;
+                let mut read_patches = Vec::new();
+                for _ in 0..count {
+                    let address = patches.u16()?;
+                    let value = patches.u8()?;
+                    let has_compare = patches.u8()? != 0;
+                    let compare = patches.u8()?;
+                    read_patches.push(ReadPatch {
+                        address,
+                        value,
+                        compare: has_compare.then_some(compare),
+                    });
+                }
+                Some(read_patches)
+            }
+            None => None,
+        };
+
+        self.register_a = register_a;
+        self.register_x = register_x;
+        self.register_y = register_y;
+        self.status = status;
+        self.program_counter = program_counter;
+        self.stack_pointer = stack_pointer;
+        self.cycles = cycles;
+        self.memory.copy_from_slice(memory);
+        if let Some(ports) = ports {
+            self.ports = ports;
+        }
+        if let Some(read_patches) = read_patches {
+            self.read_patches = read_patches;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Mem;
+    use crate::input::{InputDevice, Joypad, Vaus, BUTTON_A, BUTTON_START};
+
+    fn paused_cpu() -> CPU {
+        let mut cpu = CPU::default();
+        // LDA #$05; BRK; ADC #$10; STA $10; TAX; INX; BRK
+        cpu.load(vec![
+            0xa9, 0x05, 0x00, 0x69, 0x10, 0x85, 0x10, 0xaa, 0xe8, 0x00,
+        ]);
+        // The first BRK's handler is the rest of the program.
+        cpu.mem_write_u16(0xfffe, 0x8003);
+        cpu.reset();
+        // Up to and including the first BRK.
+        cpu.run_for_instructions(2).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn test_round_trip_restores_registers_and_memory() {
+        let mut cpu = paused_cpu();
+        cpu.register_y = 0x42;
+        cpu.status = 0b1100_0011;
+        cpu.mem_write(0x0123, 0x99);
+        let state = cpu.save_state();
+
+        let mut restored = CPU::default();
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.register_a, 0x05);
+        assert_eq!(restored.register_y, 0x42);
+        assert_eq!(restored.status, 0b1100_0011);
+        assert_eq!(restored.program_counter, cpu.program_counter);
+        assert_eq!(restored.cycles, cpu.cycles);
+        assert_eq!(restored.mem_read(0x0123), 0x99);
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn test_emulation_continues_identically_after_restore() {
+        let mut cpu = paused_cpu();
+        let state = cpu.save_state();
+
+        let mut restored = CPU::default();
+        restored.load_state(&state).unwrap();
+
+        cpu.run_until(CPU::at_brk).unwrap();
+        restored.run_until(CPU::at_brk).unwrap();
+
+        assert_eq!(restored.register_x, 0x16);
+        assert_eq!(restored.mem_read(0x10), 0x15);
+        assert_eq!(restored.save_state(), cpu.save_state());
+    }
+
+    #[test]
+    fn test_round_trip_restores_ports_and_read_patches() {
+        let mut cpu = paused_cpu();
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(BUTTON_A | BUTTON_START, true);
+        let mut vaus = Vaus::new();
+        vaus.position = 0xa5;
+        cpu.ports = Some(InputPorts::new(
+            InputDevice::Joypad(joypad),
+            InputDevice::Vaus(vaus),
+        ));
+        cpu.set_read_patches(vec![
+            ReadPatch {
+                address: 0x8001,
+                value: 0x07,
+                compare: None,
+            },
+            ReadPatch {
+                address: 0x9000,
+                value: 0xea,
+                compare: Some(0x20),
+            },
+        ]);
+        // Strobe, then shift out part of each port.
+        let ports = cpu.ports.as_mut().unwrap();
+        ports.write(1);
+        ports.write(0);
+        for _ in 0..3 {
+            ports.read(0x4016);
+            ports.read(0x4017);
+        }
+        let state = cpu.save_state();
+
+        let mut restored = CPU::default();
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.read_patches(), cpu.read_patches());
+        assert_eq!(restored.save_state(), state);
+        let ports = cpu.ports.as_mut().unwrap();
+        let restored_ports = restored.ports.as_mut().unwrap();
+        // The joypad continues at Start and the paddle mid-byte.
+        for addr in [0x4016, 0x4017] {
+            let expected: Vec<u8> = (0..6).map(|_| ports.read(addr)).collect();
+            let actual: Vec<u8> = (0..6).map(|_| restored_ports.read(addr)).collect();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_unknown_chunks_and_trailing_fields_are_ignored() {
+        let cpu = paused_cpu();
+        let mut state = cpu.save_state();
+        // Grow the CPU chunk by one byte, as a future version might.
+        let cpu_length_offset = MAGIC.len() + 2 + 4;
+        state[cpu_length_offset] += 1;
+        state.insert(cpu_length_offset + 4 + 15, 0xff);
+        state.extend_from_slice(b"XTRA");
+        state.extend_from_slice(&3u32.to_le_bytes());
+        state.extend_from_slice(&[1, 2, 3]);
+
+        let mut restored = CPU::default();
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.save_state(), cpu.save_state());
+    }
+
+    #[test]
+    fn test_rejects_invalid_states() {
+        let mut cpu = CPU::default();
+        let state = cpu.save_state();
+
+        assert_eq!(cpu.load_state(b"NES\x1a"), Err(StateError::BadMagic));
+        assert_eq!(
+            cpu.load_state(&state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+
+        let mut writer = StateWriter::new();
+        writer.chunk(CHUNK_CPU, &[0; 15]);
+        assert_eq!(
+            cpu.load_state(&writer.finish()),
+            Err(StateError::MissingChunk(*CHUNK_RAM))
+        );
+
+        // The stack pointer and cycle counter are part of every CPU chunk.
+        let mut writer = StateWriter::new();
+        writer.chunk(CHUNK_CPU, &[1, 2, 3, 4, 0x00, 0x80]);
+        writer.chunk(CHUNK_RAM, &[0; 0x10000]);
+        assert_eq!(cpu.load_state(&writer.finish()), Err(StateError::Truncated));
+
+        let mut unknown_device = StateWriter::new();
+        unknown_device.chunk(CHUNK_CPU, &[0; 15]);
+        unknown_device.chunk(CHUNK_RAM, &[0; 0x10000]);
+        unknown_device.chunk(CHUNK_INPUT, &[1, 9]);
+        assert_eq!(
+            cpu.load_state(&unknown_device.finish()),
+            Err(StateError::UnknownDevice(9))
+        );
+
+        let mut newer = state.clone();
+        newer[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            cpu.load_state(&newer),
+            Err(StateError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+        assert_eq!(
+            StateError::UnsupportedVersion(2).to_string(),
+            "save state format version 2 is newer than 1"
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//! ld65 debug info files, written with `ld65 --dbgfile game.dbg`.
+//!
+//! Each line is a record type followed by comma-separated `key=value`
+//! pairs:
+//!
+//! ```text
+//! file    id=0,name="main.s",size=812,mtime=0x5E8F1234,mod=0
+//! seg     id=0,name="CODE",start=0x008000,size=0x0040,addrsize=absolute,type=ro,oname="game.nes",ooffs=16
+//! span    id=3,seg=0,start=3,size=3
+//! line    id=7,file=0,line=12,span=3
+//! sym     id=1,name="main_loop",addrsize=absolute,scope=0,def=7,val=0x8003,seg=0,type=lab
+//! ```
+//!
+//! Banked ROMs put several segments at the same CPU address. A segment's
+//! `ooffs` tells where in the output file it lives, so callers pick the
+//! symbols for the banks currently mapped by passing the same CPU address
+//! to PRG offset mapping the code/data logger uses.
+
+use super::{ParseError, SymbolTable};
+use std::collections::BTreeMap;
+
+/// Size of the iNES header that precedes PRG ROM in the output file.
+const INES_HEADER_LEN: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    pub start: u16,
+    pub size: u32,
+    /// Offset in the output file, for segments that are written to it.
+    pub file_offset: Option<usize>,
+}
+
+/// A range of bytes emitted into a segment, relative to its start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub segment: usize,
+    pub start: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub file: usize,
+    pub line: usize,
+    pub spans: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    /// `label:` or `.proc`.
+    Label,
+    /// `name = value`.
+    Equate,
+    /// `.import`, defined in another module.
+    Import,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub value: Option<i64>,
+    pub size: Option<u16>,
+    pub segment: Option<usize>,
+}
+
+/// Contents of an ld65 `.dbg` file, keyed by record id.
+#[derive(Debug, Default, Clone)]
+pub struct DebugInfo {
+    pub files: BTreeMap<usize, String>,
+    pub segments: BTreeMap<usize, Segment>,
+    pub spans: BTreeMap<usize, Span>,
+    pub lines: BTreeMap<usize, Line>,
+    pub symbols: BTreeMap<usize, Symbol>,
+    /// Bytes in front of PRG ROM in the output file.
+    header_len: usize,
+}
+
+impl DebugInfo {
+    pub fn parse(text: &str) -> Result<DebugInfo, ParseError> {
+        let mut info = DebugInfo::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            info.parse_record(line).map_err(|message| ParseError {
+                line: index + 1,
+                message,
+            })?;
+        }
+        // An iNES file built by ld65 starts with a 16 byte HEADER segment.
+        if info
+            .segments
+            .values()
+            .any(|segment| segment.name == "HEADER" && segment.file_offset == Some(0))
+        {
+            info.header_len = INES_HEADER_LEN;
+        }
+        Ok(info)
+    }
+
+    fn parse_record(&mut self, line: &str) -> Result<(), String> {
+        let (kind, rest) = line
+            .split_once(|c: char| c.is_ascii_whitespace())
+            .unwrap_or((line, ""));
+        let attrs = Attributes::parse(rest)?;
+        match kind {
+            "file" => {
+                self.files
+                    .insert(attrs.number("id")?, attrs.string("name")?);
+            }
+            "seg" => {
+                let segment = Segment {
+                    name: attrs.string("name")?,
+                    start: attrs.number("start")?,
+                    size: attrs.number("size")?,
+                    file_offset: attrs.optional("ooffs")?,
+                };
+                self.segments.insert(attrs.number("id")?, segment);
+            }
+            "span" => {
+                let span = Span {
+                    segment: attrs.number("seg")?,
+                    start: attrs.number("start")?,
+                    size: attrs.number("size")?,
+                };
+                self.spans.insert(attrs.number("id")?, span);
+            }
+            "line" => {
+                let line = Line {
+                    file: attrs.number("file")?,
+                    line: attrs.number("line")?,
+                    spans: attrs.list("span")?,
+                };
+                self.lines.insert(attrs.number("id")?, line);
+            }
+            "sym" => {
+                let kind = match attrs.get("type") {
+                    Some("lab") => SymbolKind::Label,
+                    Some("equ") => SymbolKind::Equate,
+                    Some("imp") => SymbolKind::Import,
+                    other => return Err(format!("unknown symbol type {:?}", other)),
+                };
+                let symbol = Symbol {
+                    name: attrs.string("name")?,
+                    kind,
+                    value: attrs.optional("val")?,
+                    size: attrs.optional("size")?,
+                    segment: attrs.optional("seg")?,
+                };
+                self.symbols.insert(attrs.number("id")?, symbol);
+            }
+            // version, info, mod, scope, type and csym add nothing we show.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// PRG ROM offset of `addr` inside `segment`, or `None` for segments
+    /// that are not part of PRG ROM, like RAM or the header.
+    pub fn prg_offset(&self, segment: usize, addr: u16) -> Option<usize> {
+        let segment = self.segments.get(&segment)?;
+        let file_offset = segment.file_offset?;
+        (file_offset + addr.wrapping_sub(segment.start) as usize).checked_sub(self.header_len)
+    }
+
+    /// Whether `addr` in `segment` is currently visible to the CPU.
+    /// `prg_offset` maps CPU addresses to PRG ROM offsets for the current
+    /// banking; anything outside PRG ROM is always visible.
+    fn mapped<F>(&self, segment: Option<usize>, addr: u16, prg_offset: &F) -> bool
+    where
+        F: Fn(u16) -> Option<usize>,
+    {
+        match segment.and_then(|segment| self.prg_offset(segment, addr)) {
+            Some(offset) => prg_offset(addr) == Some(offset),
+            None => true,
+        }
+    }
+
+    /// Labels visible with the current banking. Equates are left out, as
+    /// their values are as often constants as addresses.
+    pub fn symbol_table<F>(&self, prg_offset: F) -> SymbolTable
+    where
+        F: Fn(u16) -> Option<usize>,
+    {
+        let mut table = SymbolTable::new();
+        for symbol in self.symbols.values() {
+            let addr = match (symbol.kind, symbol.value) {
+                (SymbolKind::Label, Some(value)) if (0..=0xffff).contains(&value) => value as u16,
+                _ => continue,
+            };
+            if !self.mapped(symbol.segment, addr, &prg_offset) {
+                continue;
+            }
+            match symbol.size {
+                Some(size) => table.insert_sized(addr, &symbol.name, size),
+                None => table.insert(addr, &symbol.name),
+            }
+        }
+        table
+    }
+
+    /// Source file and line that produced the byte at `addr`, picking the
+    /// narrowest span when macros or `.proc` scopes cover it too.
+    pub fn source_line<F>(&self, addr: u16, prg_offset: F) -> Option<(&str, usize)>
+    where
+        F: Fn(u16) -> Option<usize>,
+    {
+        let covers = |span: &Span| {
+            let segment = match self.segments.get(&span.segment) {
+                Some(segment) => segment,
+                None => return false,
+            };
+            let offset = addr.wrapping_sub(segment.start) as u32;
+            (span.start..span.start + span.size).contains(&offset)
+                && self.mapped(Some(span.segment), addr, &prg_offset)
+        };
+        self.lines
+            .values()
+            .filter_map(|line| {
+                line.spans
+                    .iter()
+                    .filter_map(|id| self.spans.get(id))
+                    .filter(|span| covers(span))
+                    .map(|span| span.size)
+                    .min()
+                    .map(|size| (size, line))
+            })
+            .min_by_key(|(size, _)| *size)
+            .and_then(|(_, line)| Some((self.files.get(&line.file)?.as_str(), line.line)))
+    }
+}
+
+/// The `key=value` pairs of one record.
+struct Attributes<'a> {
+    pairs: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Attributes<'a> {
+    fn parse(text: &'a str) -> Result<Self, String> {
+        let mut pairs = Vec::new();
+        let mut rest = text.trim();
+        while !rest.is_empty() {
+            let (key, value) = rest
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {}", rest))?;
+            let value_len = if let Some(quoted) = value.strip_prefix('"') {
+                let end = quoted
+                    .find('"')
+                    .ok_or_else(|| format!("unterminated string in {}", key))?;
+                end + 2
+            } else {
+                value.find(',').unwrap_or(value.len())
+            };
+            pairs.push((key.trim(), &value[..value_len]));
+            rest = value[value_len..].trim_start_matches(',').trim();
+        }
+        Ok(Attributes { pairs })
+    }
+
+    fn get(&self, key: &str) -> Option<&'a str> {
+        self.pairs
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| *value)
+    }
+
+    fn string(&self, key: &str) -> Result<String, String> {
+        let value = self.get(key).ok_or_else(|| format!("missing {}", key))?;
+        Ok(value.trim_matches('"').to_string())
+    }
+
+    fn number<T: TryFrom<i64>>(&self, key: &str) -> Result<T, String> {
+        self.optional(key)?
+            .ok_or_else(|| format!("missing {}", key))
+    }
+
+    fn optional<T: TryFrom<i64>>(&self, key: &str) -> Result<Option<T>, String> {
+        self.get(key)
+            .map(|value| parse_number(key, value))
+            .transpose()
+    }
+
+    /// A `+`-separated list of ids, like `span=3+4`.
+    fn list(&self, key: &str) -> Result<Vec<usize>, String> {
+        match self.get(key) {
+            Some(value) => value
+                .split('+')
+                .map(|item| parse_number(key, item))
+                .collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+fn parse_number<T: TryFrom<i64>>(key: &str, value: &str) -> Result<T, String> {
+    let number = match value.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    number
+        .ok()
+        .and_then(|number| T::try_from(number).ok())
+        .ok_or_else(|| format!("bad {} {}", key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cdl;
+
+    /// Two 16 KiB banks both assembled at $8000, with the header in front.
+    const DBG: &str = "\
+version\tmajor=2,minor=0
+info\tcsym=0,file=1,lib=0,line=3,mod=1,scope=1,seg=4,span=3,sym=5,type=1
+file\tid=0,name=\"main.s\",size=812,mtime=0x5E8F1234,mod=0
+line\tid=0,file=0,line=12,span=0
+line\tid=1,file=0,line=30,span=1
+line\tid=2,file=0,line=8,span=2+0
+mod\tid=0,name=\"main.o\",file=0
+seg\tid=0,name=\"HEADER\",start=0x000000,size=0x0010,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=0
+seg\tid=1,name=\"BANK0\",start=0x008000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16
+seg\tid=2,name=\"BANK1\",start=0x008000,size=0x4000,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16400
+seg\tid=3,name=\"ZEROPAGE\",start=0x000000,size=0x0004,addrsize=zeropage,type=rw
+span\tid=0,seg=1,start=3,size=3
+span\tid=1,seg=2,start=3,size=1
+span\tid=2,seg=1,start=0,size=32
+scope\tid=0,name=\"\",mod=0,size=32,span=2
+sym\tid=0,name=\"main_loop\",addrsize=absolute,scope=0,def=0,ref=1,val=0x8003,seg=1,type=lab
+sym\tid=1,name=\"sound_tick\",addrsize=absolute,scope=0,def=1,val=0x8003,seg=2,type=lab
+sym\tid=2,name=\"buffer\",addrsize=zeropage,size=4,scope=0,def=2,val=0x0,seg=3,type=lab
+sym\tid=3,name=\"PPUCTRL\",addrsize=absolute,scope=0,def=2,val=0x2000,type=equ
+sym\tid=4,name=\"famistudio_init\",addrsize=absolute,scope=0,type=imp,exp=9
+";
+
+    fn bank(bank: usize) -> impl Fn(u16) -> Option<usize> {
+        move |addr| cdl::nrom_prg_offset(0x4000, addr).map(|offset| offset + bank * 0x4000)
+    }
+
+    #[test]
+    fn test_parse_records() {
+        let info = DebugInfo::parse(DBG).unwrap();
+        assert_eq!(info.files[&0], "main.s");
+        assert_eq!(info.segments.len(), 4);
+        assert_eq!(info.segments[&2].file_offset, Some(16400));
+        assert_eq!(info.lines[&2].spans, vec![2, 0]);
+        assert_eq!(info.symbols[&4].kind, SymbolKind::Import);
+        assert_eq!(info.symbols[&4].value, None);
+        assert_eq!(info.prg_offset(2, 0x8003), Some(0x4003));
+        assert_eq!(info.prg_offset(3, 0x0000), None);
+
+        let err = DebugInfo::parse("file\tid=0,name=\"main.s").unwrap_err();
+        assert_eq!(err.line, 1);
+        assert_eq!(err.message, "unterminated string in name");
+    }
+
+    #[test]
+    fn test_symbols_follow_banking() {
+        let info = DebugInfo::parse(DBG).unwrap();
+        let first = info.symbol_table(bank(0));
+        assert_eq!(first.get(0x8003), Some("main_loop"));
+        assert_eq!(first.describe(0x0003).as_deref(), Some("buffer+3"));
+        assert_eq!(first.lookup("PPUCTRL"), None);
+
+        let second = info.symbol_table(bank(1));
+        assert_eq!(second.get(0x8003), Some("sound_tick"));
+        assert_eq!(second.lookup("main_loop"), None);
+    }
+
+    #[test]
+    fn test_source_lines() {
+        let info = DebugInfo::parse(DBG).unwrap();
+        assert_eq!(info.source_line(0x8004, bank(0)), Some(("main.s", 12)));
+        assert_eq!(info.source_line(0x8010, bank(0)), Some(("main.s", 8)));
+        assert_eq!(info.source_line(0x8003, bank(1)), Some(("main.s", 30)));
+        assert_eq!(info.source_line(0x8004, bank(1)), None);
+    }
+}
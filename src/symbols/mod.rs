@@ -0,0 +1,155 @@
+//! Names for CPU addresses, from the built-in assembler or imported from
+//! ld65 debug info ([`ld65`]) and NESASM label files ([`nesasm`]).
+
+pub mod ld65;
+pub mod nesasm;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How far past a label addresses are shown as `label+offset` when the
+/// label's size is not known.
+const MAX_OFFSET: u16 = 0xff;
+
+/// Error reading a symbol file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1-based line in the file.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Names for CPU addresses, used to label disassembly and debugger output.
+#[derive(Debug, Default, Clone)]
+pub struct SymbolTable {
+    labels: BTreeMap<u16, String>,
+    /// Byte size of labels that have one, e.g. a `.res` buffer.
+    sizes: BTreeMap<u16, u16>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    /// Names `addr`, replacing any earlier name for it.
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.labels.insert(addr, name.to_string());
+        self.sizes.remove(&addr);
+    }
+
+    /// Names the `size` bytes starting at `addr`.
+    pub fn insert_sized(&mut self, addr: u16, name: &str, size: u16) {
+        self.labels.insert(addr, name.to_string());
+        self.sizes.insert(addr, size);
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    /// Names `addr` relative to the closest label at or before it, as in
+    /// `main_loop+3`. A label covers its size if known, otherwise up to
+    /// [`MAX_OFFSET`] bytes.
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        let (&base, name) = self.labels.range(..=addr).next_back()?;
+        let offset = addr - base;
+        let in_range = match self.sizes.get(&base) {
+            Some(&size) => offset < size.max(1),
+            None => offset <= MAX_OFFSET,
+        };
+        match offset {
+            0 => Some(name.clone()),
+            _ if in_range => Some(format!("{}+{}", name, offset)),
+            _ => None,
+        }
+    }
+
+    /// Address of the symbol called `name`.
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|(_, label)| label.as_str() == name)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Adds every symbol of `other`, which wins where both name an address.
+    pub fn extend(&mut self, other: SymbolTable) {
+        for (addr, name) in other.labels {
+            match other.sizes.get(&addr) {
+                Some(&size) => self.insert_sized(addr, &name, size),
+                None => self.insert(addr, &name),
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.labels
+            .iter()
+            .map(|(addr, label)| (*addr, label.as_str()))
+    }
+}
+
+/// Formats `addr` by name where `symbols` has one, otherwise as hex of
+/// `width` digits.
+pub(crate) fn name_or_hex(symbols: Option<&SymbolTable>, addr: u16, width: usize) -> String {
+    match symbols.and_then(|symbols| symbols.describe(addr)) {
+        Some(name) => name,
+        None => format!("${:0width$X}", addr, width = width),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_lookup() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x8000, "reset");
+        symbols.insert(0x0010, "counter");
+        symbols.insert(0x8000, "main");
+
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.get(0x8000), Some("main"));
+        assert_eq!(symbols.lookup("counter"), Some(0x0010));
+        assert_eq!(symbols.lookup("reset"), None);
+        assert_eq!(
+            symbols.iter().collect::<Vec<_>>(),
+            vec![(0x0010, "counter"), (0x8000, "main")]
+        );
+    }
+
+    #[test]
+    fn test_describe_offsets() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x8120, "main_loop");
+        symbols.insert_sized(0x0300, "buffer", 4);
+
+        assert_eq!(symbols.describe(0x8120).as_deref(), Some("main_loop"));
+        assert_eq!(symbols.describe(0x8123).as_deref(), Some("main_loop+3"));
+        assert_eq!(symbols.describe(0x821f).as_deref(), Some("main_loop+255"));
+        assert_eq!(symbols.describe(0x8220), None);
+        assert_eq!(symbols.describe(0x0303).as_deref(), Some("buffer+3"));
+        assert_eq!(symbols.describe(0x0304), None);
+        assert_eq!(symbols.describe(0x0010), None);
+        assert_eq!(name_or_hex(Some(&symbols), 0x0010, 2), "$10");
+        assert_eq!(name_or_hex(None, 0x8120, 4), "$8120");
+    }
+}
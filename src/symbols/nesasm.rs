@@ -0,0 +1,72 @@
+//! NESASM `.fns` label files, written with the `-l` flag:
+//!
+//! ```text
+//! ; main.asm
+//! main_loop                = $8123
+//! counter                  = $0010
+//! ```
+//!
+//! The files carry no bank numbers, so labels from different banks at the
+//! same address overwrite each other; the last one wins.
+
+use super::{ParseError, SymbolTable};
+
+pub fn parse(text: &str) -> Result<SymbolTable, ParseError> {
+    let mut symbols = SymbolTable::new();
+    for (index, line) in text.lines().enumerate() {
+        let err = |message: String| ParseError {
+            line: index + 1,
+            message,
+        };
+        let line = match line.find(';') {
+            Some(comment) => &line[..comment],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| err(format!("expected name = $address, got {}", line)))?;
+        let name = name.trim();
+        let value = value.trim();
+        let digits = value
+            .strip_prefix('$')
+            .ok_or_else(|| err(format!("expected $address, got {}", value)))?;
+        let addr =
+            u16::from_str_radix(digits, 16).map_err(|_| err(format!("bad address {}", value)))?;
+        if name.is_empty() {
+            return Err(err("missing label name".to_string()));
+        }
+        symbols.insert(addr, name);
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fns() {
+        let symbols = parse(
+            "; main.asm\n\
+             main_loop\t\t= $8123\n\
+             \n\
+             counter                  = $0010 ; zero page\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.lookup("main_loop"), Some(0x8123));
+        assert_eq!(symbols.get(0x0010), Some("counter"));
+
+        assert_eq!(
+            parse("ok = $8000\nbroken = 8000").unwrap_err(),
+            ParseError {
+                line: 2,
+                message: "expected $address, got 8000".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use crate::cpu::{AddressingMode, Mem, CPU};
+use crate::opcodes;
+use crate::symbols::{name_or_hex, SymbolTable};
+
+/// PPU dots per scanline and scanlines per frame (NTSC).
+pub(crate) const DOTS_PER_SCANLINE: usize = 341;
+pub(crate) const SCANLINES_PER_FRAME: usize = 262;
+
+/// Formats the instruction at the program counter as a nestest.log line.
+///
+/// Meant to be called from [`CPU::run_with_callback`] before the
+/// instruction executes:
+///
+/// ```text
+/// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+/// ```
+///
+/// There is no PPU yet, so its position is derived from the CPU cycle
+/// counter at three dots per cycle.
+pub fn trace(cpu: &CPU) -> String {
+    trace_with_symbols(cpu, None)
+}
+
+/// Like [`trace`], naming operand addresses found in `symbols`:
+///
+/// ```text
+/// 8004  8D 01 03  STA buffer+1 = 00               A:00 X:00 Y:00 P:00 SP:FB PPU:  0, 39 CYC:13
+/// ```
+pub fn trace_with_symbols(cpu: &CPU, symbols: Option<&SymbolTable>) -> String {
+    let begin = cpu.program_counter;
+    let code = cpu.mem_read(begin);
+    let opcode = match opcodes::OPCODES_MAP.get(&code) {
+        Some(opcode) => opcode,
+        None => {
+            return format_line(
+                cpu,
+                &format!("{:02X}", code),
+                &format!(" .byte ${:02X}", code),
+            )
+        }
+    };
+
+    let mut hex_dump = vec![code];
+    for i in 1..opcode.len as u16 {
+        hex_dump.push(cpu.mem_read(begin.wrapping_add(i)));
+    }
+
+    let name = |addr: u16, width: usize| name_or_hex(symbols, addr, width);
+    let operand = match (opcode.len, &opcode.mode) {
+        (1, AddressingMode::Accumulator) => "A".to_string(),
+        (1, _) => String::new(),
+        (2, AddressingMode::Immediate) => format!("#${:02X}", hex_dump[1]),
+        (2, AddressingMode::Relative) => {
+            let offset = hex_dump[1] as i8;
+            let target = begin.wrapping_add(2).wrapping_add(offset as u16);
+            name(target, 4)
+        }
+        (2, mode) => {
+            let (addr, value) = effective_address(cpu, mode, begin);
+            let base = hex_dump[1];
+            match mode {
+                AddressingMode::ZeroPage => format!("{} = {:02X}", name(addr, 2), value),
+                AddressingMode::ZeroPage_X => {
+                    format!("{},X @ {:02X} = {:02X}", name(base as u16, 2), addr, value)
+                }
+                AddressingMode::ZeroPage_Y => {
+                    format!("{},Y @ {:02X} = {:02X}", name(base as u16, 2), addr, value)
+                }
+                AddressingMode::Indirect_X => format!(
+                    "({},X) @ {:02X} = {:04X} = {:02X}",
+                    name(base as u16, 2),
+                    base.wrapping_add(cpu.register_x),
+                    addr,
+                    value
+                ),
+                AddressingMode::Indirect_Y => format!(
+                    "({}),Y = {:04X} @ {:04X} = {:02X}",
+                    name(base as u16, 2),
+                    addr.wrapping_sub(cpu.register_y as u16),
+                    addr,
+                    value
+                ),
+                // No other mode takes a one-byte operand; show it raw.
+                _ => format!("${:02X}", base),
+            }
+        }
+        (3, mode) => {
+            let base = (hex_dump[2] as u16) << 8 | (hex_dump[1] as u16);
+            match mode {
+                AddressingMode::Indirect => {
+                    // JMP indirect does not carry into the high byte of the pointer.
+                    let lo = cpu.mem_read(base);
+                    let hi = cpu.mem_read((base & 0xff00) | (base.wrapping_add(1) & 0x00ff));
+                    format!("({}) = {:04X}", name(base, 4), (hi as u16) << 8 | lo as u16)
+                }
+                // JMP and JSR take the address itself, not the byte stored there.
+                AddressingMode::Absolute if code == 0x4c || code == 0x20 => name(base, 4),
+                AddressingMode::Absolute => {
+                    let (addr, value) = effective_address(cpu, mode, begin);
+                    format!("{} = {:02X}", name(addr, 4), value)
+                }
+                AddressingMode::Absolute_X => {
+                    let (addr, value) = effective_address(cpu, mode, begin);
+                    format!("{},X @ {:04X} = {:02X}", name(base, 4), addr, value)
+                }
+                AddressingMode::Absolute_Y => {
+                    let (addr, value) = effective_address(cpu, mode, begin);
+                    format!("{},Y @ {:04X} = {:02X}", name(base, 4), addr, value)
+                }
+                // No other mode takes a two-byte operand; show it raw.
+                _ => format!("${:04X}", base),
+            }
+        }
+        _ => String::new(),
+    };
+
+    let hex_str = hex_dump
+        .iter()
+        .map(|z| format!("{:02X}", z))
+        .collect::<Vec<String>>()
+        .join(" ");
+    // Unofficial opcodes are named with a leading '*' that takes the place
+    // of the separating space.
+    let separator = if opcode.mnemonic.starts_with('*') {
+        ""
+    } else {
+        " "
+    };
+    let asm_str = format!("{}{} {}", separator, opcode.mnemonic, operand)
+        .trim_end()
+        .to_string();
+
+    format_line(cpu, &hex_str, &asm_str)
+}
+
+fn format_line(cpu: &CPU, hex_str: &str, asm_str: &str) -> String {
+    let dots = cpu.cycles * 3;
+    let dot = dots % DOTS_PER_SCANLINE;
+    let scanline = dots / DOTS_PER_SCANLINE % SCANLINES_PER_FRAME;
+
+    format!(
+        "{:04X}  {:8} {:32} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+        cpu.program_counter,
+        hex_str,
+        asm_str,
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status,
+        cpu.stack_pointer,
+        scanline,
+        dot,
+        cpu.cycles
+    )
+}
+
+/// Resolves the operand address of the instruction at `begin` and reads the
+/// value stored there, without going through the CPU's own decoding.
+pub(crate) fn effective_address(cpu: &CPU, mode: &AddressingMode, begin: u16) -> (u16, u8) {
+    let operand = begin.wrapping_add(1);
+    let addr = match mode {
+        AddressingMode::ZeroPage => cpu.mem_read(operand) as u16,
+        AddressingMode::ZeroPage_X => cpu.mem_read(operand).wrapping_add(cpu.register_x) as u16,
+        AddressingMode::ZeroPage_Y => cpu.mem_read(operand).wrapping_add(cpu.register_y) as u16,
+        AddressingMode::Absolute => cpu.mem_read_u16(operand),
+        AddressingMode::Absolute_X => cpu
+            .mem_read_u16(operand)
+            .wrapping_add(cpu.register_x as u16),
+        AddressingMode::Absolute_Y => cpu
+            .mem_read_u16(operand)
+            .wrapping_add(cpu.register_y as u16),
+        AddressingMode::Indirect_X => {
+            let ptr = cpu.mem_read(operand).wrapping_add(cpu.register_x);
+            let lo = cpu.mem_read(ptr as u16);
+            let hi = cpu.mem_read(ptr.wrapping_add(1) as u16);
+            (hi as u16) << 8 | (lo as u16)
+        }
+        AddressingMode::Indirect_Y => {
+            let base = cpu.mem_read(operand);
+            let lo = cpu.mem_read(base as u16);
+            let hi = cpu.mem_read(base.wrapping_add(1) as u16);
+            ((hi as u16) << 8 | (lo as u16)).wrapping_add(cpu.register_y as u16)
+        }
+        _ => return (0, 0),
+    };
+    (addr, cpu.mem_read(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace_program(program: Vec<u8>, setup: impl FnOnce(&mut CPU)) -> Vec<String> {
+        let mut cpu = CPU::default();
+        cpu.load(program);
+        cpu.reset();
+        setup(&mut cpu);
+        let mut result: Vec<String> = vec![];
+        cpu.run_until(|cpu| {
+            result.push(trace(cpu));
+            cpu.at_brk()
+        })
+        .unwrap();
+        result
+    }
+
+    #[test]
+    fn test_format_trace() {
+        let result = trace_program(vec![0xa9, 0x01, 0xaa, 0xe8, 0x00], |cpu| {
+            cpu.register_x = 2;
+            cpu.register_y = 3;
+            cpu.status = 0x24;
+        });
+        assert_eq!(
+            "8000  A9 01     LDA #$01                        A:00 X:02 Y:03 P:24 SP:FD PPU:  0, 21 CYC:7",
+            result[0]
+        );
+        assert_eq!(
+            "8002  AA        TAX                             A:01 X:02 Y:03 P:24 SP:FD PPU:  0, 27 CYC:9",
+            result[1]
+        );
+        assert_eq!(
+            "8003  E8        INX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0, 33 CYC:11",
+            result[2]
+        );
+        assert_eq!(
+            "8004  00        BRK                             A:01 X:02 Y:03 P:24 SP:FD PPU:  0, 39 CYC:13",
+            result[3]
+        );
+    }
+
+    #[test]
+    fn test_format_mem_access() {
+        // LDA ($33),Y
+        let result = trace_program(vec![0xb1, 0x33, 0x00], |cpu| {
+            cpu.mem_write(0x33, 0x00);
+            cpu.mem_write(0x34, 0x04);
+            cpu.mem_write(0x400, 0xaa);
+        });
+        assert_eq!(
+            "8000  B1 33     LDA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:00 SP:FD PPU:  0, 21 CYC:7",
+            result[0]
+        );
+    }
+
+    #[test]
+    fn test_format_addressing_modes() {
+        // STA $10,X; LDA ($11,X); ADC $2111,X; STA $0200
+        let result = trace_program(
+            vec![
+                0x95, 0x10, 0xa1, 0x11, 0x7d, 0x11, 0x21, 0x8d, 0x00, 0x02, 0x00,
+            ],
+            |cpu| {
+                cpu.register_x = 0x01;
+                cpu.mem_write_u16(0x12, 0x3344);
+                cpu.mem_write(0x3344, 0x60);
+                cpu.mem_write(0x2112, 0x05);
+            },
+        );
+        assert!(result[0].starts_with("8000  95 10     STA $10,X @ 11 = 00 "));
+        assert!(result[1].starts_with("8002  A1 11     LDA ($11,X) @ 12 = 3344 = 60 "));
+        assert!(result[2].starts_with("8004  7D 11 21  ADC $2111,X @ 2112 = 05 "));
+        assert!(result[3].starts_with("8007  8D 00 02  STA $0200 = 00 "));
+    }
+
+    #[test]
+    fn test_operand_wraps_past_ffff() {
+        // LDA $1234 with its high byte wrapped around to $0000.
+        let mut cpu = CPU {
+            program_counter: 0xfffe,
+            ..Default::default()
+        };
+        cpu.mem_write(0xfffe, 0xad);
+        cpu.mem_write(0xffff, 0x34);
+        cpu.mem_write(0x0000, 0x12);
+        cpu.mem_write(0x1234, 0x77);
+        assert!(trace(&cpu).starts_with("FFFE  AD 34 12  LDA $1234 = 77 "));
+    }
+
+    #[test]
+    fn test_ppu_position_wraps_scanlines() {
+        let cpu = CPU {
+            cycles: 29900,
+            ..Default::default()
+        };
+        assert!(trace(&cpu).ends_with("PPU:  1, 17 CYC:29900"));
+    }
+
+    #[test]
+    fn test_format_with_symbols() {
+        let program = crate::asm::assemble_program(
+            "
+            main:   JSR sub
+                    BRK
+            sub:    STA $0301
+                    RTS
+            ",
+        )
+        .unwrap();
+        let mut symbols = program.symbols;
+        symbols.insert_sized(0x0300, "buffer", 2);
+        let mut cpu = CPU::default();
+        cpu.load(program.bytes);
+        cpu.reset();
+        let mut result = vec![];
+        cpu.run_until(|cpu| {
+            result.push(trace_with_symbols(cpu, Some(&symbols)));
+            cpu.at_brk()
+        })
+        .unwrap();
+        assert_eq!(
+            result[0],
+            "8000  20 04 80  JSR sub                         A:00 X:00 Y:00 P:00 SP:FD PPU:  0, 21 CYC:7"
+        );
+        assert_eq!(
+            result[1],
+            "8004  8D 01 03  STA buffer+1 = 00               A:00 X:00 Y:00 P:00 SP:FB PPU:  0, 39 CYC:13"
+        );
+    }
+}
@@ -0,0 +1,14 @@
+//! Runs the programs in `tests/roms` against their goldens. After an
+//! intended change in behaviour, refresh them with
+//! `cargo run -- regress tests/roms --frames 60 --bless`.
+
+use nes_emulator::regression::Suite;
+
+#[test]
+fn test_rom_goldens() {
+    let mut suite = Suite::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms"));
+    suite.frames = 60;
+    let report = suite.run(false).unwrap();
+    assert!(!report.results.is_empty());
+    assert!(report.is_success(), "{}", report.summary());
+}
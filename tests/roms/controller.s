@@ -0,0 +1,24 @@
+; Strobes the first controller and stores the state of each button from
+; $20 on, A first, once per pass.
+loop:
+    LDA #1
+    STA $4016
+    LDA #0
+    STA $4016
+    LDA $4016
+    STA $20
+    LDA $4016
+    STA $21
+    LDA $4016
+    STA $22
+    LDA $4016
+    STA $23
+    LDA $4016
+    STA $24
+    LDA $4016
+    STA $25
+    LDA $4016
+    STA $26
+    LDA $4016
+    STA $27
+    JSR loop
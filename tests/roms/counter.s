@@ -0,0 +1,11 @@
+; Adds one to $10 and carries into $11 forever.
+loop:
+    LDA $10
+    ADC #1
+    STA $10
+    LDA $11
+    ADC #0
+    STA $11
+    JSR step
+step:
+    JSR loop
@@ -0,0 +1,316 @@
+//! Conformance against the SingleStepTests 6502 suite
+//! (<https://github.com/SingleStepTests/65x02>, `6502/v1`): one JSON file per
+//! opcode, each with 10,000 cases giving the registers and RAM before and
+//! after the instruction and every bus cycle it makes.
+//!
+//! The suite is not in the repository. Point `SINGLE_STEP_TESTS` at the
+//! `v1` directory and run the ignored test:
+//!
+//! ```text
+//! SINGLE_STEP_TESTS=~/65x02/6502/v1 cargo test --test single_step -- --ignored
+//! ```
+//!
+//! Opcodes the CPU cannot execute yet are listed and skipped. Bus
+//! cycles are checked one by one against the CPU's bus log.
+
+use nes_emulator::cpu::{BusAccess, BusCycle, CpuError, FaultKind, Mem, CPU, STATUS_DECIMAL_MODE};
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const DATA_DIR_VAR: &str = "SINGLE_STEP_TESTS";
+/// Failing cases printed per opcode.
+const MAX_REPORTED: usize = 3;
+
+/// The 2A03 has no decimal mode, so ADC and SBC with D set follow the
+/// binary rules instead of the 6502 ones in the suite.
+const DECIMAL_OPCODES: &[u8] = &[
+    0x61, 0x65, 0x69, 0x6d, 0x71, 0x75, 0x79, 0x7d, 0xe1, 0xe5, 0xe9, 0xed, 0xf1, 0xf5, 0xf9, 0xfd,
+];
+
+struct State {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+impl State {
+    fn parse(value: &Value) -> Result<State, String> {
+        let field = |name: &str| {
+            value[name]
+                .as_u64()
+                .ok_or_else(|| format!("missing {}", name))
+        };
+        let ram = value["ram"]
+            .as_array()
+            .ok_or("missing ram")?
+            .iter()
+            .map(|pair| match (pair[0].as_u64(), pair[1].as_u64()) {
+                (Some(address), Some(byte)) => Ok((address as u16, byte as u8)),
+                _ => Err(format!("bad ram entry {}", pair)),
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(State {
+            pc: field("pc")? as u16,
+            s: field("s")? as u8,
+            a: field("a")? as u8,
+            x: field("x")? as u8,
+            y: field("y")? as u8,
+            p: field("p")? as u8,
+            ram,
+        })
+    }
+
+    fn load(&self, cpu: &mut CPU) {
+        cpu.program_counter = self.pc;
+        cpu.stack_pointer = self.s;
+        cpu.register_a = self.a;
+        cpu.register_x = self.x;
+        cpu.register_y = self.y;
+        cpu.status = self.p;
+        for &(address, byte) in &self.ram {
+            cpu.mem_write(address, byte);
+        }
+    }
+
+    /// Every difference between `cpu` and this state.
+    fn compare(&self, cpu: &CPU) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut check = |name: &str, expected: u16, actual: u16| {
+            if expected != actual {
+                errors.push(format!(
+                    "{} {:02X}, expected {:02X}",
+                    name, actual, expected
+                ));
+            }
+        };
+        check("pc", self.pc, cpu.program_counter);
+        check("s", self.s as u16, cpu.stack_pointer as u16);
+        check("a", self.a as u16, cpu.register_a as u16);
+        check("x", self.x as u16, cpu.register_x as u16);
+        check("y", self.y as u16, cpu.register_y as u16);
+        check("p", self.p as u16, cpu.status as u16);
+        for &(address, byte) in &self.ram {
+            let actual = cpu.mem_read(address);
+            if actual != byte {
+                errors.push(format!(
+                    "${:04X} is {:02X}, expected {:02X}",
+                    address, actual, byte
+                ));
+            }
+        }
+        errors
+    }
+}
+
+fn parse_cycles(value: &Value) -> Result<Vec<BusCycle>, String> {
+    value
+        .as_array()
+        .ok_or("missing cycles")?
+        .iter()
+        .map(|cycle| {
+            let access = match cycle[2].as_str() {
+                Some("read") => BusAccess::Read,
+                Some("write") => BusAccess::Write,
+                _ => return Err(format!("bad cycle {}", cycle)),
+            };
+            match (cycle[0].as_u64(), cycle[1].as_u64()) {
+                (Some(address), Some(value)) => Ok(BusCycle {
+                    address: address as u16,
+                    value: value as u8,
+                    access,
+                }),
+                _ => Err(format!("bad cycle {}", cycle)),
+            }
+        })
+        .collect()
+}
+
+fn describe(cycle: &BusCycle) -> String {
+    let access = match cycle.access {
+        BusAccess::Read => "read",
+        BusAccess::Write => "write",
+    };
+    format!("{} {:02X} at ${:04X}", access, cycle.value, cycle.address)
+}
+
+#[derive(Debug, PartialEq)]
+enum Failure {
+    /// The CPU refused the instruction.
+    Fault(CpuError),
+    /// The instruction ran, with these differences from the expected
+    /// outcome.
+    Mismatch(String),
+}
+
+/// Runs one case, returning what went wrong.
+fn run_case(case: &Value) -> Result<(), Failure> {
+    let initial = State::parse(&case["initial"]).map_err(Failure::Mismatch)?;
+    let expected = State::parse(&case["final"]).map_err(Failure::Mismatch)?;
+    let expected_cycles = parse_cycles(&case["cycles"]).map_err(Failure::Mismatch)?;
+
+    let mut cpu = CPU::default();
+    initial.load(&mut cpu);
+    cpu.set_bus_logging(true);
+    cpu.step().map_err(Failure::Fault)?;
+
+    let mut errors = expected.compare(&cpu);
+    let cycles = cpu.take_bus_log();
+    if cycles.len() != expected_cycles.len() {
+        errors.push(format!(
+            "took {} cycles, expected {}",
+            cycles.len(),
+            expected_cycles.len()
+        ));
+    }
+    if let Some((index, (actual, wanted))) = cycles
+        .iter()
+        .zip(&expected_cycles)
+        .enumerate()
+        .find(|(_, (actual, wanted))| actual != wanted)
+    {
+        errors.push(format!(
+            "cycle {}: {}, expected {}",
+            index + 1,
+            describe(actual),
+            describe(wanted)
+        ));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Failure::Mismatch(errors.join(", ")))
+    }
+}
+
+#[derive(Default)]
+struct OpcodeResult {
+    passed: usize,
+    failed: Vec<String>,
+    /// Why the CPU refused the first case, e.g. an unimplemented opcode.
+    unsupported: Option<FaultKind>,
+}
+
+fn run_cases(opcode: u8, cases: &[Value]) -> OpcodeResult {
+    let mut result = OpcodeResult::default();
+    for case in cases {
+        if DECIMAL_OPCODES.contains(&opcode)
+            && case["initial"]["p"].as_u64().unwrap_or(0) & STATUS_DECIMAL_MODE as u64 != 0
+        {
+            continue;
+        }
+        let name = case["name"].as_str().unwrap_or("?");
+        match run_case(case) {
+            Ok(()) => result.passed += 1,
+            Err(Failure::Fault(err)) if result.passed == 0 && result.failed.is_empty() => {
+                result.unsupported = Some(err.kind);
+                return result;
+            }
+            Err(Failure::Fault(err)) => result.failed.push(format!("{}: {}", name, err)),
+            Err(Failure::Mismatch(message)) => result.failed.push(format!("{}: {}", name, message)),
+        }
+    }
+    result
+}
+
+#[test]
+fn test_harness_on_sample_cases() {
+    // LDA #$42 from the suite's format, and the same case with a wrong
+    // expected accumulator and cycle count.
+    let case: Value = serde_json::from_str(
+        r#"{"name": "a9 42 00",
+            "initial": {"pc": 1000, "s": 253, "a": 0, "x": 0, "y": 0, "p": 38,
+                        "ram": [[1000, 169], [1001, 66]]},
+            "final": {"pc": 1002, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36,
+                      "ram": [[1000, 169], [1001, 66]]},
+            "cycles": [[1000, 169, "read"], [1001, 66, "read"]]}"#,
+    )
+    .unwrap();
+    assert_eq!(run_case(&case), Ok(()));
+
+    let mut wrong = case.clone();
+    wrong["final"]["a"] = 67.into();
+    wrong["cycles"] = serde_json::json!([[1000, 169, "read"], [1001, 66, "write"]]);
+    assert_eq!(
+        run_case(&wrong),
+        Err(Failure::Mismatch(
+            "a 42, expected 43, cycle 2: read 42 at $03E9, expected write 42 at $03E9".to_string()
+        ))
+    );
+
+    // BRK pushes the address two past it and the status with B set, then
+    // jumps through $FFFE.
+    let brk: Value = serde_json::from_str(
+        r#"{"name": "00 55 ea",
+            "initial": {"pc": 1000, "s": 253, "a": 0, "x": 0, "y": 0, "p": 32,
+                        "ram": [[1000, 0], [1001, 85], [65534, 0], [65535, 144]]},
+            "final": {"pc": 36864, "s": 250, "a": 0, "x": 0, "y": 0, "p": 36,
+                      "ram": [[509, 3], [508, 234], [507, 48]]},
+            "cycles": [[1000, 0, "read"], [1001, 85, "read"], [509, 3, "write"],
+                       [508, 234, "write"], [507, 48, "write"], [65534, 0, "read"],
+                       [65535, 144, "read"]]}"#,
+    )
+    .unwrap();
+    assert_eq!(run_case(&brk), Ok(()));
+
+    // An opcode the CPU does not implement is reported, not failed.
+    let mut unimplemented = case;
+    unimplemented["initial"]["ram"][0][1] = 0xa2.into();
+    let result = run_cases(0xa2, &[unimplemented]);
+    assert_eq!(result.unsupported, Some(FaultKind::Unimplemented));
+}
+
+#[test]
+#[ignore = "needs the SingleStepTests data in $SINGLE_STEP_TESTS"]
+fn test_single_step_suite() {
+    let dir = std::env::var(DATA_DIR_VAR)
+        .unwrap_or_else(|_| panic!("set {} to the 6502/v1 directory", DATA_DIR_VAR));
+    let mut report = Vec::new();
+    let mut unsupported = Vec::new();
+    let mut missing = Vec::new();
+    let mut failures = 0;
+
+    for opcode in 0..=0xffu8 {
+        let path = Path::new(&dir).join(format!("{:02x}.json", opcode));
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                missing.push(format!("{:02x}", opcode));
+                continue;
+            }
+            Err(err) => panic!("{}: {}", path.display(), err),
+        };
+        let cases: Vec<Value> =
+            serde_json::from_str(&text).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        let result = run_cases(opcode, &cases);
+        if let Some(kind) = result.unsupported {
+            unsupported.push(format!("{:02x} ({:?})", opcode, kind));
+            continue;
+        }
+        report.push(format!(
+            "{:02x}: {} passed, {} failed",
+            opcode,
+            result.passed,
+            result.failed.len()
+        ));
+        for failure in result.failed.iter().take(MAX_REPORTED) {
+            report.push(format!("    {}", failure));
+        }
+        failures += result.failed.len();
+    }
+
+    println!("{}", report.join("\n"));
+    println!("not run: {}", unsupported.join(" "));
+    println!("missing: {}", missing.join(" "));
+    assert!(
+        missing.len() < 256,
+        "no test files in {}, expected 00.json to ff.json",
+        dir
+    );
+    assert_eq!(failures, 0, "{} cases failed", failures);
+}